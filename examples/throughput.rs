@@ -0,0 +1,65 @@
+//! Measures how many simulated instructions per second
+//! the single-cycle backend achieves, with and without the decode cache.
+//! Usage: cargo run --release --example throughput [ELF file] [rounds]
+
+use sim_lib::cpu::{CPUPolicy, CPUState};
+use sim_lib::elf_helper;
+use sim_lib::instruction::{DecodeCache, DECODE_CACHE_SIZE};
+use sim_lib::loader;
+use sim_lib::memory::inclusive::InclusiveCache;
+use sim_lib::run_wrapper::{STACK_BASE, STACK_SIZE};
+use sim_lib::single_cycle;
+use sim_lib::system_call::SyscallIo;
+use std::time::Instant;
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = std::env::args().collect();
+    let elf_file = args
+        .get(1)
+        .map_or("test-riscv/matrixmulti.riscv", |s| s.as_str());
+    let rounds = args.get(2).map_or(Ok(20), |s| s.parse())?;
+    let (elf_reader, elf_data) = elf_helper::parse_elf_file(elf_file)?;
+
+    let mut results = Vec::new();
+    for size in [0, DECODE_CACHE_SIZE] {
+        let mut inst_count = 0u64;
+        let mut outcome = None;
+        let start = Instant::now();
+        for _ in 0..rounds {
+            let mut cpu = CPUState::make(CPUPolicy::default());
+            let mut mem = InclusiveCache::default();
+            loader::set_stack(&mut cpu, &mut mem.mmu, STACK_BASE, STACK_SIZE);
+            loader::load_elf(&mut cpu, &mut mem.mmu, &elf_reader, &elf_data)?;
+            loader::setup_argv(&mut cpu, &mut mem.mmu, &[elf_file]);
+
+            let mut io = SyscallIo {
+                input: &b""[..],
+                output: Vec::new(),
+            };
+            let mut decoder = DecodeCache::make(size);
+            single_cycle::run_with_decoder(
+                &mut cpu,
+                &mut mem,
+                &mut io,
+                &mut decoder,
+            )?;
+            inst_count += cpu.history.inst_count as u64;
+            outcome = Some((cpu.state_hash(&mem.mmu), io.output));
+        }
+        let seconds = start.elapsed().as_secs_f64();
+        println!(
+            "Decode cache of {:4} entries: {} instructions in {:.3} s = {:.2} MIPS",
+            size,
+            inst_count,
+            seconds,
+            inst_count as f64 / seconds / 1e6
+        );
+        results.push(outcome);
+    }
+
+    if results[0] != results[1] {
+        return Err("The decode cache changed the results".into());
+    }
+    println!("Identical final states and outputs");
+    Ok(())
+}
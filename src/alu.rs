@@ -13,39 +13,47 @@ pub fn alu(inst: &Instruction, op1: i32, op2: i32) -> i32 {
         AND => op1 & op2,
         OR => op1 | op2,
         XOR => op1 ^ op2,
+        // Branches yield 0 if taken, and 1 otherwise
         BEQ => (op1 != op2) as i32,
         BNE => (op1 == op2) as i32,
-        BLT => !(op1 < op2) as i32,
-        BLTU => !((op1 as u32) < (op2 as u32)) as i32,
-        BGE => !(op1 >= op2) as i32,
-        BGEU => !((op1 as u32) >= (op2 as u32)) as i32,
+        BLT => (op1 >= op2) as i32,
+        BLTU => ((op1 as u32) >= (op2 as u32)) as i32,
+        BGE => (op1 < op2) as i32,
+        BGEU => ((op1 as u32) < (op2 as u32)) as i32,
         SLL => op1 << op2,
         SRL => ((op1 as u32) >> op2) as i32,
         SRA => op1.wrapping_shr(op2 as u32),
         SLT => (op1 < op2) as i32,
         SLTU => ((op1 as u32) < (op2 as u32)) as i32,
+        // Dividing by zero yields all ones, or the dividend as remainder,
+        // and overflowing yields the dividend, or 0 as remainder
+        DIV if op2 == 0 => -1,
+        DIV => op1.wrapping_div(op2),
+        DIVU if op2 == 0 => -1,
+        DIVU => ((op1 as u32) / (op2 as u32)) as i32,
+        REM if op2 == 0 => op1,
+        REM => op1.wrapping_rem(op2),
+        REMU if op2 == 0 => op1,
+        REMU => ((op1 as u32) % (op2 as u32)) as i32,
     }
 }
 
 /// Selector for ALU src2 input
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
 pub enum ALUSrc {
     // From register
+    #[default]
     REG,
     // From immediate
     IMM,
 }
 
-impl Default for ALUSrc {
-    fn default() -> Self {
-        ALUSrc::REG
-    }
-}
-
-/// Set of ALU operations needed for rv32i
-#[derive(Clone, Copy, Debug, PartialEq)]
+/// Set of ALU operations needed for rv32i,
+/// plus the divisions of the M extension
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
 pub enum ALUOp {
     // Arithmetic
+    #[default]
     ADD,
     SUB,
     // Logical
@@ -66,10 +74,108 @@ pub enum ALUOp {
     BGE,
     BLTU,
     BGEU,
+    // Division
+    DIV,
+    DIVU,
+    REM,
+    REMU,
 }
 
-impl Default for ALUOp {
-    fn default() -> Self {
-        ALUOp::ADD
+impl ALUOp {
+    /// Whether it divides op1 by op2
+    pub fn is_division(&self) -> bool {
+        matches!(self, ALUOp::DIV | ALUOp::DIVU | ALUOp::REM | ALUOp::REMU)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::encode_helper::encode_b;
+    use crate::instruction::Function;
+
+    /// Whether the branch is taken, as the spec puts it
+    fn reference_taken(function: Function, a: i32, b: i32) -> bool {
+        match function {
+            Function::BEQ => a == b,
+            Function::BNE => a != b,
+            Function::BLT => a < b,
+            Function::BGE => a >= b,
+            Function::BLTU => (a as u32) < (b as u32),
+            Function::BGEU => (a as u32) >= (b as u32),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_branch_conditions() {
+        let operands = [
+            0,
+            1,
+            -1,
+            2,
+            -2,
+            i32::MAX,
+            i32::MIN,
+            i32::MIN + 1,
+            0x7ffffffe,
+        ];
+        for function in [
+            Function::BEQ,
+            Function::BNE,
+            Function::BLT,
+            Function::BGE,
+            Function::BLTU,
+            Function::BGEU,
+        ] {
+            let inst = Instruction::new(encode_b(function, 1, 2, 8));
+            for op1 in operands {
+                for op2 in operands {
+                    assert_eq!(
+                        alu(&inst, op1, op2) == 0,
+                        reference_taken(function, op1, op2),
+                        "{:?} {:#x}, {:#x}",
+                        function,
+                        op1,
+                        op2
+                    );
+                }
+            }
+        }
+
+        // Signed and unsigned comparisons disagree across the sign bit
+        let blt = Instruction::new(encode_b(Function::BLT, 1, 2, 8));
+        let bltu = Instruction::new(encode_b(Function::BLTU, 1, 2, 8));
+        assert_eq!(alu(&blt, -1, 1), 0);
+        assert_eq!(alu(&bltu, -1, 1), 1);
+        assert_eq!(alu(&blt, i32::MIN, 0), 0);
+        assert_eq!(alu(&bltu, i32::MIN, 0), 1);
+    }
+
+    #[test]
+    fn test_division() {
+        use crate::instruction::encode_helper::encode_r;
+        let op = |function| Instruction::new(encode_r(function, 1, 2, 3));
+        let div = op(Function::DIV);
+        let divu = op(Function::DIVU);
+        let rem = op(Function::REM);
+        let remu = op(Function::REMU);
+
+        // Rounding toward zero
+        assert_eq!(alu(&div, -7, 2), -3);
+        assert_eq!(alu(&rem, -7, 2), -1);
+        assert_eq!(alu(&divu, -7, 2), 0x7ffffffc);
+        assert_eq!(alu(&remu, -7, 2), 1);
+        // Dividing by zero
+        assert_eq!(alu(&div, 7, 0), -1);
+        assert_eq!(alu(&divu, 7, 0), -1);
+        assert_eq!(alu(&rem, -7, 0), -7);
+        assert_eq!(alu(&remu, -7, 0), -7);
+        // Overflowing
+        assert_eq!(alu(&div, i32::MIN, -1), i32::MIN);
+        assert_eq!(alu(&rem, i32::MIN, -1), 0);
+
+        // The multiplications aren't supported
+        assert!(Instruction::try_new(0x02628533).is_err()); // mul a0, t0, t1
     }
 }
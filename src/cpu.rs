@@ -1,6 +1,26 @@
 //! rv32i CPU implementation
 
+use crate::error::{SimulatorError, SimulatorResult};
+use crate::instruction::encode_helper::ABI_NAMES;
+use crate::instruction::{compressed, Opcode};
+use crate::memory::mmio::TIMER_SIZE;
+use crate::memory::mmu::MMU;
 use crate::pipelined::branch_predictor::PredictorHeuristic;
+use crate::system_call;
+
+/// Pattern filling uninitialized registers in poison mode
+pub const POISON_WORD: u32 = 0xDEADBEEF;
+
+/// FNV-1a offset basis and prime, 64-bit
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Folds the bytes into an FNV-1a hash
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+    })
+}
 
 /// CPU state
 #[derive(Clone, Copy)]
@@ -13,6 +33,15 @@ pub struct CPUState {
     pub pc: Register,
     /// General purpose registers
     pub gpr: [Register; 32],
+    /// PC of the instruction that trapped last
+    pub mepc: Register,
+    /// Raw instruction that trapped last
+    pub mtval: Register,
+    /// Address reserved by the last LR.W, if any.
+    /// With a single hart, only an SC.W clears it
+    pub reservation: Option<u32>,
+    /// Number of branches to themselves in a row
+    pub self_branch_count: u32,
 
     /// CPU policy
     pub policy: CPUPolicy,
@@ -23,20 +52,106 @@ pub struct CPUState {
 
 impl CPUState {
     pub fn make(policy: CPUPolicy) -> Self {
-        let cpu = Self {
+        let mut gpr = [Register::new(0); 32];
+        if policy.poison {
+            // x0 is hard-wired to 0
+            for register in gpr.iter_mut().skip(1) {
+                register.write(POISON_WORD);
+            }
+        }
+        Self {
             stack_base: 0,
             stack_size: 0,
             pc: Register::new(0),
-            gpr: [Register::new(0); 32],
+            gpr,
+            mepc: Register::new(0),
+            mtval: Register::new(0),
+            reservation: None,
+            self_branch_count: 0,
             policy,
             history: CPUHistory::default(),
-        };
+        }
+    }
+
+    /// Makes a CPU starting at the given PC with the given SP,
+    /// as the loader would leave it, e.g., to run hand-written code
+    pub fn make_with(policy: CPUPolicy, pc: u32, sp: u32) -> Self {
+        let mut cpu = Self::make(policy);
+        cpu.pc.write(pc);
+        cpu.write_reg(2, sp);
         cpu
     }
 
-    /// Checks for stack overflow
+    /// Resets the CPU to its initial state,
+    /// keeping the policy
+    pub fn reset(&mut self) {
+        *self = Self::make(self.policy);
+    }
+
+    /// Reads register x{index}, which is always 0 for x0
+    pub fn read_reg(&self, index: usize) -> u32 {
+        match index {
+            0 => 0,
+            _ => self.gpr[index].read(),
+        }
+    }
+
+    /// Writes register x{index}, ignoring writes to x0,
+    /// which is hard-wired to 0
+    pub fn write_reg(&mut self, index: usize, value: u32) {
+        if index != 0 {
+            self.gpr[index].write(value);
+        }
+    }
+
+    /// Enters the trap handler at trap_vector
+    /// on behalf of the instruction at pc
+    pub fn trap(&mut self, pc: u32, raw_inst: u32, trap_vector: u32) {
+        if self.policy.verbose {
+            eprintln!(
+                "[VERBOSE] Trapping on {:#010x} at PC {:#010x}",
+                raw_inst, pc
+            );
+        }
+        self.mepc.write(pc);
+        self.mtval.write(raw_inst);
+        self.pc.write(trap_vector);
+    }
+
+    /// Where MRET resumes.
+    /// Without CSR instructions, a handler can't advance mepc itself,
+    /// so execution resumes right after the trapping instruction
+    pub fn trap_return_pc(&self) -> u32 {
+        let length = compressed::length(self.mtval.read());
+        self.mepc.read().wrapping_add(length)
+    }
+
+    /// Returns the highest address below the stack,
+    /// or None if the stack reaches down to address 0
+    pub fn stack_limit(&self) -> Option<u32> {
+        self.stack_base.checked_sub(self.stack_size)
+    }
+
+    /// Checks for stack overflow.
+    /// The stack occupies (stack_base - stack_size, stack_base],
+    /// so SP at exactly stack_base - stack_size has overflowed.
+    /// Without a stack (stack_size = 0), there is nothing to check
     pub fn stack_overflow(&self) -> bool {
-        self.gpr[2].read() < self.stack_base - self.stack_size
+        if self.stack_size == 0 {
+            return false;
+        }
+        match self.stack_limit() {
+            Some(limit) => self.read_reg(2) <= limit,
+            None => false,
+        }
+    }
+
+    /// Fails on stack overflow, see stack_overflow
+    pub fn check_stack_overflow(&self) -> SimulatorResult<()> {
+        match self.stack_overflow() {
+            true => Err(SimulatorError::StackOverflow(self.read_reg(2))),
+            false => Ok(()),
+        }
     }
 
     /// Increments history cycle count
@@ -44,10 +159,77 @@ impl CPUState {
         self.history.cycle_count += value;
     }
 
+    /// Fails once the instruction count reaches the limit of the policy
+    pub fn check_instruction_limit(&self) -> SimulatorResult<()> {
+        match self.policy.max_instructions {
+            Some(limit) if self.history.inst_count as u64 >= limit => {
+                Err(SimulatorError::InstructionLimit(limit))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Counts a branch at the given PC to itself,
+    /// failing once there have been as many in a row
+    /// as the hang threshold of the policy
+    pub fn record_self_branch(&mut self, pc: u32) -> SimulatorResult<()> {
+        let Some(threshold) = self.policy.hang_threshold else {
+            return Ok(());
+        };
+        self.self_branch_count += 1;
+        match self.self_branch_count >= threshold {
+            true => Err(SimulatorError::Hang(pc)),
+            false => Ok(()),
+        }
+    }
+
     /// Increments history instruction count
     pub fn update_inst_count(&mut self, value: i32) {
         self.history.inst_count += value;
     }
+
+    /// Records a write of the given value to SP
+    pub fn record_sp(&mut self, sp: u32) {
+        let min_sp = self.history.min_sp.map_or(sp, |min_sp| min_sp.min(sp));
+        self.history.min_sp = Some(min_sp);
+    }
+
+    /// Returns the most bytes the stack has held below its base,
+    /// counting the current SP when SP is never written
+    pub fn peak_stack_usage(&self) -> u32 {
+        let min_sp = self.history.min_sp.unwrap_or(self.read_reg(2));
+        self.stack_base.saturating_sub(min_sp)
+    }
+
+    /// Formats the PC and the register file, one register per line
+    pub fn format_registers(&self) -> String {
+        let mut result = format!("{:<9} = {:#010x}\n", "pc", self.pc.read());
+        for (i, register) in self.gpr.iter().enumerate() {
+            let name = format!("x{}", i);
+            result += &format!(
+                "{:<4} {:<4} = {:#010x}\n",
+                name,
+                ABI_NAMES[i],
+                register.read()
+            );
+        }
+        result
+    }
+
+    /// Hashes the PC, the register file and the allocated memory,
+    /// summarizing the architectural state for regression tests.
+    /// FNV-1a keeps the hash the same across builds and platforms
+    pub fn state_hash(&self, mmu: &MMU) -> u64 {
+        let mut hash = fnv1a(FNV_OFFSET, &self.pc.read().to_le_bytes());
+        for i in 0..self.gpr.len() {
+            hash = fnv1a(hash, &self.read_reg(i).to_le_bytes());
+        }
+        for (base, page) in mmu.dump() {
+            hash = fnv1a(hash, &base.to_le_bytes());
+            hash = fnv1a(hash, page);
+        }
+        hash
+    }
 }
 
 /// Register file simulation
@@ -74,17 +256,22 @@ impl Register {
 }
 
 /// Implementation enum
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default, PartialEq)]
 pub enum Implementation {
     SingleCycle,
+    MultiCycle,
+    #[default]
     Pipelined,
 }
 
-impl Default for Implementation {
-    fn default() -> Self {
-        // Implementation::SingleCycle
-        Implementation::Pipelined
-    }
+/// Memory hierarchy the CPU runs on
+#[derive(Clone, Copy, Default, PartialEq)]
+pub enum CacheMode {
+    /// The default inclusive cache hierarchy
+    #[default]
+    Inclusive,
+    /// No cache, with every access going straight to the memory
+    Passthrough,
 }
 
 /// CPU policy
@@ -94,13 +281,359 @@ pub struct CPUPolicy {
     pub implementation: Implementation,
     pub history: bool,
     pub heuristic: PredictorHeuristic,
+    /// Fill untouched registers and memory with a recognizable pattern
+    pub poison: bool,
+    /// Base address of the memory-mapped console, if any
+    pub console: Option<u32>,
+    /// Base address of the memory-mapped timer, if any
+    pub timer: Option<u32>,
+    /// Additional system call number meaning exit,
+    /// e.g. 93 for newlib
+    pub exit_syscall: Option<i32>,
+    /// Print the register file at exit
+    pub dump_regs: bool,
+    /// Record demand memory accesses into a trace file
+    pub record_trace: bool,
+    /// Include instruction fetches in the trace
+    pub trace_fetches: bool,
+    /// Number of instructions executed functionally
+    /// before the detailed simulation starts
+    pub fast_forward: u64,
+    /// Address to start at instead of the ELF entry,
+    /// which must lie in an executable segment
+    pub entry_override: Option<u32>,
+    /// Address of the handler illegal instructions trap to,
+    /// which returns with MRET; the pipelined backend doesn't trap
+    pub trap_vector: Option<u32>,
+    /// Memory hierarchy to simulate
+    pub cache: CacheMode,
+    /// Offset to relocate the segments and the entry by.
+    /// Defaults to loader::PIE_LOAD_BIAS for position-independent
+    /// executables, and to 0 otherwise
+    pub load_bias: Option<u32>,
+    /// Require the page after each loadable segment to stay unallocated,
+    /// so that overrunning the segment faults. Segments are rounded up
+    /// to whole pages, so overruns within the last page go unnoticed
+    pub guard_pages: bool,
+    /// Model a single-ported memory, where the fetch stalls
+    /// whenever the MEM stage accesses memory;
+    /// only the pipelined implementation has such a conflict
+    pub single_port: bool,
+    /// Number of instructions after which the program is stopped
+    /// with an error, so that a runaway program can't hang a sweep
+    pub max_instructions: Option<u64>,
+    /// Number of branches to themselves in a row, i.e., without the PC
+    /// moving on, after which the program is stopped as hanging;
+    /// the pipelined implementation doesn't detect it
+    pub hang_threshold: Option<u32>,
+    /// Stop the program with an error on dividing by zero,
+    /// instead of yielding the result the spec defines
+    pub trap_division_by_zero: bool,
+}
+
+impl CPUPolicy {
+    /// Checks that the policy is consistent
+    pub fn validate(&self) -> SimulatorResult<()> {
+        if self.trap_vector.is_some()
+            && self.implementation == Implementation::Pipelined
+        {
+            return Err(SimulatorError::Other(
+                "Traps are not supported by the pipelined implementation"
+                    .to_string(),
+            ));
+        }
+        match self.hang_threshold {
+            Some(_) if self.implementation == Implementation::Pipelined => {
+                return Err(SimulatorError::Other(
+                    "Hangs are not detected by the pipelined implementation"
+                        .to_string(),
+                ));
+            }
+            Some(0) => {
+                return Err(SimulatorError::Other(
+                    "The hang threshold must be positive".to_string(),
+                ));
+            }
+            _ => {}
+        }
+        if self.single_port && self.implementation != Implementation::Pipelined
+        {
+            return Err(SimulatorError::Other(
+                "A single-ported memory only affects the pipelined implementation"
+                    .to_string(),
+            ));
+        }
+        if let Some(base) = self.timer {
+            if base.checked_add(TIMER_SIZE - 1).is_none() {
+                return Err(SimulatorError::Other(format!(
+                    "The timer at {:#010x} runs past the address space",
+                    base
+                )));
+            }
+        }
+        match self.exit_syscall {
+            Some(number)
+                if number != system_call::EXIT
+                    && system_call::CUSTOM_SYSCALLS.contains(&number) =>
+            {
+                Err(SimulatorError::Other(format!(
+                    "Exit system call {} collides with the custom ABI",
+                    number
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the given system call number (a7) means exit
+    pub fn is_exit(&self, number: i32) -> bool {
+        number == system_call::EXIT || self.exit_syscall == Some(number)
+    }
 }
 
 /// History module
-#[derive(Clone, Copy, Default)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct CPUHistory {
     pub cycle_count: i32,
     pub mem_stall_count: i32,
     pub mem_stall_worst_count: i32,
+    /// Cycles the fetch stalled on a single-ported memory
+    pub structural_stall_count: i32,
     pub inst_count: i32,
+    pub mix: InstructionMix,
+    /// Lowest value written to SP, if any
+    pub min_sp: Option<u32>,
+}
+
+/// Executed instruction counts by class
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct InstructionMix {
+    pub loads: i32,
+    pub stores: i32,
+    pub branches: i32,
+    /// JAL and JALR
+    pub jumps: i32,
+    /// Including LUI and AUIPC
+    pub alu: i32,
+    pub system: i32,
+    /// LR.W and SC.W
+    pub atomics: i32,
+}
+
+impl InstructionMix {
+    /// Counts an instruction with the given opcode
+    pub fn record(&mut self, opcode: Opcode) {
+        use Opcode::*;
+        match opcode {
+            Load => self.loads += 1,
+            Store => self.stores += 1,
+            Branch => self.branches += 1,
+            Jal | Jalr => self.jumps += 1,
+            Lui | AuiPc | Op | OpImm => self.alu += 1,
+            System => self.system += 1,
+            Amo => self.atomics += 1,
+        }
+    }
+
+    /// Number of instructions accessing memory,
+    /// which are loads, stores and atomics
+    pub fn memory_accesses(&self) -> i32 {
+        self.loads + self.stores + self.atomics
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_with_stack(base: u32, size: u32, sp: u32) -> CPUState {
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        cpu.stack_base = base;
+        cpu.stack_size = size;
+        cpu.gpr[2].write(sp);
+        cpu
+    }
+
+    #[test]
+    fn test_format_registers() {
+        let mut cpu = make_with_stack(0x1000, 0x400, 0xff0);
+        cpu.pc.write(0x1008);
+        let dump = cpu.format_registers();
+        assert_eq!(dump.lines().count(), 33);
+        assert!(dump.starts_with("pc        = 0x00001008\n"));
+        assert!(dump.contains("x2   sp   = 0x00000ff0\n"));
+        assert!(dump.contains("x31  t6   = 0x00000000\n"));
+    }
+
+    #[test]
+    fn test_x0_hardwired() {
+        let mut cpu = CPUState::make(CPUPolicy {
+            poison: true,
+            ..Default::default()
+        });
+        cpu.write_reg(0, 5);
+        assert_eq!(cpu.read_reg(0), 0);
+        assert_eq!(cpu.gpr[0].read(), 0);
+        cpu.write_reg(1, 5);
+        assert_eq!(cpu.read_reg(1), 5);
+        // Reads go through the guard even if x0 is written directly
+        cpu.gpr[0].write(7);
+        assert_eq!(cpu.read_reg(0), 0);
+    }
+
+    #[test]
+    fn test_program_sees_zero_x0() {
+        use crate::run_wrapper::simulate;
+        use crate::system_call::SyscallIo;
+        use crate::test_utils::load_program;
+
+        let program = [
+            0x12345037, // lui x0, 0x12345
+            0x00000533, // add a0, x0, x0
+            0x00500013, // addi x0, x0, 5
+            0x000005b3, // add a1, x0, x0
+            0x00000613, // addi a2, x0, 0
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for implementation in [
+            Implementation::SingleCycle,
+            Implementation::MultiCycle,
+            Implementation::Pipelined,
+        ] {
+            let mut cpu = CPUState::make(CPUPolicy {
+                implementation,
+                ..Default::default()
+            });
+            let mut mem = load_program(&mut cpu, &program);
+            simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+            for i in [0, 10, 11, 12] {
+                assert_eq!(cpu.read_reg(i), 0, "x{}", i);
+            }
+            assert_eq!(cpu.gpr[0].read(), 0);
+        }
+    }
+
+    #[test]
+    fn test_state_hash() {
+        use crate::run_wrapper::simulate;
+        use crate::system_call::SyscallIo;
+        use crate::test_utils::load_program;
+
+        let program = [
+            0x00002337, // lui t1, 0x2
+            0x02a00293, // addi t0, x0, 42
+            0x00532023, // sw t0, 0(t1)
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let run = || {
+            let mut cpu = CPUState::make(CPUPolicy::default());
+            let mut mem = load_program(&mut cpu, &program);
+            mem.mmu.allocate_page(0x2000);
+            simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+            (cpu, mem)
+        };
+        let (mut cpu, mut mem) = run();
+        let hash = cpu.state_hash(&mem.mmu);
+        let (other_cpu, other_mem) = run();
+        assert_eq!(other_cpu.state_hash(&other_mem.mmu), hash);
+        let pages: Vec<u32> =
+            mem.mmu.dump().iter().map(|&(base, _)| base).collect();
+        assert_eq!(pages, [0x1000, 0x2000]);
+
+        // Any register or byte of memory changes the hash
+        cpu.write_reg(5, 43);
+        assert_ne!(cpu.state_hash(&mem.mmu), hash);
+        cpu.write_reg(5, 42);
+        assert_eq!(cpu.state_hash(&mem.mmu), hash);
+        mem.mmu.set8(0x2001, 1);
+        assert_ne!(cpu.state_hash(&mem.mmu), hash);
+    }
+
+    #[test]
+    fn test_fnv1a() {
+        assert_eq!(fnv1a(FNV_OFFSET, b""), 0xcbf29ce484222325);
+        assert_eq!(fnv1a(FNV_OFFSET, b"a"), 0xaf63dc4c8601ec8c);
+        assert_eq!(fnv1a(FNV_OFFSET, b"foobar"), 0x85944171f73967e8);
+        // Folding in pieces is the same as all at once
+        assert_eq!(
+            fnv1a(fnv1a(FNV_OFFSET, b"foo"), b"bar"),
+            0x85944171f73967e8
+        );
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        // The deepest valid SP
+        assert!(!make_with_stack(0x1000, 0x400, 0xc01).stack_overflow());
+        // One byte past the limit
+        assert!(make_with_stack(0x1000, 0x400, 0xc00).stack_overflow());
+        assert!(matches!(
+            make_with_stack(0x1000, 0x400, 0xc00).check_stack_overflow(),
+            Err(SimulatorError::StackOverflow(0xc00))
+        ));
+        // A stack larger than its base reaches down to address 0
+        assert!(!make_with_stack(0x100, 0x400, 0).stack_overflow());
+        assert_eq!(make_with_stack(0x100, 0x400, 0).stack_limit(), None);
+    }
+
+    #[test]
+    fn test_exit_syscall() {
+        let policy = CPUPolicy {
+            exit_syscall: Some(93),
+            ..Default::default()
+        };
+        assert!(policy.validate().is_ok());
+        assert!(policy.is_exit(93));
+        assert!(policy.is_exit(3));
+        assert!(!CPUPolicy::default().is_exit(93));
+
+        let policy = CPUPolicy {
+            exit_syscall: Some(1),
+            ..Default::default()
+        };
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn test_timer_range() {
+        let policy = |timer| CPUPolicy {
+            timer: Some(timer),
+            ..Default::default()
+        };
+        assert!(policy(0xfffffff0).validate().is_ok());
+        assert!(policy(0xfffffff8).validate().is_err());
+    }
+
+    #[test]
+    fn test_make_with() {
+        use crate::memory::inclusive::InclusiveCache;
+        use crate::memory::StorageInterface;
+        use crate::single_cycle;
+        use crate::system_call::SyscallIo;
+
+        let mut cpu = CPUState::make_with(CPUPolicy::default(), 0x4000, 0x8000);
+        assert_eq!(cpu.pc.read(), 0x4000);
+        assert_eq!(cpu.read_reg(2), 0x8000);
+
+        let program: [u32; 3] = [
+            0x02a00093, // addi ra, x0, 42
+            0xff010113, // addi sp, sp, -16
+            0x00112623, // sw ra, 12(sp)
+        ];
+        let bytes: Vec<u8> =
+            program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let mut mem = InclusiveCache::default();
+        mem.mmu.write_bytes(0x4000, &bytes);
+        mem.mmu.allocate_page(0x7000);
+
+        let mut io = SyscallIo::stdio();
+        let exit_pc =
+            single_cycle::fast_forward(&mut cpu, &mut mem, &mut io, 3).unwrap();
+        assert_eq!(exit_pc, None);
+        assert_eq!(cpu.pc.read(), 0x400c);
+        assert_eq!(cpu.read_reg(2), 0x7ff0);
+        assert_eq!(mem.get32(0x7ffc, &mut None).unwrap(), 42);
+    }
 }
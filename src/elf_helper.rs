@@ -1,48 +1,145 @@
 //! Helper functions for parsing ELF files
 
-use object::{elf, read::elf::FileHeader};
-use std::error::Error;
-use std::fs;
+use crate::error::{SimulatorError, SimulatorResult};
+use object::{
+    elf,
+    read::elf::{FileHeader, Sym},
+};
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
 
 pub type ELFReaderType = elf::FileHeader32<object::Endianness>;
 pub type Segment = elf::ProgramHeader32<object::Endianness>;
 
 /// Returns the program entry address
-pub fn get_elf_entry(
-    elf_reader: &ELFReaderType,
-) -> Result<u32, Box<dyn Error>> {
+pub fn get_elf_entry(elf_reader: &ELFReaderType) -> SimulatorResult<u32> {
     let endian = get_elf_endian(elf_reader)?;
-    return Ok(elf_reader.e_entry(endian));
+    Ok(elf_reader.e_entry(endian))
 }
 
 /// Returns the pair (ELF reader, binary data)
 pub fn parse_elf_file(
     file_path: &str,
-) -> Result<(ELFReaderType, Vec<u8>), Box<dyn Error>> {
+) -> SimulatorResult<(ELFReaderType, Vec<u8>)> {
     let data = fs::read(file_path)?;
+    parse_elf_bytes(data)
+}
+
+/// Returns the pair (ELF reader, binary data)
+/// from the content of an ELF file
+pub fn parse_elf_bytes(
+    data: Vec<u8>,
+) -> SimulatorResult<(ELFReaderType, Vec<u8>)> {
     let elf = elf::FileHeader32::<object::Endianness>::parse(&*data)?;
-    return Ok((*elf, data));
+    Ok((*elf, data))
 }
 
 /// Returns the endianness
 pub fn get_elf_endian(
     elf_reader: &ELFReaderType,
-) -> Result<object::Endianness, Box<dyn Error>> {
-    return Ok(elf_reader.endian()?);
+) -> SimulatorResult<object::Endianness> {
+    Ok(elf_reader.endian()?)
 }
 
 /// Returns the machine type
-pub fn get_elf_machine(
-    elf_reader: &ELFReaderType,
-) -> Result<u16, Box<dyn Error>> {
-    return Ok(elf_reader.e_machine(get_elf_endian(elf_reader)?));
+pub fn get_elf_machine(elf_reader: &ELFReaderType) -> SimulatorResult<u16> {
+    Ok(elf_reader.e_machine(get_elf_endian(elf_reader)?))
 }
 
 /// Return it as a vector for good
 pub fn get_elf_segments(
     elf_reader: &ELFReaderType,
     elf_data: &[u8],
-) -> Result<Vec<Segment>, Box<dyn Error>> {
+) -> SimulatorResult<Vec<Segment>> {
     let endian = get_elf_endian(elf_reader)?;
-    return Ok(elf_reader.program_headers(endian, elf_data)?.to_vec());
+    Ok(elf_reader.program_headers(endian, elf_data)?.to_vec())
+}
+
+/// Returns the pair (ELF reader, program headers),
+/// reading nothing but the headers from the file
+pub fn read_elf_headers(
+    file: &mut File,
+) -> SimulatorResult<(ELFReaderType, Vec<Segment>)> {
+    let mut header = vec![0; std::mem::size_of::<ELFReaderType>()];
+    file.seek(SeekFrom::Start(0))?;
+    file.read_exact(&mut header)?;
+    let elf_reader = *elf::FileHeader32::<object::Endianness>::parse(&*header)?;
+
+    let endian = get_elf_endian(&elf_reader)?;
+    let count = elf_reader.e_phnum(endian) as usize;
+    if count > 0
+        && elf_reader.e_phentsize(endian) as usize
+            != std::mem::size_of::<Segment>()
+    {
+        return Err(SimulatorError::InvalidFormat(
+            "Invalid program header size".to_string(),
+        ));
+    }
+    let mut table = vec![0; count * std::mem::size_of::<Segment>()];
+    file.seek(SeekFrom::Start(elf_reader.e_phoff(endian) as u64))?;
+    file.read_exact(&mut table)?;
+    let (segments, _) = object::pod::slice_from_bytes::<Segment>(&table, count)
+        .map_err(|_| {
+            SimulatorError::InvalidFormat(
+                "Invalid program header table".to_string(),
+            )
+        })?;
+    Ok((elf_reader, segments.to_vec()))
+}
+
+/// Returns the named symbols from .symtab as (name, address, size),
+/// sorted by address
+pub fn get_symbols(
+    elf_reader: &ELFReaderType,
+    elf_data: &[u8],
+) -> SimulatorResult<Vec<(String, u32, u32)>> {
+    let endian = get_elf_endian(elf_reader)?;
+    let sections = elf_reader.sections(endian, elf_data)?;
+    let table = sections.symbols(endian, elf_data, elf::SHT_SYMTAB)?;
+    let mut symbols = Vec::new();
+    for symbol in table.iter() {
+        let name = symbol.name(endian, table.strings())?;
+        if name.is_empty() {
+            continue;
+        }
+        symbols.push((
+            String::from_utf8_lossy(name).into_owned(),
+            symbol.st_value(endian),
+            symbol.st_size(endian),
+        ));
+    }
+    symbols.sort_by_key(|&(_, address, _)| address);
+    Ok(symbols)
+}
+
+/// Returns the symbol containing the PC along with the offset into it.
+/// Symbols without a size contain nothing
+pub fn resolve_address(
+    symbols: &[(String, u32, u32)],
+    pc: u32,
+) -> Option<(&str, u32)> {
+    symbols
+        .iter()
+        .find(|(_, address, size)| *address <= pc && pc - address < *size)
+        .map(|(name, address, _)| (name.as_str(), pc - address))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_address() {
+        let (elf_reader, elf_data) =
+            parse_elf_file("test-riscv/helloworld.riscv").unwrap();
+        let symbols = get_symbols(&elf_reader, &elf_data).unwrap();
+        assert!(symbols.contains(&("main".to_string(), 0x10188, 0x38)));
+        assert!(symbols.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+
+        assert_eq!(resolve_address(&symbols, 0x10188), Some(("main", 0)));
+        assert_eq!(resolve_address(&symbols, 0x101f0), Some(("print_s", 8)));
+        // Right past the end of print_d is print_s
+        assert_eq!(resolve_address(&symbols, 0x101e8), Some(("print_s", 0)));
+        assert_eq!(resolve_address(&symbols, 0), None);
+    }
 }
@@ -0,0 +1,237 @@
+//! Simulator error types
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use core::error::Error;
+use core::fmt;
+#[cfg(feature = "std")]
+use plotters::drawing::DrawingAreaErrorKind;
+#[cfg(feature = "std")]
+use std::io;
+
+/// Errors raised by the simulator and its tooling
+#[derive(Debug)]
+pub enum SimulatorError {
+    /// File or console I/O failed
+    #[cfg(feature = "std")]
+    Io(io::Error),
+    /// The ELF file is malformed
+    #[cfg(feature = "std")]
+    Elf(object::read::Error),
+    /// The ELF file is well-formed but not supported
+    InvalidFormat(String),
+    /// Reading or writing a CSV file failed
+    #[cfg(feature = "std")]
+    Csv(csv::Error),
+    /// Drawing a plot failed
+    Plot(String),
+    /// An instruction can't be assembled
+    InvalidAssembly(String),
+    /// A raw instruction can't be decoded
+    InvalidInstruction(u32),
+    /// A raw instruction fetched from the PC can't be decoded,
+    /// as the pair (PC, raw instruction)
+    InvalidInstructionAt(u32, u32),
+    /// A decoded instruction the simulator can't execute, e.g., WFI
+    UnsupportedInstruction(String),
+    /// Control signals that contradict each other, i.e., a decode bug
+    InvalidControls(String),
+    /// The simulated memory can't serve an access
+    Memory(MemoryError),
+    /// The program hasn't exited within the given number of instructions
+    InstructionLimit(u64),
+    /// SP has grown past the bottom of the stack, holding the SP
+    StackOverflow(u32),
+    /// The instruction at the PC keeps branching to itself
+    Hang(u32),
+    /// The division at the PC divides by zero,
+    /// which only traps if the policy asks for it
+    DivisionByZero(u32),
+    /// Anything else, described by a message
+    Other(String),
+}
+
+pub type SimulatorResult<T> = Result<T, SimulatorError>;
+
+impl fmt::Display for SimulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(feature = "std")]
+            SimulatorError::Io(e) => write!(f, "I/O error: {}", e),
+            #[cfg(feature = "std")]
+            SimulatorError::Elf(e) => write!(f, "ELF error: {}", e),
+            SimulatorError::InvalidFormat(message) => {
+                write!(f, "Invalid format: {}", message)
+            }
+            #[cfg(feature = "std")]
+            SimulatorError::Csv(e) => write!(f, "CSV error: {}", e),
+            SimulatorError::Plot(message) => {
+                write!(f, "Plot error: {}", message)
+            }
+            SimulatorError::InvalidAssembly(message) => {
+                write!(f, "Invalid assembly: {}", message)
+            }
+            SimulatorError::InvalidInstruction(raw_inst) => {
+                write!(f, "Failed to decode instruction {:#010x}", raw_inst)
+            }
+            SimulatorError::InvalidInstructionAt(pc, raw_inst) => write!(
+                f,
+                "Failed to decode instruction {:#010x} at PC {:#010x}",
+                raw_inst, pc
+            ),
+            SimulatorError::UnsupportedInstruction(name) => {
+                write!(f, "Unsupported privileged instruction: {}", name)
+            }
+            SimulatorError::InvalidControls(message) => {
+                write!(f, "Invalid control signals: {}", message)
+            }
+            SimulatorError::Memory(e) => write!(f, "Memory error: {}", e),
+            SimulatorError::InstructionLimit(limit) => {
+                write!(f, "Exceeded the limit of {} instructions", limit)
+            }
+            SimulatorError::StackOverflow(sp) => {
+                write!(f, "Stack overflow with SP at {:#010x}", sp)
+            }
+            SimulatorError::DivisionByZero(pc) => {
+                write!(f, "Division by zero at PC {:#010x}", pc)
+            }
+            SimulatorError::Hang(pc) => write!(
+                f,
+                "The branch at PC {:#010x} keeps branching to itself; the program likely hangs",
+                pc
+            ),
+            SimulatorError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Errors raised by memory accesses
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryError {
+    /// Reading from an address whose page is never allocated
+    ReadUnallocated(u32),
+    /// Writing to an address whose page is never allocated
+    WriteUnallocated(u32),
+    /// A string starting at the address without a NUL in reach
+    UnterminatedString(u32),
+}
+
+impl fmt::Display for MemoryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryError::ReadUnallocated(address) => {
+                write!(f, "reading unallocated address {:#010x}", address)
+            }
+            MemoryError::WriteUnallocated(address) => {
+                write!(f, "writing unallocated address {:#010x}", address)
+            }
+            MemoryError::UnterminatedString(address) => {
+                write!(f, "unterminated string at {:#010x}", address)
+            }
+        }
+    }
+}
+
+impl From<MemoryError> for SimulatorError {
+    fn from(e: MemoryError) -> Self {
+        SimulatorError::Memory(e)
+    }
+}
+
+impl Error for SimulatorError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            #[cfg(feature = "std")]
+            SimulatorError::Io(e) => Some(e),
+            #[cfg(feature = "std")]
+            SimulatorError::Elf(e) => Some(e),
+            #[cfg(feature = "std")]
+            SimulatorError::Csv(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for SimulatorError {
+    fn from(e: io::Error) -> Self {
+        SimulatorError::Io(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<object::read::Error> for SimulatorError {
+    fn from(e: object::read::Error) -> Self {
+        SimulatorError::Elf(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<csv::Error> for SimulatorError {
+    fn from(e: csv::Error) -> Self {
+        SimulatorError::Csv(e)
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E: Error + Send + Sync> From<DrawingAreaErrorKind<E>> for SimulatorError {
+    fn from(e: DrawingAreaErrorKind<E>) -> Self {
+        SimulatorError::Plot(e.to_string())
+    }
+}
+
+impl From<Box<dyn Error>> for SimulatorError {
+    fn from(e: Box<dyn Error>) -> Self {
+        SimulatorError::Other(e.to_string())
+    }
+}
+
+impl From<String> for SimulatorError {
+    fn from(message: String) -> Self {
+        SimulatorError::Other(message)
+    }
+}
+
+impl From<&str> for SimulatorError {
+    fn from(message: &str) -> Self {
+        SimulatorError::Other(message.to_string())
+    }
+}
+
+/// Conversion into a SimulatorResult,
+/// so that call sites don't need map_err closures
+pub trait IntoSimulatorResult<T> {
+    fn into_simulator_result(self) -> SimulatorResult<T>;
+}
+
+impl<T, E: Into<SimulatorError>> IntoSimulatorResult<T> for Result<T, E> {
+    fn into_simulator_result(self) -> SimulatorResult<T> {
+        self.map_err(Into::into)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_error() {
+        let result: io::Result<()> =
+            Err(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        let error = result.into_simulator_result().unwrap_err();
+        match &error {
+            SimulatorError::Io(e) => {
+                assert_eq!(e.kind(), io::ErrorKind::NotFound)
+            }
+            _ => panic!("Expected an I/O error"),
+        }
+        assert_eq!(error.to_string(), "I/O error: no such file");
+        assert!(error.source().is_some());
+    }
+
+    #[test]
+    fn test_ok() {
+        let result: io::Result<i32> = Ok(42);
+        assert_eq!(result.into_simulator_result().unwrap(), 42);
+    }
+}
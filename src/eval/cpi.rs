@@ -1,10 +1,30 @@
 use sim_lib::cpu::CPUPolicy;
+use sim_lib::error::SimulatorResult;
 use sim_lib::run_wrapper::run;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let output_path = format!("eval/sim_eval.csv");
+/// Instructions a program may execute before it's given up on,
+/// unless overridden by --max-instructions
+const MAX_INSTRUCTIONS: u64 = 100_000_000;
+
+fn main() -> SimulatorResult<()> {
+    let param_tokens: Vec<String> = std::env::args().collect();
+    let max_instructions = match param_tokens.get(1).map(String::as_str) {
+        Some("--max-instructions") => param_tokens
+            .get(2)
+            .ok_or("You should specify an instruction count after --max-instructions")?
+            .parse()
+            .map_err(|_| "Invalid instruction count after --max-instructions")?,
+        Some(arg) => return Err(format!("Unknown parameter: {}", arg).into()),
+        None => MAX_INSTRUCTIONS,
+    };
+    let policy = CPUPolicy {
+        max_instructions: Some(max_instructions),
+        ..Default::default()
+    };
+
+    let output_path = "eval/sim_eval.csv".to_string();
     let mut writer = csv::Writer::from_path(output_path)?;
-    writer.write_record(&[
+    writer.write_record([
         "Program",
         "CPI (ideal)",
         "CPI (caching)",
@@ -29,9 +49,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     for program in programs {
         let program_path = format!("test/{}.riscv", program);
         eprintln!("Running program: {}", program_path);
+        // A failing program, e.g., one that never exits,
+        // gets an error row and the sweep goes on
         let (ideal_cpi, caching_cpi, no_caching_cpi, ratio) =
-            run(&program_path, CPUPolicy::default())?;
-        writer.write_record(&[
+            match run(&program_path, &[], policy) {
+                Ok(stats) => stats,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    writer.write_record([program, "Error", "", "", ""])?;
+                    continue;
+                }
+            };
+        writer.write_record([
             program,
             &format!("{:.3}", ideal_cpi),
             &format!("{:.3}", caching_cpi),
@@ -1,64 +1,82 @@
-use sim_lib::memory::cache::CachePolicy;
+use sim_lib::error::SimulatorResult;
+use sim_lib::memory::cache::{parse_levels, CachePolicy};
 use sim_lib::memory::exclusive::ExclusiveCache;
 use sim_lib::memory::inclusive::InclusiveCache;
 use sim_lib::run_wrapper::run_trace;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Reports errors such as an invalid --level by their message,
+/// rather than by their debug representation
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> SimulatorResult<()> {
     let param_tokens: Vec<String> = std::env::args().collect();
     let trace_path = param_tokens
         .get(1)
         .ok_or("You should specify exactly one trace file")?;
-    let trace_base_name = String::from(trace_path.split('/').last().unwrap());
+    // The default 3 levels unless given by --level
+    let levels = parse_levels(&param_tokens[2..])?;
+    let make_multi_level = || -> SimulatorResult<InclusiveCache> {
+        if levels.is_empty() {
+            return Ok(InclusiveCache::default());
+        }
+        InclusiveCache::builder()
+            .levels(&levels)
+            .miss_penalty(100)
+            .build()
+    };
+    let trace_base_name =
+        String::from(trace_path.split('/').next_back().unwrap());
     let output_path = format!("eval/multi_eval_{}.csv", trace_base_name);
 
     let mut writer = csv::Writer::from_path(output_path)?;
-    writer.write_record(&["Policy", "AMAT"])?;
+    writer.write_record(["Policy", "AMAT"])?;
 
     // Default single-level cache
     {
-        let mut mem = InclusiveCache::make(
-            vec![CachePolicy::default()],
-            Default::default(),
-            Default::default(),
-            100,
-            false,
-        );
-        let amat = run_trace(&mut mem, &trace_path);
-        writer.write_record(&["Single-level", &format!("{:.3}", amat)])?;
+        let mut mem = InclusiveCache::builder()
+            .level(CachePolicy::default())
+            .miss_penalty(100)
+            .build()?;
+        let amat = run_trace(&mut mem, trace_path);
+        writer.write_record(["Single-level", &format!("{:.3}", amat)])?;
     }
 
-    // Default 3-level inclusive cache
+    // Multi-level inclusive cache
     {
-        let mut mem = InclusiveCache::default();
-        let amat = run_trace(&mut mem, &trace_path);
+        let mut mem = make_multi_level()?;
+        let amat = run_trace(&mut mem, trace_path);
         mem.verify_inclusiveness();
-        writer.write_record(&[
-            "Multi-level inclusive",
-            &format!("{:.3}", amat),
-        ])?;
+        writer
+            .write_record(["Multi-level inclusive", &format!("{:.3}", amat)])?;
     }
 
-    // 3-level inclusive cache with victim cache
+    // Multi-level inclusive cache with victim cache
     {
-        let mut mem = InclusiveCache::default();
-        mem.use_victim_cache = true;
-        let amat = run_trace(&mut mem, &trace_path);
+        let mut mem = make_multi_level()?;
+        mem.attach_victim_cache(0);
+        let amat = run_trace(&mut mem, trace_path);
         mem.verify_inclusiveness();
-        writer.write_record(&[
+        writer.write_record([
             "Multi-level inclusive with VC",
             &format!("{:.3}", amat),
         ])?;
     }
 
-    // 3-level exclusive cache
+    // Multi-level exclusive cache
     {
-        let mut mem = ExclusiveCache::default();
-        let amat = run_trace(&mut mem, &trace_path);
+        let mut mem = match levels.is_empty() {
+            true => ExclusiveCache::default(),
+            false => ExclusiveCache::make(levels.clone(), 100),
+        };
+        let amat = run_trace(&mut mem, trace_path);
         mem.verify_exclusiveness();
-        writer.write_record(&[
-            "Multi-level exclusive",
-            &format!("{:.3}", amat),
-        ])?;
+        writer
+            .write_record(["Multi-level exclusive", &format!("{:.3}", amat)])?;
     }
 
     Ok(())
@@ -1,36 +1,54 @@
+use sim_lib::error::{IntoSimulatorResult, SimulatorResult};
 use sim_lib::{memory::inclusive::InclusiveCache, run_wrapper::run_trace};
 use std::vec;
 
-use sim_lib::memory::cache::CachePolicy;
+use sim_lib::memory::cache::{parse_levels, CachePolicy};
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// Reports errors such as an invalid --level by their message,
+/// rather than by their debug representation
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+fn run() -> SimulatorResult<()> {
     let param_tokens: Vec<String> = std::env::args().collect();
     let trace_path = param_tokens
         .get(1)
         .ok_or("You should specify exactly one trace file")?;
 
+    // Given levels are evaluated as they are, without plotting
+    let levels = parse_levels(&param_tokens[2..])?;
+    if !levels.is_empty() {
+        let mut mem = InclusiveCache::builder()
+            .levels(&levels)
+            .miss_penalty(100)
+            .build()?;
+        let amat = run_trace(&mut mem, trace_path);
+        println!("AMAT = {:.3}", amat);
+        return Ok(());
+    }
+
     // Plot line series for each cache size
     // For a fixed cache size, varie the block size
     // Performance metric: miss rate
     // Cache sizes: 4KB, 16KB, 64KB, 256KB, 1MB
-    let cache_sizes =
-        vec![4 * 1024, 16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024];
+    let cache_sizes = [4 * 1024, 16 * 1024, 64 * 1024, 256 * 1024, 1024 * 1024];
     // Block sizes: 32B, 64B, 128B, 256B
-    let block_sizes = vec![32, 64, 128, 256];
+    let block_sizes = [32, 64, 128, 256];
 
     // Propagate the data
     let mut data: Vec<Vec<(usize, f64)>> = vec![vec![]; cache_sizes.len()];
     let mut y_max: f64 = 0.;
     for (i, cache_size) in cache_sizes.iter().enumerate() {
         for block_size in block_sizes.iter() {
-            let mut mem = InclusiveCache::make(
-                vec![CachePolicy::make(*cache_size, *block_size, 1, 1)],
-                Default::default(),
-                Default::default(),
-                100,
-                false,
-            );
-            let amat = run_trace(&mut mem, &trace_path);
+            let mut mem = InclusiveCache::builder()
+                .level(CachePolicy::make_scaled(*cache_size, *block_size, 1))
+                .miss_penalty(100)
+                .build()?;
+            let amat = run_trace(&mut mem, trace_path);
             data[i].push((*block_size, amat));
             y_max = y_max.max(amat);
         }
@@ -38,14 +56,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Plot the data
     use plotters::prelude::*;
 
-    let trace_base_name = String::from(trace_path.split('/').last().unwrap());
+    let trace_base_name =
+        String::from(trace_path.split('/').next_back().unwrap());
     let plot_title =
         format!("Single level evaluation (AMAT): {}", trace_base_name);
     let output_path = format!("eval/single_eval_{}.svg", trace_base_name);
 
     let root =
         SVGBackend::new(output_path.as_str(), (800, 600)).into_drawing_area();
-    root.fill(&WHITE).unwrap();
+    root.fill(&WHITE).into_simulator_result()?;
 
     let mut ctx = ChartBuilder::on(&root)
         .caption(plot_title.as_str(), ("sans-serif", 40).into_font())
@@ -53,19 +72,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .x_label_area_size(40)
         .y_label_area_size(40)
         .build_cartesian_2d(32..256, 0.0..y_max * 1.1)
-        .unwrap();
+        .into_simulator_result()?;
     ctx.configure_mesh()
         .x_desc("Block size")
         .y_desc("AMAT")
         .draw()
-        .unwrap();
+        .into_simulator_result()?;
 
     for (i, cache_size) in cache_sizes.iter().enumerate() {
         let series = data[i].iter().map(|(x, y)| (*x as i32, *y));
         let label = format!("Cache size = {}", cache_size);
         let color = Palette99::pick(i).to_rgba();
         ctx.draw_series(LineSeries::new(series, color))
-            .unwrap()
+            .into_simulator_result()?
             .label(label)
             .legend(move |(x, y)| {
                 PathElement::new(vec![(x, y), (x + 20, y)], color)
@@ -73,10 +92,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     }
 
     ctx.configure_series_labels()
-        .background_style(&WHITE.mix(0.8))
-        .border_style(&BLACK)
+        .background_style(WHITE.mix(0.8))
+        .border_style(BLACK)
         .draw()
-        .unwrap();
+        .into_simulator_result()?;
 
     Ok(())
 }
@@ -0,0 +1,151 @@
+//! Expansion of compressed (RVC) instructions
+//! into their 32-bit equivalents.
+//! Only a common subset is supported:
+//! C.ADDI, C.LI, C.LW, C.SW, C.J, C.BEQZ, C.BNEZ and C.MV
+
+use super::encode_helper::{encode_b, encode_i, encode_j, encode_r, encode_s};
+use super::Function;
+use crate::error::{SimulatorError, SimulatorResult};
+
+/// Whether the raw instruction is compressed,
+/// i.e., its lowest two bits aren't 0b11
+pub fn is_compressed(raw_inst: u32) -> bool {
+    raw_inst & 0b11 != 0b11
+}
+
+/// Returns the size of the raw instruction in bytes
+pub fn length(raw_inst: u32) -> u32 {
+    match is_compressed(raw_inst) {
+        true => 2,
+        false => 4,
+    }
+}
+
+/// Extracts bits [hi:lo] of the raw instruction
+fn bits(raw_inst: u32, hi: u32, lo: u32) -> u32 {
+    (raw_inst >> lo) & ((1 << (hi - lo + 1)) - 1)
+}
+
+/// Sign-extends the lowest width bits
+fn sign_extend(value: u32, width: u32) -> i32 {
+    ((value << (32 - width)) as i32) >> (32 - width)
+}
+
+/// The 3-bit register fields address x8-x15
+fn compact_register(field: u32) -> u32 {
+    field + 8
+}
+
+/// Expands a compressed instruction into its 32-bit equivalent
+pub fn expand(raw_inst: u16) -> SimulatorResult<u32> {
+    let raw_inst = raw_inst as u32;
+    let invalid = Err(SimulatorError::InvalidInstruction(raw_inst));
+    let funct3 = bits(raw_inst, 15, 13);
+    let rd = bits(raw_inst, 11, 7);
+    let rs2 = bits(raw_inst, 6, 2);
+    let rs1_compact = compact_register(bits(raw_inst, 9, 7));
+    let rs2_compact = compact_register(bits(raw_inst, 4, 2));
+    // imm[5] | imm[4:0] of C.ADDI and C.LI
+    let imm6 = sign_extend((bits(raw_inst, 12, 12) << 5) | rs2, 6);
+    // uimm[5:3] | uimm[2] | uimm[6] of C.LW and C.SW
+    let word_offset = ((bits(raw_inst, 12, 10) << 3)
+        | (bits(raw_inst, 6, 6) << 2)
+        | (bits(raw_inst, 5, 5) << 6)) as i32;
+    // offset[8] | offset[4:3] | offset[7:6] | offset[2:1] | offset[5]
+    // of C.BEQZ and C.BNEZ
+    let branch_offset = sign_extend(
+        (bits(raw_inst, 12, 12) << 8)
+            | (bits(raw_inst, 11, 10) << 3)
+            | (bits(raw_inst, 6, 5) << 6)
+            | (bits(raw_inst, 4, 3) << 1)
+            | (bits(raw_inst, 2, 2) << 5),
+        9,
+    );
+
+    use Function::*;
+    let expanded = match (bits(raw_inst, 1, 0), funct3) {
+        // The all-zero instruction is defined to be illegal
+        (0b00, _) if raw_inst == 0 => return invalid,
+        (0b00, 0b010) => encode_i(LW, rs2_compact, rs1_compact, word_offset),
+        (0b00, 0b110) => encode_s(SW, rs1_compact, rs2_compact, word_offset),
+        // C.NOP is C.ADDI with rd = x0
+        (0b01, 0b000) => encode_i(ADDI, rd, rd, imm6),
+        (0b01, 0b010) => encode_i(ADDI, rd, 0, imm6),
+        (0b01, 0b101) => {
+            // offset[11|4|9:8|10|6|7|3:1|5]
+            let offset = sign_extend(
+                (bits(raw_inst, 12, 12) << 11)
+                    | (bits(raw_inst, 11, 11) << 4)
+                    | (bits(raw_inst, 10, 9) << 8)
+                    | (bits(raw_inst, 8, 8) << 10)
+                    | (bits(raw_inst, 7, 7) << 6)
+                    | (bits(raw_inst, 6, 6) << 7)
+                    | (bits(raw_inst, 5, 3) << 1)
+                    | (bits(raw_inst, 2, 2) << 5),
+                12,
+            );
+            encode_j(JAL, 0, offset)
+        }
+        (0b01, 0b110) => encode_b(BEQ, rs1_compact, 0, branch_offset),
+        (0b01, 0b111) => encode_b(BNE, rs1_compact, 0, branch_offset),
+        // C.MV; C.JR has rs2 = x0
+        (0b10, 0b100) if bits(raw_inst, 12, 12) == 0 && rs2 != 0 => {
+            encode_r(ADD, rd, 0, rs2)
+        }
+        _ => return invalid,
+    };
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::assemble;
+
+    #[test]
+    fn test_expand() {
+        let cases = [
+            (0x0505, "addi a0, a0, 1"),
+            (0x1161, "addi sp, sp, -8"),
+            (0x0001, "addi x0, x0, 0"),
+            (0x4515, "addi a0, x0, 5"),
+            (0x557d, "addi a0, x0, -1"),
+            (0x4108, "lw a0, 0(a0)"),
+            (0x5fe4, "lw s1, 124(a5)"),
+            (0xc10c, "sw a1, 0(a0)"),
+            (0xdfe4, "sw s1, 124(a5)"),
+            (0xa011, "jal x0, 4"),
+            (0xbff5, "jal x0, -4"),
+            (0xaffd, "jal x0, 2046"),
+            (0xc119, "beq a0, x0, 6"),
+            (0xdd7d, "beq a0, x0, -2"),
+            (0xe119, "bne a0, x0, 6"),
+            (0x85aa, "add a1, x0, a0"),
+        ];
+        for (raw_inst, line) in cases {
+            assert_eq!(
+                expand(raw_inst).unwrap(),
+                assemble(line).unwrap(),
+                "{:#06x}",
+                raw_inst
+            );
+        }
+    }
+
+    #[test]
+    fn test_unsupported() {
+        // The illegal instruction, C.ADDI4SPN, C.JR and C.ADD
+        for raw_inst in [0x0000, 0x0040, 0x8082, 0x95aa] {
+            assert!(matches!(
+                expand(raw_inst),
+                Err(SimulatorError::InvalidInstruction(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_length() {
+        assert_eq!(length(0x0505), 2);
+        assert_eq!(length(0x00000013), 4);
+    }
+}
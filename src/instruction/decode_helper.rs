@@ -2,6 +2,7 @@
 //! Many drawn from <https://github.com/djanderson/riscv-5stage-simulator/blob/master/src/instruction/decoder.rs>
 
 use super::{Attributes, Controls, Format, Function, Instruction, Opcode};
+use crate::error::{SimulatorError, SimulatorResult};
 
 /// Extracts the sign-extended immediate from an instruction
 fn get_imm_sign_extended(inst: &Instruction) -> Option<u32> {
@@ -12,14 +13,23 @@ fn get_imm_sign_extended(inst: &Instruction) -> Option<u32> {
         _ => 20,
     };
 
-    match inst.attributes.imm {
-        Some(v) => Some((((v as i32) << shamt) >> shamt) as u32),
-        None => None,
-    }
+    inst.attributes
+        .imm
+        .map(|v| (((v as i32) << shamt) >> shamt) as u32)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Number of get_function calls on this thread,
+    /// so tests can tell whether a full decode happened
+    pub(crate) static GET_FUNCTION_CALLS: std::cell::Cell<usize> =
+        const { std::cell::Cell::new(0) };
 }
 
 /// Determines an instruction's mnemonic, e.g., JAL, XOR, or SRA
-fn get_function(inst: &Instruction) -> Function {
+fn get_function(inst: &Instruction) -> SimulatorResult<Function> {
+    #[cfg(test)]
+    GET_FUNCTION_CALLS.with(|calls| calls.set(calls.get() + 1));
     use Function::*;
     use Opcode::*;
     // Opcode-determined ones
@@ -28,14 +38,18 @@ fn get_function(inst: &Instruction) -> Function {
         AuiPc => AUIPC,
         Jal => JAL,
         Jalr => JALR,
-        System => ECALL,
+        System => get_system_function(inst.raw_inst)?,
+        Amo => get_amo_function(inst.raw_inst)?,
+        Op if inst.attributes.funct7 == Some(0x01) => {
+            get_muldiv_function(inst.raw_inst)?
+        }
         _ => Function::default(),
     };
     if function != Function::default() {
-        return function;
+        return Ok(function);
     }
 
-    match (
+    let function = match (
         inst.opcode,
         inst.attributes.funct3.unwrap(),
         (inst.raw_inst & 0x40000000) >> 30,
@@ -73,8 +87,55 @@ fn get_function(inst: &Instruction) -> Function {
         (Op, 0b101, 0b1) => SRA,
         (Op, 0b110, _) => OR,
         (Op, 0b111, _) => AND,
-        _ => panic!("Failed to decode instruction {:#0x}", inst.raw_inst),
-    }
+        _ => return Err(SimulatorError::InvalidInstruction(inst.raw_inst)),
+    };
+    Ok(function)
+}
+
+/// Determines a system instruction from its funct12 (or funct7) field,
+/// where rd and funct3 (and rs1 unless SFENCE.VMA) must be zero
+fn get_system_function(raw_inst: u32) -> SimulatorResult<Function> {
+    use Function::*;
+    let function = match (raw_inst >> 20, raw_inst & 0x000fff80) {
+        (0x000, 0) => ECALL,
+        (0x001, 0) => EBREAK,
+        (0x102, 0) => SRET,
+        (0x302, 0) => MRET,
+        (0x105, 0) => WFI,
+        (funct12, rest) if funct12 >> 5 == 0x09 && rest & 0x7f80 == 0 => {
+            SFENCEVMA
+        }
+        _ => return Err(SimulatorError::InvalidInstruction(raw_inst)),
+    };
+    Ok(function)
+}
+
+/// Determines an atomic instruction from its funct5 field,
+/// ignoring the aq and rl bits, where funct3 must be 0b010 (word)
+/// and rs2 of LR.W must be zero
+fn get_amo_function(raw_inst: u32) -> SimulatorResult<Function> {
+    use Function::*;
+    let function =
+        match (raw_inst >> 27, get_funct3(raw_inst), get_rs2(raw_inst)) {
+            (0b00010, 0b010, 0) => LRW,
+            (0b00011, 0b010, _) => SCW,
+            _ => return Err(SimulatorError::InvalidInstruction(raw_inst)),
+        };
+    Ok(function)
+}
+
+/// Determines an M extension instruction from its funct3 field,
+/// of which only the divisions are supported
+fn get_muldiv_function(raw_inst: u32) -> SimulatorResult<Function> {
+    use Function::*;
+    let function = match get_funct3(raw_inst) {
+        0b100 => DIV,
+        0b101 => DIVU,
+        0b110 => REM,
+        0b111 => REMU,
+        _ => return Err(SimulatorError::InvalidInstruction(raw_inst)),
+    };
+    Ok(function)
 }
 
 pub fn get_controls(inst: &Instruction) -> Controls {
@@ -83,26 +144,14 @@ pub fn get_controls(inst: &Instruction) -> Controls {
     use Opcode::*;
 
     Controls {
-        branch: match inst.opcode {
-            Branch | Jal | Jalr => true,
-            _ => false,
-        },
-        mem_read: match inst.opcode {
-            Opcode::Load => true,
-            _ => false,
-        },
-        mem_write: match inst.opcode {
-            Opcode::Store => true,
-            _ => false,
-        },
-        reg_write: match inst.opcode {
-            Branch | Store => false,
-            _ => true,
-        },
+        branch: matches!(inst.opcode, Branch | Jal | Jalr),
+        mem_read: inst.opcode == Opcode::Load || inst.function == LRW,
+        mem_write: inst.opcode == Opcode::Store || inst.function == SCW,
+        reg_write: !matches!(inst.opcode, Branch | Store),
         mem_step: match inst.function {
             LB | LBU | SB => 1,
             LH | LHU | SH => 2,
-            LW | SW => 4,
+            LW | SW | LRW | SCW => 4,
             _ => 0,
         },
         alu_op: match inst.function {
@@ -135,7 +184,12 @@ pub fn get_controls(inst: &Instruction) -> Controls {
             SRA => ALUOp::SRA,
             OR => ALUOp::OR,
             AND => ALUOp::AND,
-            ECALL => ALUOp::default(),
+            ECALL | EBREAK | SRET | MRET | WFI | SFENCEVMA => ALUOp::default(),
+            LRW | SCW => ALUOp::ADD,
+            DIV => ALUOp::DIV,
+            DIVU => ALUOp::DIVU,
+            REM => ALUOp::REM,
+            REMU => ALUOp::REMU,
         },
         alu_src: match inst.opcode {
             Branch | Op | Jal => ALUSrc::REG,
@@ -145,9 +199,9 @@ pub fn get_controls(inst: &Instruction) -> Controls {
 }
 
 /// Returns the opcode from a raw instruction
-pub fn raw_to_opcode(raw_inst: u32) -> Opcode {
-    let opcode = raw_inst & (0x7f as u32);
-    match opcode {
+pub fn raw_to_opcode(raw_inst: u32) -> SimulatorResult<Opcode> {
+    let opcode = raw_inst & 0x7f_u32;
+    let opcode = match opcode {
         0x37 => Opcode::Lui,
         0x17 => Opcode::AuiPc,
         0x6f => Opcode::Jal,
@@ -158,8 +212,10 @@ pub fn raw_to_opcode(raw_inst: u32) -> Opcode {
         0x33 => Opcode::Op,
         0x13 => Opcode::OpImm,
         0x73 => Opcode::System,
-        _ => panic!("Unknown opcode: {:07b}", opcode),
-    }
+        0x2f => Opcode::Amo,
+        _ => return Err(SimulatorError::InvalidInstruction(raw_inst)),
+    };
+    Ok(opcode)
 }
 
 /// Returns the instruction format from an opcode
@@ -175,11 +231,12 @@ pub fn opcode_to_format(opcode: Opcode) -> Format {
         Opcode::Op => Format::R,
         Opcode::OpImm => Format::I,
         Opcode::System => Format::Sys,
+        Opcode::Amo => Format::R,
     }
 }
 
 /// Parses other stuff
-pub fn parse(inst: &mut Instruction) {
+pub fn parse(inst: &mut Instruction) -> SimulatorResult<()> {
     inst.attributes = match inst.format {
         Format::R => parse_format_r(inst.raw_inst),
         Format::I => parse_format_i(inst.raw_inst),
@@ -189,9 +246,10 @@ pub fn parse(inst: &mut Instruction) {
         Format::J => parse_format_j(inst.raw_inst),
         Format::Sys => parse_format_sys(inst.raw_inst),
     };
-    inst.attributes.imm = get_imm_sign_extended(&inst);
-    inst.function = get_function(&inst);
-    inst.controls = get_controls(&inst);
+    inst.attributes.imm = get_imm_sign_extended(inst);
+    inst.function = get_function(inst)?;
+    inst.controls = get_controls(inst);
+    Ok(())
 }
 
 /// Parses attributes for an R-type instruction
@@ -311,6 +369,79 @@ fn parse_format_sys(raw_inst: u32) -> Attributes {
     }
 }
 
+/// Encodes an R-type instruction from its fields,
+/// the inverse of parse_format_r
+pub fn encode_r(
+    funct7: u32,
+    rs2: u32,
+    rs1: u32,
+    funct3: u32,
+    rd: u32,
+    opcode: u32,
+) -> u32 {
+    ((funct7 & 0x7f) << 25)
+        | ((rs2 & 0x1f) << 20)
+        | ((rs1 & 0x1f) << 15)
+        | ((funct3 & 0x7) << 12)
+        | ((rd & 0x1f) << 7)
+        | (opcode & 0x7f)
+}
+
+/// Encodes an I-type instruction from its fields,
+/// the inverse of parse_format_i.
+/// For shifts, imm carries funct7 above the shift amount
+pub fn encode_i(imm: u32, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    ((imm & 0xfff) << 20)
+        | ((rs1 & 0x1f) << 15)
+        | ((funct3 & 0x7) << 12)
+        | ((rd & 0x1f) << 7)
+        | (opcode & 0x7f)
+}
+
+/// Encodes an S-type instruction from its fields,
+/// the inverse of parse_format_s
+pub fn encode_s(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (((imm >> 5) & 0x7f) << 25)
+        | ((rs2 & 0x1f) << 20)
+        | ((rs1 & 0x1f) << 15)
+        | ((funct3 & 0x7) << 12)
+        | ((imm & 0x1f) << 7)
+        | (opcode & 0x7f)
+}
+
+/// Encodes a B-type instruction from its fields,
+/// the inverse of parse_format_b.
+/// Bit 0 of imm is dropped, as branch offsets are even
+pub fn encode_b(imm: u32, rs2: u32, rs1: u32, funct3: u32, opcode: u32) -> u32 {
+    (((imm >> 12) & 0x1) << 31)
+        | (((imm >> 5) & 0x3f) << 25)
+        | ((rs2 & 0x1f) << 20)
+        | ((rs1 & 0x1f) << 15)
+        | ((funct3 & 0x7) << 12)
+        | (((imm >> 1) & 0xf) << 8)
+        | (((imm >> 11) & 0x1) << 7)
+        | (opcode & 0x7f)
+}
+
+/// Encodes a U-type instruction from its fields,
+/// the inverse of parse_format_u,
+/// where imm is already in the upper 20 bits
+pub fn encode_u(imm: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xfffff000) | ((rd & 0x1f) << 7) | (opcode & 0x7f)
+}
+
+/// Encodes a J-type instruction from its fields,
+/// the inverse of parse_format_j.
+/// Bit 0 of imm is dropped, as jump offsets are even
+pub fn encode_j(imm: u32, rd: u32, opcode: u32) -> u32 {
+    (((imm >> 20) & 0x1) << 31)
+        | (((imm >> 1) & 0x3ff) << 21)
+        | (((imm >> 11) & 0x1) << 20)
+        | (((imm >> 12) & 0xff) << 12)
+        | ((rd & 0x1f) << 7)
+        | (opcode & 0x7f)
+}
+
 /// Extracts opcode from a raw instruction
 fn get_opcode(raw_inst: u32) -> u32 {
     raw_inst & 0x7f
@@ -406,6 +537,21 @@ mod tests {
         assert_eq!(imm, -96);
     }
 
+    #[test]
+    fn type_i_sltiu() {
+        // sltiu x1, x0, 1
+        let inst = Instruction::new(0x00103093);
+        assert_eq!(inst.function, Function::SLTIU);
+        assert_eq!(inst.controls.alu_op, crate::alu::ALUOp::SLTU);
+        assert_eq!(inst.attributes.imm.unwrap(), 1);
+
+        // sltiu x1, x2, -1 compares against 0xffffffff
+        let inst = Instruction::new(0xfff13093);
+        assert_eq!(inst.function, Function::SLTIU);
+        assert_eq!(inst.attributes.rs1.unwrap(), 2);
+        assert_eq!(inst.attributes.imm.unwrap(), 0xffffffff);
+    }
+
     #[test]
     fn type_s() {
         // sw ra, 28(sp)
@@ -473,4 +619,85 @@ mod tests {
         let wrapped_inst = Instruction::new(inst);
         assert_eq!(wrapped_inst.attributes.imm.unwrap() as i32, 3892);
     }
+
+    #[test]
+    fn type_sys() {
+        use Function::*;
+        let cases = [
+            (0x00000073, ECALL),
+            (0x00100073, EBREAK),
+            (0x10200073, SRET),
+            (0x30200073, MRET),
+            (0x10500073, WFI),
+            (0x12000073, SFENCEVMA),
+            (0x12b50073, SFENCEVMA), // sfence.vma a0, a1
+        ];
+        for (raw_inst, function) in cases {
+            assert_eq!(Instruction::new(raw_inst).function, function);
+        }
+        // Nonzero rd or funct3, or an unknown funct12
+        for raw_inst in [0x00000573, 0x00001073, 0x7b200073] {
+            assert!(Instruction::try_new(raw_inst).is_err());
+        }
+    }
+
+    #[test]
+    fn test_encode_round_trip() {
+        // R-type has no immediate
+        let raw_inst = encode_r(0x20, 7, 6, 0b101, 5, 0x33);
+        let attributes = parse_format_r(raw_inst);
+        assert_eq!(attributes.funct7, Some(0x20));
+        assert_eq!(attributes.rs2, Some(7));
+        assert_eq!(attributes.rs1, Some(6));
+        assert_eq!(attributes.funct3, Some(0b101));
+        assert_eq!(attributes.rd, Some(5));
+        assert_eq!(attributes.opcode, Some(0x33));
+
+        // Immediates exercising every scrambled bit, masked to their width
+        let imms = [0, 1, 0x7ff, 0x800, 0xaaaaaaaa, 0x55555555, u32::MAX];
+        for imm in imms {
+            let raw_inst = encode_i(imm, 31, 0b010, 1, 0x03);
+            let attributes = parse_format_i(raw_inst);
+            assert_eq!(attributes.imm, Some(imm & 0xfff));
+            assert_eq!(attributes.rs1, Some(31));
+            assert_eq!(attributes.funct3, Some(0b010));
+            assert_eq!(attributes.rd, Some(1));
+            assert_eq!(attributes.opcode, Some(0x03));
+
+            let raw_inst = encode_s(imm, 2, 3, 0b001, 0x23);
+            let attributes = parse_format_s(raw_inst);
+            assert_eq!(attributes.imm, Some(imm & 0xfff));
+            assert_eq!(attributes.rs2, Some(2));
+            assert_eq!(attributes.rs1, Some(3));
+            assert_eq!(attributes.funct3, Some(0b001));
+
+            let raw_inst = encode_b(imm, 14, 13, 0b110, 0x63);
+            let attributes = parse_format_b(raw_inst);
+            assert_eq!(attributes.imm, Some(imm & 0x1ffe));
+            assert_eq!(attributes.rs2, Some(14));
+            assert_eq!(attributes.rs1, Some(13));
+            assert_eq!(attributes.funct3, Some(0b110));
+
+            let raw_inst = encode_u(imm, 5, 0x37);
+            let attributes = parse_format_u(raw_inst);
+            assert_eq!(attributes.imm, Some(imm & 0xfffff000));
+            assert_eq!(attributes.rd, Some(5));
+
+            let raw_inst = encode_j(imm, 1, 0x6f);
+            let attributes = parse_format_j(raw_inst);
+            assert_eq!(attributes.imm, Some(imm & 0x1ffffe));
+            assert_eq!(attributes.rd, Some(1));
+            assert_eq!(attributes.opcode, Some(0x6f));
+        }
+
+        // Shifts carry funct7 above the shift amount
+        let raw_inst = encode_i((0x20 << 5) | 6, 5, 0b101, 5, 0x13);
+        assert_eq!(raw_inst, 0x4062d293); // srai t0, t0, 6
+        assert_eq!(parse_format_i(raw_inst).imm, Some(6));
+
+        // Known encodings, as in the tests above
+        assert_eq!(encode_b(100, 6, 5, 0, 0x63), 0x6628263);
+        assert_eq!(encode_j(-136i32 as u32, 0, 0x6f), 0xf79ff06f);
+        assert_eq!(encode_s(28, 1, 2, 0b010, 0x23), 0x00112e23);
+    }
 }
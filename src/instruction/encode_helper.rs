@@ -0,0 +1,502 @@
+//! Encoding helper functions,
+//! including a minimal assembler for single instructions
+
+use super::{decode_helper, Function, Opcode};
+use crate::error::{SimulatorError, SimulatorResult};
+use alloc::format;
+use alloc::vec::Vec;
+
+/// ABI names of x0-x31
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1",
+    "a2", "a3", "a4", "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7",
+    "s8", "s9", "s10", "s11", "t3", "t4", "t5", "t6",
+];
+
+/// Returns the function with the given mnemonic
+pub fn mnemonic_to_function(mnemonic: &str) -> Option<Function> {
+    use Function::*;
+    let function = match mnemonic {
+        "lui" => LUI,
+        "auipc" => AUIPC,
+        "jal" => JAL,
+        "jalr" => JALR,
+        "beq" => BEQ,
+        "bne" => BNE,
+        "blt" => BLT,
+        "bge" => BGE,
+        "bltu" => BLTU,
+        "bgeu" => BGEU,
+        "lb" => LB,
+        "lh" => LH,
+        "lw" => LW,
+        "lbu" => LBU,
+        "lhu" => LHU,
+        "sb" => SB,
+        "sh" => SH,
+        "sw" => SW,
+        "addi" => ADDI,
+        "slti" => SLTI,
+        "sltiu" => SLTIU,
+        "xori" => XORI,
+        "ori" => ORI,
+        "andi" => ANDI,
+        "slli" => SLLI,
+        "srli" => SRLI,
+        "srai" => SRAI,
+        "add" => ADD,
+        "sub" => SUB,
+        "sll" => SLL,
+        "slt" => SLT,
+        "sltu" => SLTU,
+        "xor" => XOR,
+        "srl" => SRL,
+        "sra" => SRA,
+        "or" => OR,
+        "and" => AND,
+        "ecall" => ECALL,
+        "ebreak" => EBREAK,
+        "sret" => SRET,
+        "mret" => MRET,
+        "wfi" => WFI,
+        "sfence.vma" => SFENCEVMA,
+        "lr.w" => LRW,
+        "sc.w" => SCW,
+        "div" => DIV,
+        "divu" => DIVU,
+        "rem" => REM,
+        "remu" => REMU,
+        _ => return None,
+    };
+    Some(function)
+}
+
+/// Returns the opcode of a function
+pub fn function_to_opcode(function: Function) -> Opcode {
+    use Function::*;
+    match function {
+        LUI => Opcode::Lui,
+        AUIPC => Opcode::AuiPc,
+        JAL => Opcode::Jal,
+        JALR => Opcode::Jalr,
+        BEQ | BNE | BLT | BGE | BLTU | BGEU => Opcode::Branch,
+        LB | LH | LW | LBU | LHU => Opcode::Load,
+        SB | SH | SW => Opcode::Store,
+        ADDI | SLTI | SLTIU | XORI | ORI | ANDI | SLLI | SRLI | SRAI => {
+            Opcode::OpImm
+        }
+        ADD | SUB | SLL | SLT | SLTU | XOR | SRL | SRA | OR | AND => Opcode::Op,
+        DIV | DIVU | REM | REMU => Opcode::Op,
+        ECALL | EBREAK | SRET | MRET | WFI | SFENCEVMA => Opcode::System,
+        LRW | SCW => Opcode::Amo,
+    }
+}
+
+/// Returns the raw 7-bit encoding of an opcode
+pub fn opcode_to_raw(opcode: Opcode) -> u32 {
+    match opcode {
+        Opcode::Lui => 0x37,
+        Opcode::AuiPc => 0x17,
+        Opcode::Jal => 0x6f,
+        Opcode::Jalr => 0x67,
+        Opcode::Branch => 0x63,
+        Opcode::Load => 0x03,
+        Opcode::Store => 0x23,
+        Opcode::Op => 0x33,
+        Opcode::OpImm => 0x13,
+        Opcode::System => 0x73,
+        Opcode::Amo => 0x2f,
+    }
+}
+
+/// Returns funct12 of a system function,
+/// where SFENCE.VMA has rs2 = x0
+pub fn function_to_funct12(function: Function) -> u32 {
+    use Function::*;
+    match function {
+        EBREAK => 0x001,
+        SRET => 0x102,
+        MRET => 0x302,
+        WFI => 0x105,
+        SFENCEVMA => 0x120,
+        _ => 0x000,
+    }
+}
+
+/// Returns (funct3, funct7) of a function,
+/// where funct7 only matters for R-type, shift and atomic instructions,
+/// the latter leaving the aq and rl bits clear
+pub fn function_to_funct(function: Function) -> (u32, u32) {
+    use Function::*;
+    match function {
+        LUI | AUIPC | JAL => (0, 0),
+        ECALL | EBREAK | SRET | MRET | WFI | SFENCEVMA => (0, 0),
+        JALR => (0b000, 0),
+        BEQ => (0b000, 0),
+        BNE => (0b001, 0),
+        BLT => (0b100, 0),
+        BGE => (0b101, 0),
+        BLTU => (0b110, 0),
+        BGEU => (0b111, 0),
+        LB => (0b000, 0),
+        LH => (0b001, 0),
+        LW => (0b010, 0),
+        LBU => (0b100, 0),
+        LHU => (0b101, 0),
+        SB => (0b000, 0),
+        SH => (0b001, 0),
+        SW => (0b010, 0),
+        ADDI => (0b000, 0),
+        SLTI => (0b010, 0),
+        SLTIU => (0b011, 0),
+        XORI => (0b100, 0),
+        ORI => (0b110, 0),
+        ANDI => (0b111, 0),
+        SLLI => (0b001, 0),
+        SRLI => (0b101, 0),
+        SRAI => (0b101, 0b0100000),
+        ADD => (0b000, 0),
+        SUB => (0b000, 0b0100000),
+        SLL => (0b001, 0),
+        SLT => (0b010, 0),
+        SLTU => (0b011, 0),
+        XOR => (0b100, 0),
+        SRL => (0b101, 0),
+        SRA => (0b101, 0b0100000),
+        OR => (0b110, 0),
+        AND => (0b111, 0),
+        LRW => (0b010, 0b0001000),
+        SCW => (0b010, 0b0001100),
+        DIV => (0b100, 0b0000001),
+        DIVU => (0b101, 0b0000001),
+        REM => (0b110, 0b0000001),
+        REMU => (0b111, 0b0000001),
+    }
+}
+
+/// Encodes an R-type instruction
+pub fn encode_r(function: Function, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    let (funct3, funct7) = function_to_funct(function);
+    let opcode = opcode_to_raw(function_to_opcode(function));
+    decode_helper::encode_r(funct7, rs2, rs1, funct3, rd, opcode)
+}
+
+/// Encodes an I-type instruction;
+/// for shifts, imm is the shift amount
+pub fn encode_i(function: Function, rd: u32, rs1: u32, imm: i32) -> u32 {
+    let (funct3, funct7) = function_to_funct(function);
+    let imm = match function {
+        Function::SLLI | Function::SRLI | Function::SRAI => {
+            (funct7 << 5) | (imm as u32 & 0x1f)
+        }
+        _ => imm as u32,
+    };
+    let opcode = opcode_to_raw(function_to_opcode(function));
+    decode_helper::encode_i(imm, rs1, funct3, rd, opcode)
+}
+
+/// Encodes an S-type instruction
+pub fn encode_s(function: Function, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let (funct3, _) = function_to_funct(function);
+    let opcode = opcode_to_raw(function_to_opcode(function));
+    decode_helper::encode_s(imm as u32, rs2, rs1, funct3, opcode)
+}
+
+/// Encodes a B-type instruction
+pub fn encode_b(function: Function, rs1: u32, rs2: u32, imm: i32) -> u32 {
+    let (funct3, _) = function_to_funct(function);
+    let opcode = opcode_to_raw(function_to_opcode(function));
+    decode_helper::encode_b(imm as u32, rs2, rs1, funct3, opcode)
+}
+
+/// Encodes a U-type instruction;
+/// imm is the upper 20 bits
+pub fn encode_u(function: Function, rd: u32, imm: u32) -> u32 {
+    let opcode = opcode_to_raw(function_to_opcode(function));
+    decode_helper::encode_u(imm << 12, rd, opcode)
+}
+
+/// Encodes a J-type instruction
+pub fn encode_j(function: Function, rd: u32, imm: i32) -> u32 {
+    let opcode = opcode_to_raw(function_to_opcode(function));
+    decode_helper::encode_j(imm as u32, rd, opcode)
+}
+
+fn invalid(line: &str, reason: &str) -> SimulatorError {
+    SimulatorError::InvalidAssembly(format!("{}: {}", reason, line))
+}
+
+/// Parses a register name, either x0-x31 or an ABI name
+fn parse_register(token: &str) -> Option<u32> {
+    if let Some(number) = token.strip_prefix('x') {
+        return number.parse::<u32>().ok().filter(|&n| n < 32);
+    }
+    if token == "fp" {
+        return Some(8);
+    }
+    ABI_NAMES
+        .iter()
+        .position(|&name| name == token)
+        .map(|n| n as u32)
+}
+
+/// Parses a decimal or hexadecimal immediate, possibly negative
+fn parse_imm(token: &str) -> Option<i64> {
+    let (negative, token) = match token.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, token),
+    };
+    let value = match token.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => token.parse::<i64>().ok()?,
+    };
+    Some(if negative { -value } else { value })
+}
+
+/// Parses "imm(rs1)" into (imm, rs1)
+fn parse_offset(token: &str) -> Option<(i64, u32)> {
+    let (imm, rest) = token.split_once('(')?;
+    let rs1 = parse_register(rest.strip_suffix(')')?.trim())?;
+    let imm = if imm.is_empty() { 0 } else { parse_imm(imm)? };
+    Some((imm, rs1))
+}
+
+/// Assembles a single instruction, e.g., "addi x1, x2, -4".
+/// Branch and jump targets are numeric offsets relative to the PC
+pub fn assemble(line: &str) -> SimulatorResult<u32> {
+    let line = line.trim();
+    let (mnemonic, rest) =
+        line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let function = mnemonic_to_function(&mnemonic.to_lowercase())
+        .ok_or_else(|| invalid(line, "Unknown mnemonic"))?;
+    let operands: Vec<&str> = rest
+        .split(',')
+        .map(str::trim)
+        .filter(|operand| !operand.is_empty())
+        .collect();
+
+    let expect = |count: usize| {
+        if operands.len() == count {
+            Ok(())
+        } else {
+            Err(invalid(line, "Wrong number of operands"))
+        }
+    };
+    let register = |token: &str| {
+        parse_register(token).ok_or_else(|| invalid(line, "Invalid register"))
+    };
+    // Immediate within [lo, hi]
+    let imm = |token: &str, lo: i64, hi: i64| {
+        parse_imm(token)
+            .filter(|imm| (lo..=hi).contains(imm))
+            .map(|imm| imm as i32)
+            .ok_or_else(|| invalid(line, "Invalid immediate"))
+    };
+    let offset = |token: &str| {
+        parse_offset(token)
+            .filter(|(imm, _)| (-2048..=2047).contains(imm))
+            .map(|(imm, rs1)| (imm as i32, rs1))
+            .ok_or_else(|| invalid(line, "Invalid offset"))
+    };
+
+    use Function::*;
+    let raw_inst = match function_to_opcode(function) {
+        Opcode::Lui | Opcode::AuiPc => {
+            expect(2)?;
+            let rd = register(operands[0])?;
+            encode_u(function, rd, imm(operands[1], 0, 0xfffff)? as u32)
+        }
+        Opcode::Jal => {
+            expect(2)?;
+            let rd = register(operands[0])?;
+            let imm = imm(operands[1], -(1 << 20), (1 << 20) - 2)?;
+            if imm % 2 != 0 {
+                return Err(invalid(line, "Misaligned offset"));
+            }
+            encode_j(function, rd, imm)
+        }
+        Opcode::Jalr | Opcode::Load => {
+            expect(2)?;
+            let rd = register(operands[0])?;
+            let (imm, rs1) = offset(operands[1])?;
+            encode_i(function, rd, rs1, imm)
+        }
+        Opcode::Store => {
+            expect(2)?;
+            let rs2 = register(operands[0])?;
+            let (imm, rs1) = offset(operands[1])?;
+            encode_s(function, rs1, rs2, imm)
+        }
+        Opcode::Branch => {
+            expect(3)?;
+            let rs1 = register(operands[0])?;
+            let rs2 = register(operands[1])?;
+            let imm = imm(operands[2], -(1 << 12), (1 << 12) - 2)?;
+            if imm % 2 != 0 {
+                return Err(invalid(line, "Misaligned offset"));
+            }
+            encode_b(function, rs1, rs2, imm)
+        }
+        Opcode::OpImm => {
+            expect(3)?;
+            let rd = register(operands[0])?;
+            let rs1 = register(operands[1])?;
+            let imm = match function {
+                SLLI | SRLI | SRAI => imm(operands[2], 0, 31)?,
+                _ => imm(operands[2], -2048, 2047)?,
+            };
+            encode_i(function, rd, rs1, imm)
+        }
+        Opcode::Op => {
+            expect(3)?;
+            let rd = register(operands[0])?;
+            let rs1 = register(operands[1])?;
+            let rs2 = register(operands[2])?;
+            encode_r(function, rd, rs1, rs2)
+        }
+        Opcode::Amo => {
+            // "lr.w rd, (rs1)" and "sc.w rd, rs2, (rs1)"
+            let (rd, rs2, address) = match function {
+                LRW => {
+                    expect(2)?;
+                    (register(operands[0])?, 0, operands[1])
+                }
+                _ => {
+                    expect(3)?;
+                    let rs2 = register(operands[1])?;
+                    (register(operands[0])?, rs2, operands[2])
+                }
+            };
+            let rs1 = match parse_offset(address) {
+                Some((0, rs1)) => rs1,
+                _ => return Err(invalid(line, "Invalid address")),
+            };
+            encode_r(function, rd, rs1, rs2)
+        }
+        Opcode::System => {
+            expect(0)?;
+            opcode_to_raw(Opcode::System) | function_to_funct12(function) << 20
+        }
+    };
+    Ok(raw_inst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction;
+
+    #[test]
+    fn test_every_function() {
+        use Function::*;
+        let cases = [
+            ("lui x5, 0x12345", LUI),
+            ("auipc x5, 1", AUIPC),
+            ("jal ra, -136", JAL),
+            ("jalr ra, -96(a3)", JALR),
+            ("beq x1, x0, 16", BEQ),
+            ("bne x1, x2, -4", BNE),
+            ("blt x1, x2, 8", BLT),
+            ("bge x1, x2, 8", BGE),
+            ("bltu x1, x2, 8", BLTU),
+            ("bgeu x1, x2, 8", BGEU),
+            ("lb a0, 0(sp)", LB),
+            ("lh a0, 2(sp)", LH),
+            ("lw x5, 8(x6)", LW),
+            ("lbu a0, -1(sp)", LBU),
+            ("lhu a0, 2(sp)", LHU),
+            ("sb a0, 0(sp)", SB),
+            ("sh a0, 2(sp)", SH),
+            ("sw ra, 28(sp)", SW),
+            ("addi x1, x2, -4", ADDI),
+            ("slti x1, x2, 4", SLTI),
+            ("sltiu x1, x2, 4", SLTIU),
+            ("xori x1, x2, -1", XORI),
+            ("ori x1, x2, 0x7ff", ORI),
+            ("andi x1, x2, 255", ANDI),
+            ("slli x5, x6, 3", SLLI),
+            ("srli x5, x6, 3", SRLI),
+            ("srai x5, x6, 31", SRAI),
+            ("add x5, x6, x7", ADD),
+            ("sub x5, x6, x7", SUB),
+            ("sll x5, x6, x7", SLL),
+            ("slt x5, x6, x7", SLT),
+            ("sltu x5, x6, x7", SLTU),
+            ("xor x5, x6, x7", XOR),
+            ("srl x5, x6, x7", SRL),
+            ("sra x5, x6, x7", SRA),
+            ("or x5, x6, x7", OR),
+            ("and x5, x6, x7", AND),
+            ("ecall", ECALL),
+            ("ebreak", EBREAK),
+            ("sret", SRET),
+            ("mret", MRET),
+            ("wfi", WFI),
+            ("sfence.vma", SFENCEVMA),
+            ("lr.w a0, (a1)", LRW),
+            ("sc.w t0, a2, (a1)", SCW),
+            ("div x5, x6, x7", DIV),
+            ("divu x5, x6, x7", DIVU),
+            ("rem x5, x6, x7", REM),
+            ("remu x5, x6, x7", REMU),
+        ];
+        for (line, function) in cases {
+            let inst = Instruction::new(assemble(line).unwrap());
+            assert_eq!(inst.function, function, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_known_encodings() {
+        assert_eq!(assemble("addi x0, x0, 0").unwrap(), 0x13);
+        assert_eq!(assemble("add x5, x6, x7").unwrap(), 0x7302b3);
+        assert_eq!(assemble("addi x5, x6, 20").unwrap(), 0x01430293);
+        assert_eq!(assemble("slli x5, x6, 3").unwrap(), 0x00331293);
+        assert_eq!(assemble("lw a0, 0(sp)").unwrap(), 0x00012503);
+        assert_eq!(assemble("jalr ra, -96(a3)").unwrap(), 0xfa0680e7);
+        assert_eq!(assemble("sw ra, 28(sp)").unwrap(), 0x00112e23);
+        assert_eq!(assemble("beq x5, x6, 100").unwrap(), 0x6628263);
+        assert_eq!(assemble("bltu a3, a4, 16").unwrap(), 0x00e6e863);
+        assert_eq!(assemble("lui x5, 0x12345").unwrap(), 0x123452b7);
+        assert_eq!(assemble("jal x1, 100").unwrap(), 0x64000ef);
+        assert_eq!(assemble("jal x0, -136").unwrap(), 0xf79ff06f);
+        assert_eq!(assemble("wfi").unwrap(), 0x10500073);
+        assert_eq!(assemble("lr.w a0, (a1)").unwrap(), 0x1005a52f);
+        assert_eq!(assemble("sc.w t0, a2, (a1)").unwrap(), 0x18c5a2af);
+        assert_eq!(assemble("div a0, t0, t1").unwrap(), 0x0262c533);
+        assert_eq!(assemble("remu a3, t0, t1").unwrap(), 0x0262f6b3);
+    }
+
+    #[test]
+    fn test_attributes() {
+        let inst = Instruction::new(assemble("addi x1, x2, -4").unwrap());
+        assert_eq!(inst.attributes.rd, Some(1));
+        assert_eq!(inst.attributes.rs1, Some(2));
+        assert_eq!(inst.attributes.imm.unwrap() as i32, -4);
+
+        let inst = Instruction::new(assemble("sw a0, -8(s0)").unwrap());
+        assert_eq!(inst.attributes.rs1, Some(8));
+        assert_eq!(inst.attributes.rs2, Some(10));
+        assert_eq!(inst.attributes.imm.unwrap() as i32, -8);
+
+        let inst = Instruction::new(assemble("bge t0, t1, -2048").unwrap());
+        assert_eq!(inst.attributes.rs1, Some(5));
+        assert_eq!(inst.attributes.rs2, Some(6));
+        assert_eq!(inst.attributes.imm.unwrap() as i32, -2048);
+
+        let inst = Instruction::new(assemble("srai a0, a1, 7").unwrap());
+        assert_eq!(inst.attributes.rd, Some(10));
+        assert_eq!(inst.attributes.rs1, Some(11));
+        assert_eq!(inst.attributes.imm, Some(7));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(assemble("foo x1, x2, x3").is_err());
+        assert!(assemble("add x1, x2").is_err());
+        assert!(assemble("add x1, x2, x32").is_err());
+        assert!(assemble("addi x1, x2, 2048").is_err());
+        assert!(assemble("beq x1, x2, 3").is_err());
+        assert!(assemble("lw x1, 8").is_err());
+    }
+}
@@ -1,17 +1,33 @@
 //! Instruction representation
 
 use crate::alu::{ALUOp, ALUSrc};
+use crate::error::{SimulatorError, SimulatorResult};
+use alloc::vec;
+use alloc::vec::Vec;
+#[cfg(feature = "std")]
+use std::sync::LazyLock;
 
+pub mod compressed;
 pub mod decode_helper;
+pub mod encode_helper;
+
+pub use encode_helper::assemble;
 
 /// NOP: ADDI x0, x0, 0
 pub(crate) const NOP: u32 = 0x13;
 
+/// NOP decoded once, as bubbles are made all the time
+#[cfg(feature = "std")]
+static NOP_INSTRUCTION: LazyLock<Instruction> =
+    LazyLock::new(|| Instruction::new(NOP));
+
 /// Wrapped instruction
 #[derive(Clone, Copy, Debug)]
 pub struct Instruction {
-    /// Raw representation
+    /// Raw representation, expanded if compressed
     pub raw_inst: u32,
+    /// Size in bytes, which is 2 if compressed and 4 otherwise
+    pub length: u32,
     /// Opcode
     pub opcode: Opcode,
     /// Format
@@ -25,34 +41,133 @@ pub struct Instruction {
 }
 
 impl Instruction {
+    /// Decodes a raw instruction,
+    /// panicking if it's not a valid rv32i instruction
     pub fn new(raw_inst: u32) -> Self {
-        let opcode = decode_helper::raw_to_opcode(raw_inst);
+        Self::try_new(raw_inst).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Decodes a raw instruction.
+    /// A compressed instruction is given as a zero-extended halfword
+    pub fn try_new(raw_inst: u32) -> SimulatorResult<Self> {
+        let (raw_inst, length) = match compressed::is_compressed(raw_inst) {
+            true if raw_inst >> 16 != 0 => {
+                return Err(SimulatorError::InvalidInstruction(raw_inst))
+            }
+            true => (compressed::expand(raw_inst as u16)?, 2),
+            false => (raw_inst, 4),
+        };
+        let opcode = decode_helper::raw_to_opcode(raw_inst)?;
         let format = decode_helper::opcode_to_format(opcode);
         let attributes = Attributes::default();
         let function = Function::default();
         let controls = Controls::default();
         let mut inst = Self {
             raw_inst,
+            length,
             opcode,
             format,
             function,
             attributes,
             controls,
         };
-        decode_helper::parse(&mut inst);
+        decode_helper::parse(&mut inst)?;
+
+        Ok(inst)
+    }
+
+    /// Whether it has no architectural effect, computing a value
+    /// only to drop it in x0, like the canonical NOP and HINTs
+    /// such as `addi x0, x1, 0`. Loads to x0 still access memory,
+    /// jumps to x0 still jump, and divisions into x0 may still trap
+    pub fn is_nop(&self) -> bool {
+        use Opcode::*;
+        matches!(self.opcode, Lui | AuiPc | Op | OpImm)
+            && self.attributes.rd == Some(0)
+            && !self.controls.alu_op.is_division()
+    }
+}
+
+/// Decodes a blob of instructions in 4-byte steps,
+/// pairing each address with its decode result.
+/// Trailing bytes that don't make up a word are ignored
+pub fn decode_all(
+    bytes: &[u8],
+    base_addr: u32,
+) -> Vec<(u32, SimulatorResult<Instruction>)> {
+    bytes
+        .chunks_exact(4)
+        .enumerate()
+        .map(|(i, word)| {
+            let raw_inst =
+                u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            let address = base_addr.wrapping_add(4 * i as u32);
+            (address, Instruction::try_new(raw_inst))
+        })
+        .collect()
+}
+
+/// Number of entries in a decode cache by default
+pub const DECODE_CACHE_SIZE: usize = 1024;
+
+/// Direct-mapped cache of decoded instructions keyed on the raw word,
+/// since a program decodes the same few static instructions over and over.
+/// Undecodable words are never cached, so they fail every time
+pub struct DecodeCache {
+    /// (raw word as fetched, decoded instruction)
+    entries: Vec<Option<(u32, Instruction)>>,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::make(DECODE_CACHE_SIZE)
+    }
+}
+
+impl DecodeCache {
+    /// Makes a cache of the given number of entries,
+    /// which must be a power of two.
+    /// A cache of 0 entries decodes every time
+    pub fn make(size: usize) -> Self {
+        assert!(size == 0 || size.is_power_of_two());
+        Self {
+            entries: vec![None; size],
+        }
+    }
 
-        inst
+    /// Decodes a raw instruction like Instruction::try_new
+    pub fn decode(&mut self, raw_inst: u32) -> SimulatorResult<Instruction> {
+        if self.entries.is_empty() {
+            return Instruction::try_new(raw_inst);
+        }
+        // Fibonacci hashing, taking the well-mixed high bits,
+        // as the low bits are mostly the opcode
+        let hash = raw_inst.wrapping_mul(0x9e3779b9).rotate_left(16);
+        let index = hash as usize & (self.entries.len() - 1);
+        match self.entries[index] {
+            Some((raw, inst)) if raw == raw_inst => Ok(inst),
+            _ => {
+                let inst = Instruction::try_new(raw_inst)?;
+                self.entries[index] = Some((raw_inst, inst));
+                Ok(inst)
+            }
+        }
     }
 }
 
 impl Default for Instruction {
+    /// A NOP, copied from the one decoded up front;
+    /// without std, there's no lazy static to decode it into
     fn default() -> Self {
+        #[cfg(feature = "std")]
+        return *NOP_INSTRUCTION;
+        #[cfg(not(feature = "std"))]
         Self::new(NOP)
     }
 }
 
 /// rv32i opcode
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Opcode {
     Lui,
     AuiPc,
@@ -64,6 +179,8 @@ pub enum Opcode {
     Op,
     OpImm,
     System,
+    /// Atomic memory operations, of which only LR.W and SC.W exist here
+    Amo,
 }
 
 /// rv32i instruction format
@@ -79,7 +196,7 @@ pub enum Format {
 }
 
 /// rv32i function (instruction?)
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
 pub enum Function {
     LUI,
     AUIPC,
@@ -99,6 +216,7 @@ pub enum Function {
     SB,
     SH,
     SW,
+    #[default]
     ADDI,
     SLTI,
     SLTIU,
@@ -119,12 +237,17 @@ pub enum Function {
     OR,
     AND,
     ECALL,
-}
-
-impl Default for Function {
-    fn default() -> Self {
-        Function::ADDI
-    }
+    EBREAK,
+    SRET,
+    MRET,
+    WFI,
+    SFENCEVMA,
+    LRW,
+    SCW,
+    DIV,
+    DIVU,
+    REM,
+    REMU,
 }
 
 /// Instruction attributes
@@ -151,3 +274,114 @@ pub struct Controls {
     pub alu_op: ALUOp,
     pub alu_src: ALUSrc,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SimulatorError;
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_default_skips_decode() {
+        use decode_helper::GET_FUNCTION_CALLS;
+        let inst = Instruction::default();
+        let calls = GET_FUNCTION_CALLS.with(|calls| calls.get());
+        for _ in 0..10 {
+            let default = Instruction::default();
+            assert_eq!(default.raw_inst, inst.raw_inst);
+            assert_eq!(default.function, Function::ADDI);
+            assert_eq!(default.attributes.rd, Some(0));
+        }
+        assert_eq!(GET_FUNCTION_CALLS.with(|calls| calls.get()), calls);
+        // Decoding it explicitly still goes all the way
+        Instruction::new(NOP);
+        assert_eq!(GET_FUNCTION_CALLS.with(|calls| calls.get()), calls + 1);
+    }
+
+    #[test]
+    fn test_decode_all() {
+        let mut bytes = Vec::new();
+        // addi x5, x6, 20
+        bytes.extend_from_slice(&0x01430293u32.to_le_bytes());
+        // Unknown opcode
+        bytes.extend_from_slice(&0xffffffffu32.to_le_bytes());
+
+        let decoded = decode_all(&bytes, 0x1000);
+        assert_eq!(decoded.len(), 2);
+
+        let (address, inst) = &decoded[0];
+        assert_eq!(*address, 0x1000);
+        assert_eq!(inst.as_ref().unwrap().function, Function::ADDI);
+
+        let (address, inst) = &decoded[1];
+        assert_eq!(*address, 0x1004);
+        assert!(matches!(
+            inst,
+            Err(SimulatorError::InvalidInstruction(0xffffffff))
+        ));
+    }
+
+    #[test]
+    fn test_decode_cache() {
+        use decode_helper::GET_FUNCTION_CALLS;
+        let program = [
+            0x123452b7, // lui t0, 0x12345
+            0x00001317, // auipc t1, 1
+            0x008000ef, // jal ra, 8
+            0x00008067, // jalr x0, 0(ra)
+            0xfe029ee3, // bne t0, x0, -4
+            0xffc12283, // lw t0, -4(sp)
+            0x00514223, // sb t0, 4(sp)
+            0x01430293, // addi t0, t1, 20
+            0x4062d293, // srai t0, t0, 6
+            0x406282b3, // sub t0, t0, t1
+            0x00000073, // ecall
+            0x30200073, // mret
+            0x4505,     // c.li a0, 1
+            0xffffffff, // Unknown opcode
+        ];
+        let mut cache = DecodeCache::make(4);
+        for _ in 0..2 {
+            for &raw_inst in &program {
+                let cached = cache.decode(raw_inst).map(|i| format!("{:?}", i));
+                let uncached =
+                    Instruction::try_new(raw_inst).map(|i| format!("{:?}", i));
+                assert_eq!(cached.ok(), uncached.ok());
+            }
+        }
+
+        // Hits skip decoding altogether
+        let mut cache = DecodeCache::default();
+        cache.decode(0x01430293).unwrap();
+        let calls = GET_FUNCTION_CALLS.with(|calls| calls.get());
+        assert_eq!(cache.decode(0x01430293).unwrap().function, Function::ADDI);
+        assert_eq!(GET_FUNCTION_CALLS.with(|calls| calls.get()), calls);
+
+        // Errors are never cached
+        assert!(matches!(
+            cache.decode(0xffffffff),
+            Err(SimulatorError::InvalidInstruction(0xffffffff))
+        ));
+    }
+
+    #[test]
+    fn test_is_nop() {
+        for raw_inst in [
+            NOP,        // addi x0, x0, 0
+            0x00008013, // addi x0, x1, 0
+            0x00208033, // add x0, x1, x2
+            0x00001037, // lui x0, 1
+        ] {
+            assert!(Instruction::new(raw_inst).is_nop(), "{:#010x}", raw_inst);
+        }
+        for raw_inst in [
+            0x00000093, // addi x1, x0, 0
+            0x0002a003, // lw x0, 0(t0)
+            0x0080006f, // jal x0, 8
+            0x0262c033, // div x0, t0, t1
+            0x00000073, // ecall
+        ] {
+            assert!(!Instruction::new(raw_inst).is_nop(), "{:#010x}", raw_inst);
+        }
+    }
+}
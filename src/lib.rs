@@ -1,13 +1,56 @@
+//! A toy simulator for a 5-stage pipelined RV32I processor.
+//!
+//! Without the default `std` feature, only the instruction decoder
+//! and the ALU are built, as a `no_std` core that needs `alloc`
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
 pub mod alu;
+pub mod error;
+pub mod instruction;
+
+#[cfg(feature = "std")]
 pub mod cpu;
+#[cfg(feature = "std")]
 pub mod elf_helper;
-pub mod instruction;
+#[cfg(feature = "std")]
 pub mod loader;
+#[cfg(feature = "std")]
 pub mod memory;
+#[cfg(feature = "std")]
 pub mod run_wrapper;
+#[cfg(feature = "std")]
 pub mod system_call;
 
+#[cfg(feature = "std")]
 pub mod stages_simple;
 
+#[cfg(feature = "std")]
+pub mod multi_cycle;
+#[cfg(feature = "std")]
 pub mod pipelined;
+#[cfg(feature = "std")]
 pub mod single_cycle;
+
+#[cfg(all(test, feature = "std"))]
+mod test_utils;
+
+/// Run with `cargo test --lib --no-default-features`
+/// to check the core on its own
+#[cfg(test)]
+mod tests {
+    use crate::alu::alu;
+    use crate::instruction::{Function, Instruction};
+
+    #[test]
+    fn test_core_decode() {
+        // addi x5, x6, 20
+        let inst = Instruction::new(0x01430293);
+        assert_eq!(inst.function, Function::ADDI);
+        assert_eq!(inst.attributes.rd, Some(5));
+        assert_eq!(alu(&inst, 22, inst.attributes.imm.unwrap() as i32), 42);
+        // Bubbles decode without the lazily decoded NOP of std
+        assert_eq!(Instruction::default().function, Function::ADDI);
+    }
+}
@@ -2,9 +2,12 @@
 
 use crate::cpu::CPUState;
 use crate::elf_helper::*;
-use crate::memory::mmu::MMU;
+use crate::error::{SimulatorError, SimulatorResult};
+use crate::memory::mmu::{MMU, PAGE_SIZE};
 use object::read::elf::*;
-use std::error::Error;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
 
 /// Initializes the stack for the CPU
 pub fn set_stack(
@@ -17,54 +20,179 @@ pub fn set_stack(
     cpu.stack_size = stack_size;
 
     // Initialize SP register
-    cpu.gpr[2].write(stack_base);
+    cpu.write_reg(2, stack_base);
 
     // Allocate the stack memory for (stack_base - stack_size, stack_base]
-    for address in stack_base - stack_size + 1..stack_base + 1 {
-        mem.allocate_page(address);
-        mem.set8(address, 0);
+    let bottom = cpu.stack_limit().map_or(0, |limit| limit + 1);
+    let zeros = vec![0; (stack_base - bottom) as usize + 1];
+    mem.write_bytes(bottom, &zeros);
+}
+
+/// Pushes the program arguments onto the stack.
+/// The strings go on top, followed by argv (NULL-terminated),
+/// an empty envp and finally argc, which SP points at.
+/// a0 and a1 are also set to argc and argv for convenience
+pub fn setup_argv(cpu: &mut CPUState, mem: &mut MMU, args: &[&str]) {
+    let mut sp = cpu.read_reg(2);
+
+    // Push the strings
+    let mut pointers = Vec::new();
+    for arg in args.iter().rev() {
+        sp -= arg.len() as u32 + 1;
+        for (i, byte) in arg.bytes().chain([0]).enumerate() {
+            mem.set8(sp + i as u32, byte);
+        }
+        pointers.push(sp);
     }
+    pointers.reverse();
+
+    // argc, argv[0..argc], NULL, envp NULL
+    let mut words = vec![args.len() as u32];
+    words.extend(&pointers);
+    words.extend([0, 0]);
+
+    // SP must be 16-byte aligned
+    sp = (sp - 4 * words.len() as u32) & !0xf;
+    for (i, word) in words.iter().enumerate() {
+        for j in 0..4 {
+            mem.set8(sp + 4 * i as u32 + j, (word >> (8 * j)) as u8);
+        }
+    }
+
+    cpu.write_reg(2, sp);
+    cpu.write_reg(10, args.len() as u32);
+    cpu.write_reg(11, sp + 4);
 }
 
+/// Load bias of position-independent executables (ET_DYN),
+/// whose segments are linked at addresses starting from 0
+pub const PIE_LOAD_BIAS: u32 = 0x10000;
+
 /// Loads an ELF file for the CPU
 pub fn load_elf(
     cpu: &mut CPUState,
     mem: &mut MMU,
     elf_reader: &ELFReaderType,
     elf_data: &[u8],
-) -> Result<(), Box<dyn Error>> {
+) -> SimulatorResult<()> {
+    let segments = get_elf_segments(elf_reader, elf_data)?;
+    load_segments(cpu, mem, elf_reader, segments, |offset, size| {
+        elf_data
+            .get(offset..offset + size)
+            .map(Cow::Borrowed)
+            .ok_or_else(|| {
+                SimulatorError::InvalidFormat(
+                    "Segment out of file bounds".to_string(),
+                )
+            })
+    })
+}
+
+/// Loads the ELF file at the given path for the CPU,
+/// reading each segment from the file on demand
+/// instead of holding the whole file in memory
+pub fn load_elf_streaming(
+    cpu: &mut CPUState,
+    mem: &mut MMU,
+    path: &str,
+) -> SimulatorResult<()> {
+    let mut file = File::open(path)?;
+    let (elf_reader, segments) = read_elf_headers(&mut file)?;
+    load_segments(cpu, mem, &elf_reader, segments, |offset, size| {
+        let mut bytes = vec![0; size];
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.read_exact(&mut bytes).map_err(|_| {
+            SimulatorError::InvalidFormat(
+                "Segment out of file bounds".to_string(),
+            )
+        })?;
+        Ok(Cow::Owned(bytes))
+    })
+}
+
+/// Sets the program entry and loads the loadable segments,
+/// whose file bytes are read by read_bytes(offset, size)
+fn load_segments<'a>(
+    cpu: &mut CPUState,
+    mem: &mut MMU,
+    elf_reader: &ELFReaderType,
+    segments: Vec<Segment>,
+    mut read_bytes: impl FnMut(usize, usize) -> SimulatorResult<Cow<'a, [u8]>>,
+) -> SimulatorResult<()> {
     let endian = get_elf_endian(elf_reader)?;
 
+    // Memory accesses are assembled in little-endian
+    if endian == object::Endianness::Big {
+        return Err(SimulatorError::InvalidFormat(
+            "Big-endian ELF files are not supported".to_string(),
+        ));
+    }
+
+    // Relocate everything by the load bias
+    let bias = match cpu.policy.load_bias {
+        Some(bias) => bias,
+        None if elf_reader.e_type(endian) == object::elf::ET_DYN => {
+            PIE_LOAD_BIAS
+        }
+        None => 0,
+    };
+    let out_of_bounds = || SimulatorError::from("Memory address out of bounds");
+    if cpu.policy.verbose && bias != 0 {
+        eprintln!("[VERBOSE] Load bias: {:#010x}", bias);
+    }
+
     // Set program entry
     match get_elf_entry(elf_reader) {
         Ok(entry) => {
-            cpu.pc.write(entry);
+            cpu.pc
+                .write(entry.checked_add(bias).ok_or_else(out_of_bounds)?);
         }
         Err(e) => {
             return Err(e);
         }
     }
 
-    if cpu.policy.verbose {
-        // Print the initial PC
-        eprintln!("[VERBOSE] Initial PC: {:#010x}", cpu.pc.read());
+    // Get the loadable segments, skipping notes, GNU_STACK and such
+    let segments: Vec<Segment> = segments
+        .into_iter()
+        .filter(|segment| segment.p_type(endian) == object::elf::PT_LOAD)
+        .collect();
+
+    // Loadable segments must not overlap
+    let mut ranges: Vec<(u64, u64)> = segments
+        .iter()
+        .map(|segment| {
+            let start = segment.p_vaddr(endian) as u64 + bias as u64;
+            (start, start + segment.p_memsz(endian) as u64)
+        })
+        .filter(|(start, end)| start < end)
+        .collect();
+    ranges.sort();
+    for pair in ranges.windows(2) {
+        if pair[1].0 < pair[0].1 {
+            return Err(SimulatorError::InvalidFormat(format!(
+                "Segments overlap at {:#010x}",
+                pair[1].0
+            )));
+        }
     }
 
-    // Get all segments (program headers)
-    let segments = get_elf_segments(elf_reader, elf_data)?;
-    for segment in segments {
+    for segment in segments.iter() {
         // Load the segment into memory
 
         // Get memory size
         let memory_size = segment.p_memsz(endian);
-        // Get virtual address
-        let virtual_address = segment.p_vaddr(endian);
+        // Get virtual address, relocated
+        let virtual_address = segment
+            .p_vaddr(endian)
+            .checked_add(bias)
+            .ok_or_else(out_of_bounds)?;
         // Get file size
         let file_size = segment.p_filesz(endian);
 
         // Can't handle with 32b memory
         if virtual_address.checked_add(memory_size).is_none() {
-            return Err("Memory address out of bounds".into());
+            return Err(out_of_bounds());
         }
 
         if cpu.policy.verbose {
@@ -75,28 +203,399 @@ pub fn load_elf(
             eprintln!();
         }
 
-        for address in virtual_address..virtual_address + memory_size {
-            // Allocate the page if it doesn't exist
-            if !mem.page_exists(address) {
-                mem.allocate_page(address);
+        // Bytes in the file, followed by zeros
+        let file_size = file_size.min(memory_size);
+        let file_offset = segment.p_offset(endian) as usize;
+        let file_bytes = read_bytes(file_offset, file_size as usize)?;
+        mem.write_bytes(virtual_address, &file_bytes);
+        let zeros = vec![0; (memory_size - file_size) as usize];
+        mem.write_bytes(virtual_address + file_size, &zeros);
+    }
+
+    // Nothing may be allocated right after a segment,
+    // including the stack and the other segments
+    if cpu.policy.guard_pages {
+        for &(_, end) in ranges.iter() {
+            // The page after the one holding the last byte
+            let guard = ((end - 1) | (PAGE_SIZE as u64 - 1)) + 1;
+            if guard <= u32::MAX as u64 && mem.page_exists(guard as u32) {
+                return Err(SimulatorError::InvalidFormat(format!(
+                    "No guard page after the segment ending at {:#010x}",
+                    end
+                )));
+            }
+        }
+    }
+
+    // Start elsewhere if asked to, as long as there is code
+    if let Some(entry) = cpu.policy.entry_override {
+        let executable = segments.iter().any(|segment| {
+            let start = segment.p_vaddr(endian).checked_add(bias);
+            segment.p_flags(endian) & object::elf::PF_X != 0
+                && start.is_some_and(|start| {
+                    start <= entry && entry - start < segment.p_memsz(endian)
+                })
+        });
+        if !executable {
+            return Err(SimulatorError::Other(format!(
+                "Entry {:#010x} is outside every executable segment",
+                entry
+            )));
+        }
+        cpu.pc.write(entry);
+    }
+
+    if cpu.policy.verbose {
+        // Print the initial PC
+        eprintln!("[VERBOSE] Initial PC: {:#010x}", cpu.pc.read());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CPUPolicy, POISON_WORD};
+    use crate::memory::inclusive::InclusiveCache;
+    use crate::memory::mmu::POISON_BYTE;
+    use crate::single_cycle;
+    use crate::system_call::SyscallIo;
+    use crate::test_utils::load_program;
+
+    #[test]
+    fn test_big_endian() {
+        // A minimal big-endian ELF32 header for RISC-V
+        let mut data = vec![0u8; 52];
+        data[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 2, 1]);
+        // e_type = ET_EXEC, e_machine = EM_RISCV, e_version = 1
+        data[16..24].copy_from_slice(&[0, 2, 0, 243, 0, 0, 0, 1]);
+        // e_ehsize
+        data[40..42].copy_from_slice(&[0, 52]);
+
+        let (elf_reader, elf_data) = parse_elf_bytes(data).unwrap();
+        assert_eq!(
+            get_elf_endian(&elf_reader).unwrap(),
+            object::Endianness::Big
+        );
+        assert_eq!(get_elf_machine(&elf_reader).unwrap(), 243);
+
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = MMU::make();
+        let result = load_elf(&mut cpu, &mut mem, &elf_reader, &elf_data);
+        assert!(matches!(result, Err(SimulatorError::InvalidFormat(_))));
+    }
+
+    /// Makes a little-endian RISC-V ELF32 file with the given
+    /// (p_type, p_offset, p_vaddr, p_filesz, p_memsz) program headers
+    fn make_elf(segments: &[(u32, u32, u32, u32, u32)]) -> Vec<u8> {
+        let mut data = vec![0u8; 52];
+        data[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1]);
+        // e_type = ET_EXEC, e_machine = EM_RISCV, e_version = 1
+        data[16..24].copy_from_slice(&[2, 0, 243, 0, 1, 0, 0, 0]);
+        // e_entry and e_phoff
+        data[24..28].copy_from_slice(&0x1000u32.to_le_bytes());
+        data[28..32].copy_from_slice(&52u32.to_le_bytes());
+        // e_ehsize, e_phentsize and e_phnum
+        data[40..42].copy_from_slice(&52u16.to_le_bytes());
+        data[42..44].copy_from_slice(&32u16.to_le_bytes());
+        data[44..46].copy_from_slice(&(segments.len() as u16).to_le_bytes());
+        for &(p_type, offset, vaddr, file_size, memory_size) in segments {
+            for field in [p_type, offset, vaddr, vaddr, file_size, memory_size]
+            {
+                data.extend_from_slice(&field.to_le_bytes());
             }
+            // p_flags and p_align
+            data.extend_from_slice(&[0; 8]);
+        }
+        data
+    }
+
+    #[test]
+    fn test_loadable_segments_only() {
+        use object::elf::{PT_GNU_STACK, PT_LOAD, PT_NOTE};
+        let data = make_elf(&[
+            // The ELF header itself, at file offset 0
+            (PT_LOAD, 0, 0x1000, 16, 32),
+            // A note whose address would clobber the segment above
+            (PT_NOTE, 52, 0x1000, 16, 16),
+            (PT_NOTE, 52, 0x5000, 16, 16),
+            (PT_GNU_STACK, 0, 0, 0, 0),
+        ]);
+        let (elf_reader, elf_data) = parse_elf_bytes(data).unwrap();
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = MMU::make();
+        load_elf(&mut cpu, &mut mem, &elf_reader, &elf_data).unwrap();
+
+        for i in 0..16 {
+            assert_eq!(mem.get8(0x1000 + i), elf_data[i as usize]);
+        }
+        assert_eq!(mem.get8(0x1010), 0);
+        assert!(!mem.page_exists(0x5000));
+        assert!(!mem.page_exists(0));
+    }
+
+    #[test]
+    fn test_overlapping_segments() {
+        use object::elf::PT_LOAD;
+        let data = make_elf(&[
+            (PT_LOAD, 0, 0x1000, 16, 0x100),
+            (PT_LOAD, 0, 0x10f0, 16, 16),
+        ]);
+        let (elf_reader, elf_data) = parse_elf_bytes(data).unwrap();
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = MMU::make();
+        let result = load_elf(&mut cpu, &mut mem, &elf_reader, &elf_data);
+        assert!(matches!(result, Err(SimulatorError::InvalidFormat(_))));
+
+        // Adjacent segments are fine
+        let data = make_elf(&[
+            (PT_LOAD, 0, 0x1000, 16, 0x100),
+            (PT_LOAD, 0, 0x1100, 16, 16),
+        ]);
+        let (elf_reader, elf_data) = parse_elf_bytes(data).unwrap();
+        load_elf(&mut cpu, &mut mem, &elf_reader, &elf_data).unwrap();
+    }
+
+    #[test]
+    fn test_guard_pages() {
+        use crate::error::MemoryError;
+        use crate::memory::StorageInterface;
+        use object::elf::PT_LOAD;
+
+        let load = |segments: &[(u32, u32, u32, u32, u32)]| {
+            let policy = CPUPolicy {
+                guard_pages: true,
+                ..Default::default()
+            };
+            let mut cpu = CPUState::make(policy);
+            let mut mem = InclusiveCache::default();
+            let (elf_reader, elf_data) = parse_elf_bytes(make_elf(segments))?;
+            load_elf(&mut cpu, &mut mem.mmu, &elf_reader, &elf_data)?;
+            Ok::<_, SimulatorError>(mem)
+        };
+
+        // Writing past the end of the segment faults
+        let mut mem = load(&[
+            (PT_LOAD, 0, 0x1000, 16, 0x1000),
+            (PT_LOAD, 0, 0x3000, 16, 16),
+        ])
+        .unwrap();
+        mem.set8(0x1fff, 1, &mut Some(0)).unwrap();
+        let result = mem.set8(0x2000, 1, &mut Some(0));
+        assert!(matches!(
+            result,
+            Err(SimulatorError::Memory(MemoryError::WriteUnallocated(
+                0x2000
+            )))
+        ));
+
+        // A segment ending mid-page is rounded up to the whole page,
+        // so only overruns past that page fault
+        let mut mem = load(&[(PT_LOAD, 0, 0x1000, 16, 0x800)]).unwrap();
+        mem.set8(0x1800, 1, &mut Some(0)).unwrap();
+        mem.set8(0x1fff, 1, &mut Some(0)).unwrap();
+        assert!(mem.set8(0x2000, 1, &mut Some(0)).is_err());
+
+        // A segment right after another leaves no room for a guard page
+        let result = load(&[
+            (PT_LOAD, 0, 0x1000, 16, 0x1000),
+            (PT_LOAD, 0, 0x2000, 16, 16),
+        ]);
+        assert!(matches!(result, Err(SimulatorError::InvalidFormat(_))));
+        let result = load(&[
+            (PT_LOAD, 0, 0x1000, 16, 0x800),
+            (PT_LOAD, 0, 0x2800, 16, 16),
+        ]);
+        assert!(matches!(result, Err(SimulatorError::InvalidFormat(_))));
+    }
+
+    /// Loads the ELF file with the given entry override
+    fn load_with_entry(
+        data: Vec<u8>,
+        entry_override: Option<u32>,
+    ) -> SimulatorResult<(CPUState, InclusiveCache)> {
+        let policy = CPUPolicy {
+            entry_override,
+            ..Default::default()
+        };
+        let mut cpu = CPUState::make(policy);
+        let mut mem = InclusiveCache::default();
+        let (elf_reader, elf_data) = parse_elf_bytes(data)?;
+        load_elf(&mut cpu, &mut mem.mmu, &elf_reader, &elf_data)?;
+        Ok((cpu, mem))
+    }
 
-            assert!(mem.page_exists(address));
-
-            // If this is in the file
-            let file_offset = address - virtual_address;
-            if file_offset < file_size {
-                // Get the byte from the file
-                let byte = elf_data
-                    [segment.p_offset(endian) as usize + file_offset as usize];
-                // Set the byte in memory
-                mem.set8(address, byte);
-            } else {
-                // Otherwise, set the byte to 0
-                mem.set8(address, 0);
+    #[test]
+    fn test_entry_override() {
+        use object::elf::{PF_R, PF_X, PT_LOAD};
+        let code: [u32; 4] = [
+            0x00100513, // addi a0, x0, 1
+            0x00250513, // addi a0, a0, 2
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        // The code right after the header and the program header
+        let mut data = make_elf(&[(PT_LOAD, 84, 0x1000, 16, 16)]);
+        for word in code {
+            data.extend_from_slice(&word.to_le_bytes());
+        }
+        // Not executable yet
+        assert!(load_with_entry(data.clone(), Some(0x1004)).is_err());
+        // p_flags of the only program header
+        data[76..80].copy_from_slice(&(PF_R | PF_X).to_le_bytes());
+
+        let (cpu, _) = load_with_entry(data.clone(), None).unwrap();
+        assert_eq!(cpu.pc.read(), 0x1000);
+        // Skipping the first instruction
+        let (mut cpu, mut mem) =
+            load_with_entry(data.clone(), Some(0x1004)).unwrap();
+        assert_eq!(cpu.pc.read(), 0x1004);
+        single_cycle::run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        assert_eq!(cpu.gpr[10].read(), 2);
+        assert_eq!(cpu.history.inst_count, 3);
+        // Out of the segment
+        assert!(load_with_entry(data.clone(), Some(0x1010)).is_err());
+        assert!(load_with_entry(data, Some(0xffc)).is_err());
+    }
+
+    #[test]
+    fn test_load_bias() {
+        let path = "test-riscv/helloworld.riscv";
+        let (elf_reader, exec_data) = parse_elf_file(path).unwrap();
+        let main = get_symbols(&elf_reader, &exec_data)
+            .unwrap()
+            .into_iter()
+            .find(|(name, _, _)| name == "main")
+            .unwrap()
+            .1;
+        // Turn it into a PIE, which is all the loader looks at
+        let mut pie_data = exec_data.clone();
+        pie_data[16..18].copy_from_slice(&object::elf::ET_DYN.to_le_bytes());
+
+        let load = |data: &[u8], load_bias: Option<u32>| {
+            let policy = CPUPolicy {
+                load_bias,
+                ..Default::default()
+            };
+            let mut cpu = CPUState::make(policy);
+            let mut mem = MMU::make();
+            let (elf_reader, elf_data) =
+                parse_elf_bytes(data.to_vec()).unwrap();
+            load_elf(&mut cpu, &mut mem, &elf_reader, &elf_data).unwrap();
+            (cpu.pc.read(), mem)
+        };
+        let (entry, mem) = load(&exec_data, None);
+        let main_code: Vec<Option<u32>> =
+            (0..4).map(|i| mem.try_get32(main + 4 * i)).collect();
+        assert!(main_code.iter().all(Option::is_some));
+
+        for (data, load_bias) in [
+            (&pie_data, Some(0x10000)),
+            // PIEs are biased by default
+            (&pie_data, None),
+            // as are other executables when asked to
+            (&exec_data, Some(0x10000)),
+        ] {
+            let (biased_entry, mem) = load(data, load_bias);
+            assert_eq!(biased_entry, entry + PIE_LOAD_BIAS);
+            for (i, word) in main_code.iter().enumerate() {
+                let address = main + PIE_LOAD_BIAS + 4 * i as u32;
+                assert_eq!(mem.try_get32(address), *word);
             }
         }
+
+        // Relocating past the end of the address space
+        let mut cpu = CPUState::make(CPUPolicy {
+            load_bias: Some(0xfffff000),
+            ..Default::default()
+        });
+        let result =
+            load_elf(&mut cpu, &mut MMU::make(), &elf_reader, &exec_data);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_poison() {
+        let policy = CPUPolicy {
+            poison: true,
+            ..Default::default()
+        };
+        let mut cpu = CPUState::make(policy);
+        let mut mem = MMU::make();
+        mem.poison = policy.poison;
+
+        let (elf_reader, elf_data) =
+            parse_elf_file("test-riscv/helloworld.riscv").unwrap();
+        load_elf(&mut cpu, &mut mem, &elf_reader, &elf_data).unwrap();
+
+        // Uninitialized registers, except x0
+        assert_eq!(cpu.gpr[0].read(), 0);
+        assert_eq!(cpu.gpr[10].read(), POISON_WORD);
+
+        // The data segment starts at 0x130f0 with file offset 0x20f0
+        assert_eq!(mem.get8(0x130f0), elf_data[0x20f0]);
+        // The bytes before it share the page but are never loaded
+        assert_eq!(mem.get8(0x13000), POISON_BYTE);
     }
 
-    return Ok(());
+    #[test]
+    fn test_load_elf_streaming() {
+        let elf_file = "test-riscv/helloworld.riscv";
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = MMU::make();
+        let (elf_reader, elf_data) = parse_elf_file(elf_file).unwrap();
+        load_elf(&mut cpu, &mut mem, &elf_reader, &elf_data).unwrap();
+
+        let mut streamed_cpu = CPUState::make(CPUPolicy::default());
+        let mut streamed_mem = MMU::make();
+        load_elf_streaming(&mut streamed_cpu, &mut streamed_mem, elf_file)
+            .unwrap();
+        assert_eq!(streamed_cpu.pc.read(), cpu.pc.read());
+        assert!(streamed_mem.same_contents(&mem));
+    }
+
+    #[test]
+    fn test_setup_argv() {
+        let program = [
+            0x00012503, // lw a0, 0(sp)
+            0x00812283, // lw t0, 8(sp)
+            0x0002c303, // lbu t1, 0(t0)
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let args = ["prog", "xy", "z"];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        set_stack(&mut cpu, &mut mem.mmu, 0x10000, 0x1000);
+        setup_argv(&mut cpu, &mut mem.mmu, &args);
+        let sp = cpu.read_reg(2);
+        assert_eq!(sp % 16, 0);
+        assert_eq!(cpu.read_reg(11), sp + 4);
+
+        // argc, then argv[0..argc] and the NULL ending it
+        let mmu = &mut mem.mmu;
+        let mut word = |address: u32| {
+            u32::from_le_bytes([0, 1, 2, 3].map(|i| mmu.get8(address + i)))
+        };
+        assert_eq!(word(sp), args.len() as u32);
+        let pointers: Vec<u32> = (0..=args.len() as u32)
+            .map(|i| word(sp + 4 + 4 * i))
+            .collect();
+        assert_eq!(pointers[args.len()], 0);
+        for (arg, &pointer) in args.iter().zip(&pointers) {
+            let bytes: Vec<u8> = (pointer..)
+                .map(|address| mem.mmu.get8(address))
+                .take_while(|&byte| byte != 0)
+                .collect();
+            assert_eq!(bytes, arg.as_bytes());
+        }
+
+        // What the guest sees
+        single_cycle::run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        // argc
+        assert_eq!(cpu.read_reg(10), 3);
+        // argv[1][0]
+        assert_eq!(cpu.read_reg(6), b'x' as u32);
+    }
 }
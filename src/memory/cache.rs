@@ -1,6 +1,8 @@
 //! Cache implementation
 
 use super::AccessType;
+use crate::error::{SimulatorError, SimulatorResult};
+use std::collections::HashSet;
 
 pub fn get_log_2(value: u32) -> usize {
     assert!(value > 0);
@@ -30,6 +32,13 @@ pub struct Cache {
     pub tag_mask: u32,
 
     pub blocks: Vec<Block>,
+
+    /// Block addresses that have ever been touched,
+    /// used to tell compulsory misses apart
+    seen_blocks: HashSet<u32>,
+
+    /// Next way to evict in each set under round-robin replacement
+    next_victims: Vec<usize>,
 }
 
 // Assume that address is 32-bit
@@ -48,12 +57,12 @@ impl Cache {
 
         // Initialize blocks
         let mut blocks = vec![Block::default(); policy.block_num];
-        for i in 0..blocks.len() {
-            blocks[i].index = i / policy.associativity;
+        for (i, block) in blocks.iter_mut().enumerate() {
+            block.index = i / policy.associativity;
         }
 
         Self {
-            policy: policy,
+            policy,
             history: CacheHistory::default(),
             offset_bits,
             index_bits,
@@ -61,6 +70,8 @@ impl Cache {
             index_mask,
             tag_mask,
             blocks,
+            seen_blocks: HashSet::new(),
+            next_victims: vec![0; policy.block_num / policy.associativity],
         }
     }
 
@@ -73,6 +84,10 @@ impl Cache {
             tag: self.get_tag(address),
             index: self.get_index(address),
             prv_ref: 0,
+            prefetched: false,
+            num_writes: 0,
+            num_refs: 0,
+            data: None,
         }
     }
 
@@ -83,12 +98,56 @@ impl Cache {
         block.tag = 0;
         block.index = i / self.policy.associativity;
         block.prv_ref = 0;
+        block.prefetched = false;
+        block.num_writes = 0;
+        block.num_refs = 0;
+        block.data = None;
+    }
+
+    /// Invalidates all blocks and clears the history
+    pub fn reset(&mut self) {
+        for i in 0..self.blocks.len() {
+            self.reset_block(i);
+        }
+        self.history = CacheHistory::default();
+        self.seen_blocks.clear();
+        self.next_victims.fill(0);
     }
 
-    /// Computes the current miss rate of the cache
+    /// Computes the current miss rate of the cache,
+    /// which is 0 before any access
     pub fn get_miss_rate(&self) -> f64 {
-        (self.history.num_miss as f64)
-            / ((self.history.num_hit + self.history.num_miss) as f64)
+        self.rate_of(self.history.num_miss)
+    }
+
+    /// Computes the miss rate excluding compulsory misses,
+    /// i.e., the conflict and capacity miss rate
+    pub fn get_non_compulsory_miss_rate(&self) -> f64 {
+        self.rate_of(self.history.num_miss - self.history.num_compulsory_miss)
+    }
+
+    /// Fraction of all accesses so far that the count makes up
+    fn rate_of(&self, count: i32) -> f64 {
+        let num_accesses = self.history.num_hit + self.history.num_miss;
+        if num_accesses == 0 {
+            return 0.0;
+        }
+        count as f64 / num_accesses as f64
+    }
+
+    /// Fraction of the blocks that are valid
+    pub fn occupancy(&self) -> f64 {
+        let num_valid = self.blocks.iter().filter(|block| block.valid).count();
+        num_valid as f64 / self.blocks.len() as f64
+    }
+
+    /// Number of valid blocks in each set.
+    /// Full sets in a cache that isn't full point at conflict misses
+    pub fn set_occupancy(&self) -> Vec<usize> {
+        self.blocks
+            .chunks(self.policy.associativity)
+            .map(|set| set.iter().filter(|block| block.valid).count())
+            .collect()
     }
 
     /// Given a block that is not necessarily
@@ -108,6 +167,8 @@ impl Cache {
         let block = &mut self.blocks[i];
         block.tag = tag;
         block.index = index;
+        // It starts over in this cache
+        block.num_refs = 0;
         assert!(index == i / self.policy.associativity);
     }
 
@@ -123,9 +184,32 @@ impl Cache {
         (address >> (self.offset_bits + self.index_bits)) & self.tag_mask
     }
 
+    /// Returns the address of the block containing the given address
+    pub fn get_block_address(&self, address: u32) -> u32 {
+        address & !self.offset_mask
+    }
+
     pub fn get_address(&self, block: &Block) -> u32 {
-        (block.tag << (self.offset_bits + self.index_bits))
-            | ((block.index as u32) << self.offset_bits)
+        self.compose(block.tag, block.index, 0)
+    }
+
+    /// Splits an address into (tag, index, offset)
+    pub fn decompose(&self, address: u32) -> (u32, usize, u32) {
+        let tag = self.get_tag(address);
+        let index = self.get_index(address);
+        let offset = address & self.offset_mask;
+        assert_eq!(self.compose(tag, index, offset), address);
+        (tag, index, offset)
+    }
+
+    /// Joins a tag, an index and an offset into an address
+    pub fn compose(&self, tag: u32, index: usize, offset: u32) -> u32 {
+        assert!(tag <= self.tag_mask);
+        assert!(index as u32 <= self.index_mask);
+        assert!(offset <= self.offset_mask);
+        (tag << (self.offset_bits + self.index_bits))
+            | ((index as u32) << self.offset_bits)
+            | offset
     }
 
     pub fn is_in_cache(&self, address: u32) -> bool {
@@ -150,18 +234,42 @@ impl Cache {
         None
     }
 
-    pub fn record_hit(&mut self) {
+    pub fn record_hit(&mut self, address: u32) {
         self.history.num_hit += 1;
+        self.seen_blocks.insert(self.get_block_address(address));
     }
 
-    pub fn record_miss(&mut self) {
+    /// Records a miss, which is also compulsory
+    /// if the block is touched for the first time
+    pub fn record_miss(&mut self, address: u32) {
         self.history.num_miss += 1;
+        if self.seen_blocks.insert(self.get_block_address(address)) {
+            self.history.num_compulsory_miss += 1;
+        }
     }
 
-    pub fn get_index_to_replace(&self, index: usize) -> usize {
+    pub fn get_index_to_replace(&mut self, index: usize) -> usize {
         let begin = index * self.policy.associativity;
         let end = (index + 1) * self.policy.associativity;
         assert!(begin < end);
+        if self.policy.replacement == ReplacementPolicy::RoundRobin {
+            // Invalid blocks are still replaced first
+            if let Some(i) = (begin..end).find(|&i| !self.blocks[i].valid) {
+                return i;
+            }
+            let way = self.next_victims[index];
+            self.next_victims[index] = (way + 1) % self.policy.associativity;
+            return begin + way;
+        }
+        if self.policy.replacement == ReplacementPolicy::LFU {
+            // Invalid blocks are still replaced first
+            return (begin..end)
+                .min_by_key(|&i| {
+                    let block = &self.blocks[i];
+                    (block.valid, block.num_refs, block.prv_ref)
+                })
+                .unwrap();
+        }
         let mut result = begin;
         let mut min_ref = self.blocks[begin].prv_ref;
         for i in begin..end {
@@ -201,12 +309,48 @@ impl Cache {
 
         // Update reference counter
         target_block.prv_ref = ref_counter;
+        target_block.num_refs += 1;
 
         // If it's a write, mark the block as dirty
         if access_type == AccessType::Write {
             target_block.dirty = true;
         }
     }
+
+    /// Counts a store into the block holding the address, if any.
+    /// A store is counted once however many bytes it spans
+    pub fn record_write(&mut self, address: u32) {
+        if let Some(i) = self.lookup(address) {
+            self.blocks[i].num_writes += 1;
+        }
+    }
+
+    /// Access a block just filled on a miss,
+    /// placing it according to the insertion policy
+    pub fn install_index(
+        &mut self,
+        target_index: usize,
+        access_type: AccessType,
+        ref_counter: i32,
+    ) {
+        self.access_index(target_index, access_type, ref_counter);
+        if self.policy.insertion == InsertionPolicy::MRU {
+            return;
+        }
+
+        // Right below the least recent block of the set
+        let begin = target_index / self.policy.associativity
+            * self.policy.associativity;
+        let end = begin + self.policy.associativity;
+        let least_recent = (begin..end)
+            .filter(|&i| i != target_index && self.blocks[i].valid)
+            .map(|i| self.blocks[i].prv_ref)
+            .min();
+        if let Some(least_recent) = least_recent {
+            self.blocks[target_index].prv_ref = least_recent - 1;
+        }
+        self.history.num_lru_insertion += 1;
+    }
 }
 
 #[derive(Clone, Default)]
@@ -218,15 +362,54 @@ pub struct Block {
     pub index: usize,
 
     pub prv_ref: i32,
+
+    /// Brought in by a prefetch and not demanded yet
+    pub prefetched: bool,
+
+    /// Stores combined into the block since it was installed,
+    /// a write-back from the level above counting as one
+    pub num_writes: i32,
+
+    /// References since the block was installed, for LFU
+    pub num_refs: i32,
+
+    /// Contents of the block, if the cache holds data
+    /// rather than only modeling the timing
+    pub data: Option<Vec<u8>>,
 }
 
-#[derive(Clone, Copy, Default, Debug)]
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
 pub struct CacheHistory {
     pub num_hit: i32,
     pub num_miss: i32,
+    /// Misses on blocks that were never touched before
+    pub num_compulsory_miss: i32,
+    /// Dirty blocks evicted and written to the next level
+    pub num_writeback: i32,
+    /// Stores combined into the blocks written back
+    pub num_combined_write: i32,
+    /// Blocks brought in by prefetches
+    pub num_prefetch: i32,
+    /// Prefetched blocks demanded later
+    pub num_useful_prefetch: i32,
+    /// Prefetched blocks evicted before being demanded
+    pub num_useless_prefetch: i32,
+    /// Blocks filled at the LRU position
+    pub num_lru_insertion: i32,
 }
 
-#[derive(Clone, Copy)]
+impl CacheHistory {
+    /// Average number of stores each write-back carries,
+    /// or 0 without write-backs
+    pub fn writes_per_writeback(&self) -> f64 {
+        if self.num_writeback == 0 {
+            return 0.0;
+        }
+        self.num_combined_write as f64 / self.num_writeback as f64
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct CachePolicy {
     pub cache_size: usize,
     pub block_size: usize,
@@ -234,6 +417,33 @@ pub struct CachePolicy {
     pub associativity: usize,
 
     pub hit_latency: i32,
+
+    pub replacement: ReplacementPolicy,
+    pub insertion: InsertionPolicy,
+}
+
+/// Where a block filled on a miss goes in the recency order
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum InsertionPolicy {
+    /// Most recently used, as a regular access
+    #[default]
+    MRU,
+    /// Least recently used (LIP), so it's evicted next
+    /// unless referenced again, which resists thrashing
+    LRU,
+}
+
+/// Which block in a set to evict
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ReplacementPolicy {
+    /// Least recently used
+    #[default]
+    LRU,
+    /// Cycle through the ways of each set
+    RoundRobin,
+    /// Least frequently used since installed,
+    /// with ties broken by recency
+    LFU,
 }
 
 impl Default for CachePolicy {
@@ -251,45 +461,338 @@ impl CachePolicy {
         associativity: usize,
         hit_latency: i32,
     ) -> Self {
-        assert!(cache_size % block_size == 0);
+        assert!(cache_size.is_multiple_of(block_size));
         Self {
             cache_size,
             block_size,
             block_num: cache_size / block_size,
             associativity,
             hit_latency,
+            replacement: ReplacementPolicy::default(),
+            insertion: InsertionPolicy::default(),
+        }
+    }
+
+    /// Make a cache policy whose hit latency
+    /// is derived from the cache size
+    pub fn make_scaled(
+        cache_size: usize,
+        block_size: usize,
+        associativity: usize,
+    ) -> Self {
+        let hit_latency = Self::scaled_hit_latency(cache_size);
+        Self::make(cache_size, block_size, associativity, hit_latency)
+    }
+
+    /// Hit latency of 1 cycle for a 4KB cache,
+    /// plus 1 cycle each time the size doubles
+    pub fn scaled_hit_latency(cache_size: usize) -> i32 {
+        const BASE_SIZE: usize = 4 * 1024;
+        if cache_size <= BASE_SIZE {
+            return 1;
         }
+        1 + get_log_2((cache_size / BASE_SIZE) as u32) as i32
+    }
+
+    /// Parses "SIZE,BLOCK,ASSOC[,LATENCY]" in bytes and cycles,
+    /// where the hit latency defaults to the scaled one
+    pub fn parse(spec: &str) -> SimulatorResult<Self> {
+        let invalid =
+            || SimulatorError::Other(format!("Invalid cache level: {}", spec));
+        let fields: Vec<&str> = spec.split(',').map(str::trim).collect();
+        if !(3..=4).contains(&fields.len()) {
+            return Err(invalid());
+        }
+        let number =
+            |field: &str| field.parse::<usize>().map_err(|_| invalid());
+        let cache_size = number(fields[0])?;
+        let block_size = number(fields[1])?;
+        let associativity = number(fields[2])?;
+        let hit_latency = match fields.get(3) {
+            Some(field) => field.parse::<i32>().map_err(|_| invalid())?,
+            None => Self::scaled_hit_latency(cache_size),
+        };
+        if hit_latency < 0 {
+            return Err(SimulatorError::Other(format!(
+                "hit_latency {} is negative",
+                hit_latency
+            )));
+        }
+        // Validate before making it, which asserts the geometry
+        let policy = Self {
+            cache_size,
+            block_size,
+            block_num: cache_size.checked_div(block_size).unwrap_or(0),
+            associativity,
+            hit_latency,
+            replacement: ReplacementPolicy::default(),
+            insertion: InsertionPolicy::default(),
+        };
+        policy.validate()?;
+        Ok(policy)
     }
 
     pub fn is_valid(&self) -> bool {
+        self.validate().is_ok()
+    }
+
+    /// Checks the geometry, describing the first problem found
+    pub fn validate(&self) -> SimulatorResult<()> {
+        let invalid = |message: String| Err(SimulatorError::Other(message));
         // Cache size must be a power of 2
         if !is_pow_2(self.cache_size as u32) {
-            return false;
+            return invalid(format!(
+                "cache_size {} is not a power of two",
+                self.cache_size
+            ));
         }
         // Block size must be a power of 2
         if !is_pow_2(self.block_size as u32) {
-            return false;
+            return invalid(format!(
+                "block_size {} is not a power of two",
+                self.block_size
+            ));
         }
         // Cache size must be a multiple of block size
-        if self.cache_size % self.block_size != 0 {
-            return false;
+        if !self.cache_size.is_multiple_of(self.block_size) {
+            return invalid(format!(
+                "cache_size {} is not a multiple of block_size {}",
+                self.cache_size, self.block_size
+            ));
         }
         // cache_size = block_size * block_num
         if self.cache_size != self.block_size * self.block_num {
-            return false;
+            return invalid(format!(
+                "block_num {} doesn't fit cache_size {}",
+                self.block_num, self.cache_size
+            ));
         }
         // Block number must be a multiple of associativity
-        if self.block_num % self.associativity != 0 {
-            return false;
+        if self.associativity == 0
+            || !self.block_num.is_multiple_of(self.associativity)
+        {
+            return invalid(format!(
+                "associativity {} doesn't divide the {} blocks",
+                self.associativity, self.block_num
+            ));
         }
-        true
+        Ok(())
     }
 }
 
+/// Parses cache levels, L1 first,
+/// from repeated "--level SIZE,BLOCK,ASSOC[,LATENCY]" arguments
+pub fn parse_levels(args: &[String]) -> SimulatorResult<Vec<CachePolicy>> {
+    let mut levels = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--level" => {
+                let spec = args
+                    .next()
+                    .ok_or("You should specify a cache level after --level")?;
+                let policy = CachePolicy::parse(spec).map_err(|e| {
+                    SimulatorError::Other(format!(
+                        "L{}: {}",
+                        levels.len() + 1,
+                        e
+                    ))
+                })?;
+                levels.push(policy);
+            }
+            _ => return Err(format!("Unknown parameter: {}", arg).into()),
+        }
+    }
+    Ok(levels)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_levels() {
+        let args: Vec<String> =
+            ["--level", "32768,64,4,2", "--level", "262144,64,8"]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect();
+        let levels = parse_levels(&args).unwrap();
+        assert_eq!(
+            levels,
+            [
+                CachePolicy::make(32 * 1024, 64, 4, 2),
+                CachePolicy::make_scaled(256 * 1024, 64, 8),
+            ]
+        );
+        assert_eq!(levels[1].hit_latency, 7);
+
+        // Validation
+        for spec in ["4096,64", "4096,48,1", "4096,64,3", "4096,64,0", "a,b,c"]
+        {
+            assert!(CachePolicy::parse(spec).is_err(), "{}", spec);
+        }
+        let args: Vec<String> =
+            ["--level", "4096,64,1", "--level", "100000,64,1"]
+                .iter()
+                .map(|arg| arg.to_string())
+                .collect();
+        assert_eq!(
+            parse_levels(&args).unwrap_err().to_string(),
+            "L2: cache_size 100000 is not a power of two"
+        );
+        assert!(parse_levels(&["--level".to_string()]).is_err());
+        assert!(parse_levels(&["--size".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_lfu() {
+        use crate::memory::inclusive::InclusiveCache;
+        use crate::memory::StorageInterface;
+
+        // A hot block followed by a stream of cold ones,
+        // all in a single 4-way set
+        let hot_survives = |replacement: ReplacementPolicy| {
+            let mut policy = CachePolicy::make(256, 64, 4, 1);
+            policy.replacement = replacement;
+            let mut cache =
+                InclusiveCache::builder().level(policy).build().unwrap();
+            cache.mmu().allocate_page(0);
+            for _ in 0..3 {
+                cache.get8(0, &mut Some(0)).unwrap();
+            }
+            for i in 1..=6 {
+                cache.get8(i * 64, &mut Some(0)).unwrap();
+            }
+            cache.caches[0].lookup(0).is_some()
+        };
+        assert!(hot_survives(ReplacementPolicy::LFU));
+        assert!(!hot_survives(ReplacementPolicy::LRU));
+    }
+
+    #[test]
+    fn test_round_robin() {
+        // A single 4-way set
+        let mut policy = CachePolicy::make(256, 64, 4, 1);
+        policy.replacement = ReplacementPolicy::RoundRobin;
+        let mut cache = Cache::make(policy);
+
+        let mut victims = Vec::new();
+        for i in 0..9 {
+            let address = i * 256;
+            let victim = cache.get_index_to_replace(0);
+            cache.blocks[victim] = cache.make_block(address);
+            victims.push(victim);
+        }
+        assert_eq!(victims, [0, 1, 2, 3, 0, 1, 2, 3, 0]);
+    }
+
+    /// Cycles through 5 blocks in a single 4-way set,
+    /// and returns the number of misses
+    fn count_thrashing_misses(insertion: InsertionPolicy) -> i32 {
+        use crate::memory::inclusive::InclusiveCache;
+        use crate::memory::StorageInterface;
+
+        let mut policy = CachePolicy::make(256, 64, 4, 1);
+        policy.insertion = insertion;
+        let mut cache =
+            InclusiveCache::builder().level(policy).build().unwrap();
+        cache.mmu().allocate_page(0);
+        for _ in 0..10 {
+            for i in 0..5 {
+                cache.get8(i * 64, &mut Some(0)).unwrap();
+            }
+        }
+        let history = cache.get_history()[0];
+        match insertion {
+            InsertionPolicy::MRU => assert_eq!(history.num_lru_insertion, 0),
+            InsertionPolicy::LRU => {
+                assert_eq!(history.num_lru_insertion, history.num_miss)
+            }
+        }
+        history.num_miss
+    }
+
+    #[test]
+    fn test_lru_insertion() {
+        // Every access misses with MRU insertion
+        assert_eq!(count_thrashing_misses(InsertionPolicy::MRU), 50);
+        // LIP keeps 3 of the blocks
+        assert_eq!(count_thrashing_misses(InsertionPolicy::LRU), 5 + 9 * 2);
+    }
+
+    #[test]
+    fn test_occupancy() {
+        use crate::memory::inclusive::InclusiveCache;
+        use crate::memory::StorageInterface;
+
+        // 4 sets of 2 64-byte blocks
+        let mut cache = InclusiveCache::builder()
+            .level(CachePolicy::make(512, 64, 2, 1))
+            .build()
+            .unwrap();
+        cache.mmu().allocate_page(0);
+        assert_eq!(cache.caches[0].occupancy(), 0.0);
+
+        // 3 lines mapping to sets 0, 1 and 0
+        for address in [0, 64, 256] {
+            cache.get8(address, &mut Some(0)).unwrap();
+        }
+        assert_eq!(cache.caches[0].occupancy(), 3.0 / 8.0);
+        assert_eq!(cache.caches[0].set_occupancy(), [2, 1, 0, 0]);
+
+        // A working set twice the capacity
+        for address in (0..1024).step_by(64) {
+            cache.get8(address, &mut Some(0)).unwrap();
+        }
+        assert_eq!(cache.caches[0].occupancy(), 1.0);
+        assert_eq!(cache.caches[0].set_occupancy(), [2; 4]);
+    }
+
+    #[test]
+    fn test_rates_without_accesses() {
+        let mut cache = Cache::make(CachePolicy::default());
+        assert_eq!(cache.get_miss_rate(), 0.0);
+        assert_eq!(cache.get_non_compulsory_miss_rate(), 0.0);
+
+        cache.record_miss(0x1000);
+        cache.record_miss(0x1000);
+        cache.record_hit(0x1000);
+        cache.record_hit(0x1000);
+        assert_eq!(cache.get_miss_rate(), 0.5);
+        assert_eq!(cache.get_non_compulsory_miss_rate(), 0.25);
+    }
+
+    #[test]
+    fn test_decompose() {
+        // 64 sets of 4 64-byte blocks: 6 offset bits and 6 index bits
+        let cache = Cache::make(CachePolicy::make(16 * 1024, 64, 4, 1));
+        assert_eq!(cache.decompose(0x12345678), (0x12345, 0x19, 0x38));
+        for address in [0, 0x3f, 0x40, 0xfff, 0x1000, 0x80001234, u32::MAX] {
+            let (tag, index, offset) = cache.decompose(address);
+            assert_eq!(cache.compose(tag, index, offset), address);
+        }
+
+        // Same index and offset, different tags
+        let a = cache.compose(0x1, 0x2a, 0x8);
+        let b = cache.compose(0x7, 0x2a, 0x8);
+        assert_ne!(a, b);
+        assert_eq!(cache.get_index(a), cache.get_index(b));
+        assert_ne!(cache.get_tag(a), cache.get_tag(b));
+    }
+
+    #[test]
+    fn test_scaled_hit_latency() {
+        assert_eq!(CachePolicy::scaled_hit_latency(1024), 1);
+        assert_eq!(CachePolicy::scaled_hit_latency(4 * 1024), 1);
+        assert_eq!(CachePolicy::scaled_hit_latency(16 * 1024), 3);
+        assert_eq!(CachePolicy::scaled_hit_latency(1024 * 1024), 9);
+
+        let policy = CachePolicy::make_scaled(64 * 1024, 64, 1);
+        assert_eq!(policy.hit_latency, 5);
+        assert_eq!(policy.block_num, 1024);
+    }
+
     #[test]
     fn test_get_log_2() {
         for n in 1..1234567 {
@@ -309,6 +812,6 @@ mod tests {
     #[test]
     fn test_is_valid() {
         let policy = CachePolicy::default();
-        assert_eq!(policy.is_valid(), true);
+        assert!(policy.is_valid());
     }
 }
@@ -0,0 +1,109 @@
+//! Main memory access models,
+//! refining the fixed miss penalty
+
+/// Latency model of the main memory
+pub trait MemoryModel {
+    /// Latency of an access reaching the main memory,
+    /// updating any state the model keeps
+    fn latency(&mut self, address: u32) -> i32;
+
+    /// Average latency of the accesses so far,
+    /// or None before the first one
+    fn average_latency(&self) -> Option<f64>;
+
+    /// Forgets the accesses so far, keeping any other state
+    fn reset_stats(&mut self);
+
+    fn box_clone(&self) -> Box<dyn MemoryModel>;
+}
+
+impl Clone for Box<dyn MemoryModel> {
+    fn clone(&self) -> Self {
+        self.box_clone()
+    }
+}
+
+/// DRAM with a single row buffer.
+/// An access to the open row is cheap;
+/// any other one closes it and opens the row accessed
+#[derive(Clone, Debug)]
+pub struct RowBufferModel {
+    /// Size of a row in bytes, a power of 2
+    pub row_size: u32,
+    /// Latency of an access to the open row
+    pub open_latency: i32,
+    /// Latency of an access to any other row
+    pub closed_latency: i32,
+    /// The row last accessed, if any
+    pub open_row: Option<u32>,
+
+    pub num_row_hits: i32,
+    pub num_row_misses: i32,
+}
+
+impl RowBufferModel {
+    pub fn make(row_size: u32, open_latency: i32, closed_latency: i32) -> Self {
+        assert!(row_size.is_power_of_two());
+        Self {
+            row_size,
+            open_latency,
+            closed_latency,
+            open_row: None,
+            num_row_hits: 0,
+            num_row_misses: 0,
+        }
+    }
+}
+
+impl MemoryModel for RowBufferModel {
+    fn latency(&mut self, address: u32) -> i32 {
+        let row = address / self.row_size;
+        if self.open_row == Some(row) {
+            self.num_row_hits += 1;
+            self.open_latency
+        } else {
+            self.num_row_misses += 1;
+            self.open_row = Some(row);
+            self.closed_latency
+        }
+    }
+
+    fn average_latency(&self) -> Option<f64> {
+        let num_accesses = self.num_row_hits + self.num_row_misses;
+        (num_accesses > 0).then(|| {
+            (self.num_row_hits as f64 * self.open_latency as f64
+                + self.num_row_misses as f64 * self.closed_latency as f64)
+                / num_accesses as f64
+        })
+    }
+
+    fn reset_stats(&mut self) {
+        self.num_row_hits = 0;
+        self.num_row_misses = 0;
+    }
+
+    fn box_clone(&self) -> Box<dyn MemoryModel> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_buffer() {
+        let mut model = RowBufferModel::make(1024, 20, 100);
+        assert_eq!(model.latency(0x1000), 100);
+        assert_eq!(model.latency(0x13fc), 20);
+        assert_eq!(model.latency(0x1400), 100);
+        assert_eq!(model.latency(0x1000), 100);
+        assert_eq!((model.num_row_hits, model.num_row_misses), (1, 3));
+        assert_eq!(model.average_latency(), Some(80.0));
+
+        // The open row survives
+        model.reset_stats();
+        assert_eq!(model.average_latency(), None);
+        assert_eq!(model.latency(0x1000), 20);
+    }
+}
@@ -156,10 +156,14 @@ mod tests {
         cache.mmu().allocate_page(address);
 
         let mut stall_count = Some(0);
-        cache.set(address, 4, value, &mut stall_count, &mut None);
+        cache
+            .set(address, 4, value, &mut stall_count, &mut None)
+            .unwrap();
         // Cold miss
         assert!(stall_count == Some(100));
-        cache.set(address, 4, value, &mut stall_count, &mut None);
+        cache
+            .set(address, 4, value, &mut stall_count, &mut None)
+            .unwrap();
         // Hit at L1
         assert!(stall_count == Some(1));
     }
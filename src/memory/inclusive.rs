@@ -1,13 +1,17 @@
 //! Inclusive cache implementation
 
 use super::cache::Block;
+use super::cache::CacheHistory;
 use super::cache::CachePolicy;
+use super::dram::MemoryModel;
+use super::trace::TraceRecorder;
 use super::AccessType;
 use super::Cache;
 use super::StorageInterface;
 use super::WriteHitPolicy;
 use super::WriteMissPolicy;
 use super::MMU;
+use crate::error::{SimulatorError, SimulatorResult};
 
 /// Inclusive cache implementation.
 /// We maintain n (k >= 0) caches and 1 MMU
@@ -19,12 +23,43 @@ pub struct InclusiveCache {
     write_hit_policy: WriteHitPolicy,
     write_miss_policy: WriteMissPolicy,
 
+    /// Latency of the main memory (DRAM)
     pub miss_penalty: i32,
     pub total_penalty: i32,
     pub total_worst_penalty: i32,
 
-    pub use_victim_cache: bool,
-    pub victim_cache: Cache,
+    /// Latency of handing a miss at each level down to the next one,
+    /// on top of the latency of the next level itself.
+    /// The last one separates a last-level miss from the DRAM access
+    pub level_penalties: Vec<i32>,
+
+    /// Victim caches attached to each level, if any
+    pub victim_caches: Vec<Option<Cache>>,
+
+    /// Separate L1 cache for instruction fetches, if any,
+    /// backed by the same lower levels as the L1 data cache
+    pub icache: Option<Cache>,
+    /// Whether the current access is routed to the L1-I cache
+    fetching: bool,
+    /// Warn about stores to blocks resident in the L1-I cache,
+    /// which would need a FENCE.I on a real machine
+    pub check_stale_instructions: bool,
+    /// Number of stores that made the L1-I cache stale
+    pub num_stale_instructions: i32,
+
+    /// Number of sequential blocks prefetched into L1 on a miss,
+    /// where 0 disables prefetching
+    pub prefetch_degree: usize,
+
+    /// Whether the data caches hold the contents of their blocks,
+    /// filled from the next level and written back on eviction.
+    /// Otherwise, the data lives in the MMU and the caches only
+    /// model the timing.
+    /// System calls still read the MMU directly
+    pub hold_data: bool,
+
+    /// Records demand accesses for replay, if set
+    pub trace: Option<TraceRecorder>,
 
     pub ref_counter: i32,
 }
@@ -51,7 +86,152 @@ impl Default for InclusiveCache {
     }
 }
 
+/// Builder for an inclusive cache
+#[derive(Clone, Default)]
+pub struct InclusiveCacheBuilder {
+    policies: Vec<CachePolicy>,
+    write_hit_policy: WriteHitPolicy,
+    write_miss_policy: WriteMissPolicy,
+    miss_penalty: i32,
+    victim_levels: Vec<usize>,
+    prefetch_degree: usize,
+    memory_model: Option<Box<dyn MemoryModel>>,
+    level_penalties: Vec<(usize, i32)>,
+    hold_data: bool,
+}
+
+impl InclusiveCacheBuilder {
+    /// Add the next lower level
+    pub fn level(mut self, policy: CachePolicy) -> Self {
+        self.policies.push(policy);
+        self
+    }
+
+    /// Add several lower levels, upper first
+    pub fn levels(mut self, policies: &[CachePolicy]) -> Self {
+        self.policies.extend_from_slice(policies);
+        self
+    }
+
+    pub fn write_hit(mut self, policy: WriteHitPolicy) -> Self {
+        self.write_hit_policy = policy;
+        self
+    }
+
+    pub fn write_miss(mut self, policy: WriteMissPolicy) -> Self {
+        self.write_miss_policy = policy;
+        self
+    }
+
+    /// Penalty of accessing the main memory (DRAM)
+    pub fn miss_penalty(mut self, miss_penalty: i32) -> Self {
+        self.miss_penalty = miss_penalty;
+        self
+    }
+
+    /// Penalty of handing a miss at level k down to the next level
+    pub fn level_penalty(mut self, k: usize, penalty: i32) -> Self {
+        self.level_penalties.push((k, penalty));
+        self
+    }
+
+    /// Attach a victim cache to level k
+    pub fn victim_cache(mut self, k: usize) -> Self {
+        self.victim_levels.push(k);
+        self
+    }
+
+    /// Number of sequential blocks prefetched into L1 on a miss
+    pub fn prefetch_degree(mut self, degree: usize) -> Self {
+        self.prefetch_degree = degree;
+        self
+    }
+
+    /// Let the data caches hold the contents of their blocks
+    pub fn hold_data(mut self, hold_data: bool) -> Self {
+        self.hold_data = hold_data;
+        self
+    }
+
+    /// Latency model of the main memory,
+    /// replacing the fixed miss penalty for the accesses reaching it
+    pub fn memory_model(mut self, model: Box<dyn MemoryModel>) -> Self {
+        self.memory_model = Some(model);
+        self
+    }
+
+    pub fn build(self) -> SimulatorResult<InclusiveCache> {
+        let invalid = |message: String| Err(SimulatorError::Other(message));
+        for (k, policy) in self.policies.iter().enumerate() {
+            if let Err(e) = policy.validate() {
+                return invalid(format!("L{}: {}", k + 1, e));
+            }
+        }
+        if self.miss_penalty < 0 {
+            return invalid("Miss penalty must be non-negative".to_string());
+        }
+        if let Some(k) = self
+            .victim_levels
+            .iter()
+            .find(|&&k| k >= self.policies.len())
+        {
+            return invalid(format!("No level {} for the victim cache", k));
+        }
+        if self.hold_data && !self.victim_levels.is_empty() {
+            return invalid("Victim caches can't hold data".to_string());
+        }
+        for &(k, penalty) in self.level_penalties.iter() {
+            if k >= self.policies.len() {
+                return invalid(format!("No level {} for the penalty", k));
+            }
+            if penalty < 0 {
+                return invalid("Level penalties must be non-negative".into());
+            }
+        }
+
+        let mut cache = InclusiveCache::make(
+            self.policies,
+            self.write_hit_policy,
+            self.write_miss_policy,
+            self.miss_penalty,
+            false,
+        );
+        for k in self.victim_levels {
+            cache.attach_victim_cache(k);
+        }
+        cache.prefetch_degree = self.prefetch_degree;
+        cache.hold_data = self.hold_data;
+        cache.mmu.memory_model = self.memory_model;
+        for (k, penalty) in self.level_penalties {
+            cache.level_penalties[k] = penalty;
+        }
+        Ok(cache)
+    }
+}
+
 impl InclusiveCache {
+    /// Start building an inclusive cache.
+    /// Beyond what make takes, the builder can attach victim caches,
+    /// prefetching, per-level penalties and a latency model of the
+    /// main memory, which is the only way to select one:
+    ///
+    /// ```
+    /// use sim_lib::memory::cache::CachePolicy;
+    /// use sim_lib::memory::dram::RowBufferModel;
+    /// use sim_lib::memory::inclusive::InclusiveCache;
+    ///
+    /// let cache = InclusiveCache::builder()
+    ///     .level(CachePolicy::make(32 * 1024, 64, 4, 2))
+    ///     .level(CachePolicy::make(256 * 1024, 64, 8, 10))
+    ///     .miss_penalty(100)
+    ///     .memory_model(Box::new(RowBufferModel::make(2048, 20, 100)))
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn builder() -> InclusiveCacheBuilder {
+        InclusiveCacheBuilder::default()
+    }
+
     /// Create an inclusive cache
     /// from a vector of cache policies for each level,
     /// and write-hit and write-miss policies
@@ -64,14 +244,7 @@ impl InclusiveCache {
     ) -> Self {
         let caches: Vec<_> =
             policies.iter().map(|policy| Cache::make(*policy)).collect();
-        let victim_cache = if caches.len() > 0 {
-            let block_size = caches[0].policy.block_size;
-            Cache::make(CachePolicy::make(8 * block_size, block_size, 1, 0))
-        } else {
-            // Whatever it is, it's not going to be used
-            Cache::make(CachePolicy::default())
-        };
-        Self {
+        let mut result = Self {
             n: policies.len(),
             caches,
             mmu: MMU::make(),
@@ -80,20 +253,177 @@ impl InclusiveCache {
             miss_penalty,
             total_penalty: 0,
             total_worst_penalty: 0,
-            use_victim_cache,
-            victim_cache,
+            level_penalties: vec![0; policies.len()],
+            victim_caches: policies.iter().map(|_| None).collect(),
+            icache: None,
+            fetching: false,
+            check_stale_instructions: false,
+            num_stale_instructions: 0,
+            prefetch_degree: 0,
+            hold_data: false,
+            trace: None,
             ref_counter: 0,
+        };
+        if use_victim_cache && result.n > 0 {
+            result.attach_victim_cache(0);
         }
+        result
+    }
+
+    /// Attach an 8-block victim cache to level k
+    pub fn attach_victim_cache(&mut self, k: usize) {
+        assert!(k < self.n());
+        let block_size = self.caches[k].policy.block_size;
+        self.victim_caches[k] = Some(Cache::make(CachePolicy::make(
+            8 * block_size,
+            block_size,
+            1,
+            0,
+        )));
+    }
+
+    /// Whether level k is backed by a victim cache.
+    /// At L1, only the data cache is
+    fn has_victim_cache(&self, k: usize) -> bool {
+        self.victim_caches[k].is_some() && !(k == 0 && self.fetching)
+    }
+
+    fn victim_cache(&mut self, k: usize) -> &mut Cache {
+        self.victim_caches[k].as_mut().unwrap()
+    }
+
+    /// Return the history of the L1-I cache, if any
+    pub fn get_icache_history(&self) -> Option<CacheHistory> {
+        self.icache.as_ref().map(|icache| icache.history)
     }
 
-    /// Write a block to the victim cache
-    fn write_block_to_victim_cache(&mut self, block: &Block) {
-        let address = self.caches[0].get_address(&block);
-        let index_to_replace = self.victim_cache.get_index(address);
-        self.victim_cache.blocks[index_to_replace] = block.clone();
+    /// Reports a store to a block resident in the L1-I cache
+    fn check_icache_coherence(&mut self, address: u32) {
+        if !self.check_stale_instructions {
+            return;
+        }
+        let Some(icache) = self.icache.as_mut() else {
+            return;
+        };
+        if icache.lookup(address).is_some() {
+            self.num_stale_instructions += 1;
+            eprintln!(
+                "[WARNING] Store to {:#010x} leaves a stale instruction in the I-cache",
+                address
+            );
+        }
+    }
+
+    /// Prefetch the blocks following the given address into L1
+    fn prefetch(&mut self, address: u32) {
+        let block_size = self.caches(0).policy.block_size as u32;
+        let block_address = self.caches(0).get_block_address(address);
+        for i in 1..=self.prefetch_degree as u32 {
+            let Some(target) = block_address.checked_add(i * block_size) else {
+                break;
+            };
+            if !self.mmu.page_exists(target) || self.lookup(0, target).is_some()
+            {
+                continue;
+            }
+            // No stall counter, so it's not recorded as a demand miss
+            let index =
+                self.access_inner(0, target, AccessType::Read, &mut None);
+            if let Some(index) = index {
+                let cache = self.caches(0);
+                cache.blocks[index].prefetched = true;
+                cache.history.num_prefetch += 1;
+            }
+        }
+    }
+
+    /// Records a block evicted from level k
+    fn note_eviction(&mut self, k: usize, block: &Block) {
+        if block.valid && block.prefetched {
+            self.caches(k).history.num_useless_prefetch += 1;
+        }
+    }
+
+    /// Write a block evicted from level k to its victim cache
+    fn write_block_to_victim_cache(&mut self, k: usize, block: &Block) {
+        let address = self.caches(k).get_address(block);
+        let victim_cache = self.victim_cache(k);
+        let index_to_replace = victim_cache.get_index(address);
+        victim_cache.blocks[index_to_replace] = block.clone();
         // Must fix fields
-        // Note that the block must from L1
-        self.victim_cache.fix_block(index_to_replace, address);
+        victim_cache.fix_block(index_to_replace, address);
+    }
+
+    /// Whether level k holds the contents of its blocks.
+    /// The L1-I cache never does
+    fn holds_data(&self, k: usize) -> bool {
+        self.hold_data && !(k == 0 && self.fetching)
+    }
+
+    /// Find the topmost data cache at level k or below
+    /// holding the contents of the given address,
+    /// and return the level and the block index
+    fn find_data(&self, k: usize, address: u32) -> Option<(usize, usize)> {
+        (k..self.n).find_map(|k| {
+            let cache = &self.caches[k];
+            let i = cache.lookup(address)?;
+            cache.blocks[i].data.is_some().then_some((k, i))
+        })
+    }
+
+    /// Read a byte from its topmost copy at level k or below,
+    /// or from the MMU if no cache holds it
+    fn read_data(&mut self, k: usize, address: u32) -> u8 {
+        let Some((k, i)) = self.find_data(k, address) else {
+            return self.mmu.get8(address);
+        };
+        let cache = &self.caches[k];
+        let offset = cache.decompose(address).2 as usize;
+        cache.blocks[i].data.as_ref().unwrap()[offset]
+    }
+
+    /// Write a byte to its topmost copy at level k or below,
+    /// or to the MMU if no cache holds it.
+    /// With write-through, the lower copies and the MMU are written as well
+    fn write_data(&mut self, k: usize, address: u32, value: u8) {
+        let mut k = k;
+        while let Some((k2, i)) = self.find_data(k, address) {
+            let cache = &mut self.caches[k2];
+            let offset = cache.decompose(address).2 as usize;
+            cache.blocks[i].data.as_mut().unwrap()[offset] = value;
+            if self.write_hit_policy == WriteHitPolicy::WriteBack {
+                return;
+            }
+            k = k2 + 1;
+        }
+        self.mmu.set8(address, value);
+    }
+
+    /// Copy the contents of a block evicted from level k
+    /// to the next level, which must have just been written
+    fn write_back_data(&mut self, k: usize, block: &Block) {
+        let Some(data) = &block.data else {
+            return;
+        };
+        let address = self.caches(k).get_address(block);
+        for (i, &value) in data.iter().enumerate() {
+            self.write_data(k + 1, address + i as u32, value);
+        }
+    }
+
+    /// Hands a miss at level k down to the next level,
+    /// charging the penalty in between
+    fn access_next_level(
+        &mut self,
+        k: usize,
+        address: u32,
+        access_type: AccessType,
+        stall_count: &mut Option<i32>,
+    ) {
+        self.access_inner(k + 1, address, access_type, stall_count);
+        if let Some(stall_count) = stall_count {
+            *stall_count += self.level_penalties[k];
+        }
     }
 
     /// Fetche a block from the next level
@@ -108,52 +438,64 @@ impl InclusiveCache {
     ) -> usize {
         assert!(k < self.n());
 
-        // If we enable victim cache and we're at k = 0,
+        // If level k has a victim cache,
         // we shall first check the victim cache
-        if self.use_victim_cache && k == 0 {
-            if let Some(hit_index) = self.victim_cache.lookup(address) {
+        if self.has_victim_cache(k) {
+            if let Some(hit_index) = self.victim_cache(k).lookup(address) {
                 // A hit at the victim cache
                 if let Some(stall_count) = stall_count {
-                    *stall_count = self.victim_cache.policy.hit_latency;
+                    let victim_cache = self.victim_cache(k);
+                    *stall_count = victim_cache.policy.hit_latency;
                     // Record the hit
-                    self.victim_cache.record_hit();
+                    victim_cache.record_hit(address);
                 }
 
-                // Swap the hit block with a block in L1
-                let victim_address = self
-                    .victim_cache
-                    .get_address(&self.victim_cache.blocks[hit_index]);
-                let index = self.caches[0].get_index(victim_address);
+                // Swap the hit block with a block at level k
+                let hit_block =
+                    std::mem::take(&mut self.victim_cache(k).blocks[hit_index]);
+                let index = self.caches(k).get_index(address);
                 let index_to_replace =
-                    self.caches[k].get_index_to_replace(index);
-                let replaced_address = self.caches[k]
-                    .get_address(&self.caches[k].blocks[index_to_replace]);
-                assert_eq!(index, index_to_replace);
-                assert_eq!(index, self.caches[0].get_index(address));
-                std::mem::swap(
-                    &mut self.caches[0].blocks[index],
-                    &mut self.victim_cache.blocks[hit_index],
+                    self.caches(k).get_index_to_replace(index);
+                let replaced_block = std::mem::replace(
+                    &mut self.caches(k).blocks[index_to_replace],
+                    hit_block,
                 );
                 // Must fix fields
-                self.caches[0].fix_block(index, victim_address);
-                self.victim_cache.fix_block(hit_index, replaced_address);
+                self.caches(k).fix_block(index_to_replace, address);
+                self.victim_cache(k).fix_block(hit_index, address);
+                self.note_eviction(k, &replaced_block);
+                if replaced_block.valid {
+                    self.write_block_to_victim_cache(k, &replaced_block);
+                }
 
                 return index_to_replace;
             } else {
                 // Record the miss
-                self.victim_cache.record_miss();
+                self.victim_cache(k).record_miss(address);
             }
         }
 
         // Make a new block and replace some
         // evicted one
-        let block = self.caches[k].make_block(address);
+        let mut block = self.caches(k).make_block(address);
 
         // Access the next level
-        self.access_inner(k + 1, address, AccessType::Write, stall_count);
+        self.access_next_level(k, address, AccessType::Write, stall_count);
+
+        // Copy the contents of the block from the next level
+        if self.holds_data(k) {
+            let cache = self.caches(k);
+            let block_address = cache.get_block_address(address);
+            let block_size = cache.policy.block_size as u32;
+            block.data = Some(
+                (block_address..block_address + block_size)
+                    .map(|address| self.read_data(k + 1, address))
+                    .collect(),
+            );
+        }
 
         // Replace the block with the least recent reference
-        let index_to_replace = self.caches[k].get_index_to_replace(block.index);
+        let index_to_replace = self.caches(k).get_index_to_replace(block.index);
 
         // Make sure this replacement is needed
         // assert!(block.tag != self.caches[k].blocks[index_to_replace].tag);
@@ -161,14 +503,15 @@ impl InclusiveCache {
 
         // Replace the block
         let replaced_block = std::mem::replace(
-            &mut self.caches[k].blocks[index_to_replace],
+            &mut self.caches(k).blocks[index_to_replace],
             block,
         );
+        self.note_eviction(k, &replaced_block);
 
-        // If we enable victim cache and we're at k = 0,
+        // If level k has a victim cache,
         // we write the replaced block to the victim cache
-        if self.use_victim_cache && k == 0 && replaced_block.valid {
-            self.write_block_to_victim_cache(&replaced_block);
+        if self.has_victim_cache(k) && replaced_block.valid {
+            self.write_block_to_victim_cache(k, &replaced_block);
         }
 
         // If this block is using write-back policy,
@@ -178,38 +521,29 @@ impl InclusiveCache {
             && replaced_block.dirty
         {
             self.write_to_next_level(k, &replaced_block);
+            self.write_back_data(k, &replaced_block);
         }
 
         index_to_replace
     }
 
+    /// Checks that each block is present in the lower levels.
+    /// A block can skip the levels below a victim cache,
+    /// since a victim hit doesn't access them
     pub fn verify_inclusiveness(&mut self) {
-        if !self.use_victim_cache {
-            for k in 0..self.n() {
-                for i in 0..self.caches[k].policy.block_num {
-                    if !self.caches[k].blocks[i].valid {
-                        continue;
-                    }
-                    let address =
-                        self.caches[k].get_address(&self.caches[k].blocks[i]);
-
-                    for k2 in k + 1..self.n() {
-                        assert!(self.caches[k2].lookup(address).is_some());
-                    }
+        for k in 0..self.n() {
+            for i in 0..self.caches[k].policy.block_num {
+                if !self.caches[k].blocks[i].valid {
+                    continue;
                 }
-            }
-        } else {
-            for k in 1..self.n() {
-                for i in 0..self.caches[k].policy.block_num {
-                    if !self.caches[k].blocks[i].valid {
-                        continue;
-                    }
-                    let address =
-                        self.caches[k].get_address(&self.caches[k].blocks[i]);
+                let address =
+                    self.caches[k].get_address(&self.caches[k].blocks[i]);
 
-                    for k2 in k + 1..self.n() {
-                        assert!(self.caches[k2].lookup(address).is_some());
+                for k2 in k + 1..self.n() {
+                    if self.victim_caches[k2 - 1].is_some() {
+                        break;
                     }
+                    assert!(self.caches[k2].lookup(address).is_some());
                 }
             }
         }
@@ -221,7 +555,10 @@ impl StorageInterface for InclusiveCache {
         self.n
     }
     fn caches(&mut self, k: usize) -> &mut Cache {
-        self.caches.get_mut(k).unwrap()
+        match self.icache.as_mut() {
+            Some(icache) if k == 0 && self.fetching => icache,
+            _ => self.caches.get_mut(k).unwrap(),
+        }
     }
     fn mmu(&mut self) -> &mut MMU {
         &mut self.mmu
@@ -229,6 +566,9 @@ impl StorageInterface for InclusiveCache {
     fn ref_counter(&mut self) -> &mut i32 {
         &mut self.ref_counter
     }
+    fn trace(&mut self) -> Option<&mut TraceRecorder> {
+        self.trace.as_mut()
+    }
 
     fn total_penalty(&mut self) -> &mut i32 {
         &mut self.total_penalty
@@ -240,26 +580,106 @@ impl StorageInterface for InclusiveCache {
         self.miss_penalty
     }
 
-    /// Must take victim cache into account
-    fn get_amat(&mut self) -> f64 {
-        let mut result = self.miss_penalty() as f64;
-        for k in (0..self.n()).rev() {
-            // If we use a victim cache
-            if k == 0 && self.use_victim_cache {
-                // Need to access lower level caches
-                // only if vc misses
-                let vc = &self.victim_cache;
-                eprintln!("vc: {:?}", vc.history);
-                result =
-                    vc.policy.hit_latency as f64 + vc.get_miss_rate() * result;
+    fn load8(&mut self, address: u32) -> u8 {
+        if !self.hold_data {
+            return self.mmu.get8(address);
+        }
+        self.read_data(0, address)
+    }
+
+    fn store8(&mut self, address: u32, value: u8) {
+        if !self.hold_data {
+            self.mmu.set8(address, value);
+            return;
+        }
+        self.write_data(0, address, value);
+    }
+
+    fn access(
+        &mut self,
+        address: u32,
+        access_type: AccessType,
+        stall_count: &mut Option<i32>,
+    ) {
+        self.ref_counter += 1;
+        // Only the first byte of a store carries the stall counter
+        if access_type == AccessType::Write && stall_count.is_some() {
+            self.check_icache_coherence(address);
+        }
+        // Route instruction fetches to the L1-I cache
+        self.fetching =
+            access_type == AccessType::Execute && self.icache.is_some();
+
+        // Only demand accesses count towards prefetching,
+        // which needs an L1 to prefetch into
+        let demand = stall_count.is_some() && !self.fetching && self.n > 0;
+        let hit_index = if demand {
+            self.lookup(0, address)
+        } else {
+            None
+        };
+        if let Some(hit_index) = hit_index {
+            let block = &mut self.caches(0).blocks[hit_index];
+            if block.prefetched {
+                block.prefetched = false;
+                self.caches(0).history.num_useful_prefetch += 1;
             }
+        }
 
-            let cache = &self.caches(k);
-            eprintln!("k = {}: {:?}", k, cache.history);
-            result = cache.policy.hit_latency as f64
-                + cache.get_miss_rate() * result;
+        self.access_inner(0, address, access_type, stall_count);
+        if demand && hit_index.is_none() {
+            self.prefetch(address);
         }
-        result
+        self.fetching = false;
+    }
+
+    /// Must take victim cache, L1-I cache and stale instructions into account
+    fn reset(&mut self) {
+        for cache in self.caches.iter_mut() {
+            cache.reset();
+        }
+        for victim_cache in self.victim_caches.iter_mut().flatten() {
+            victim_cache.reset();
+        }
+        if let Some(icache) = self.icache.as_mut() {
+            icache.reset();
+        }
+        self.total_penalty = 0;
+        self.total_worst_penalty = 0;
+        self.num_stale_instructions = 0;
+        self.ref_counter = 0;
+        self.mmu.reset_access_stats();
+    }
+
+    /// Must take victim cache and L1-I cache into account
+    fn reset_history(&mut self) {
+        for cache in self.caches.iter_mut() {
+            cache.history = CacheHistory::default();
+        }
+        for victim_cache in self.victim_caches.iter_mut().flatten() {
+            victim_cache.history = CacheHistory::default();
+        }
+        if let Some(icache) = self.icache.as_mut() {
+            icache.history = CacheHistory::default();
+        }
+        self.mmu.reset_access_stats();
+    }
+
+    /// Must take victim cache into account.
+    /// Prefetched blocks demanded later are recorded as hits,
+    /// so prefetching shows up as a lower L1 miss rate
+    fn level_amat(&mut self, k: usize, lower_amat: f64) -> f64 {
+        let mut lower_amat = self.level_penalties[k] as f64 + lower_amat;
+        // If we use a victim cache
+        if let Some(vc) = &self.victim_caches[k] {
+            // Need to access lower level caches
+            // only if vc misses
+            lower_amat =
+                vc.policy.hit_latency as f64 + vc.get_miss_rate() * lower_amat;
+        }
+
+        let cache = &self.caches(k);
+        cache.policy.hit_latency as f64 + cache.get_miss_rate() * lower_amat
     }
 
     fn handle_hit(
@@ -278,7 +698,9 @@ impl StorageInterface for InclusiveCache {
             // so the address must be present in the next level.
             // In addition, we've already done the write
             // instruction and there is no need to penalize further;
-            // that is, we won't pass the stall counter to this write
+            // that is, we won't pass the stall counter to this write.
+            // It still touches the block at the next level
+            // for LRU purposes
             self.access_inner(k + 1, address, AccessType::Write, &mut None);
         }
     }
@@ -296,13 +718,469 @@ impl StorageInterface for InclusiveCache {
         // target_index = Some(
         //     self.fetch_from_next_level(k, address, stall_count)
         // );
-        if access_type == AccessType::Read
+        if access_type != AccessType::Write
             || self.write_miss_policy == WriteMissPolicy::WriteAllocate
         {
             Some(self.fetch_from_next_level(k, address, stall_count))
         } else {
-            self.access_inner(k + 1, address, AccessType::Write, stall_count);
+            self.access_next_level(k, address, AccessType::Write, stall_count);
             None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compulsory_miss() {
+        // Single-level direct-mapped 16KB cache with 64B blocks
+        let mut cache = InclusiveCache::make(
+            vec![CachePolicy::default()],
+            WriteHitPolicy::default(),
+            WriteMissPolicy::default(),
+            100,
+            false,
+        );
+        // 0x1000 and 0x5000 map to the same set
+        let trace = [0x1000, 0x1004, 0x1040, 0x1000, 0x5000, 0x1000];
+        for address in trace {
+            cache.mmu().allocate_page(address);
+        }
+        for address in trace {
+            cache.get8(address, &mut Some(0)).unwrap();
+        }
+
+        let history = cache.get_history()[0];
+        // Distinct blocks: 0x1000, 0x1040, 0x5000
+        assert_eq!(history.num_compulsory_miss, 3);
+        // The last access to 0x1000 is a conflict miss
+        assert_eq!(history.num_miss, 4);
+        assert_eq!(history.num_hit, 2);
+    }
+
+    /// Writes to conflicting blocks in a single-level cache
+    /// and returns the number of write-backs
+    fn count_writebacks(write_hit_policy: WriteHitPolicy) -> i32 {
+        let mut cache = InclusiveCache::make(
+            vec![CachePolicy::default()],
+            write_hit_policy,
+            WriteMissPolicy::default(),
+            100,
+            false,
+        );
+        // 0x1000, 0x5000 and 0x9000 map to the same set
+        for address in [0x1000, 0x5000, 0x9000] {
+            cache.mmu().allocate_page(address);
+        }
+        for address in [0x1000, 0x5000, 0x1000, 0x5000] {
+            cache.set8(address, 1, &mut Some(0)).unwrap();
+        }
+        // Evicts a dirty block, then a clean one
+        cache.get8(0x9000, &mut Some(0)).unwrap();
+        cache.get8(0x1000, &mut Some(0)).unwrap();
+
+        cache.get_history()[0].num_writeback
+    }
+
+    #[test]
+    fn test_writeback_count() {
+        assert_eq!(count_writebacks(WriteHitPolicy::WriteBack), 4);
+        assert_eq!(count_writebacks(WriteHitPolicy::WriteThrough), 0);
+    }
+
+    #[test]
+    fn test_write_combining() {
+        let mut cache = InclusiveCache::default();
+        // 0x1000 and 0x5000 map to the same set
+        cache.mmu().allocate_page(0x1000);
+        cache.mmu().allocate_page(0x5000);
+        // Fill the block word by word, twice
+        for _ in 0..2 {
+            for address in (0x1000..0x1040).step_by(4) {
+                cache.set(address, 4, 1, &mut Some(0), &mut None).unwrap();
+            }
+        }
+        // A word store counts once, not once per byte
+        assert_eq!(cache.caches[0].blocks[64].num_writes, 32);
+        // Evicts the dirty block
+        cache.get8(0x5000, &mut Some(0)).unwrap();
+
+        let history = cache.get_history()[0];
+        assert_eq!(history.num_writeback, 1);
+        assert_eq!(history.writes_per_writeback(), 32.0);
+        assert_eq!(cache.caches[0].blocks[64].num_writes, 0);
+    }
+
+    #[test]
+    fn test_hold_data() {
+        let mut cache = InclusiveCache::builder()
+            .level(CachePolicy::default())
+            .level(CachePolicy::make(128 * 1024, 64, 8, 8))
+            .miss_penalty(100)
+            .hold_data(true)
+            .build()
+            .unwrap();
+        // 0x1000 and 0x5000 map to the same set at L1
+        cache.mmu().allocate_page(0x1000);
+        cache.mmu().allocate_page(0x5000);
+        cache.mmu().set8(0x1001, 0x11);
+
+        // The store only reaches the L1 block, filled from the MMU
+        cache.set8(0x1000, 0x22, &mut Some(0)).unwrap();
+        assert_eq!(cache.mmu().get8(0x1000), 0);
+        let l1_index = cache.caches[0].lookup(0x1000).unwrap();
+        let data = cache.caches[0].blocks[l1_index].data.as_ref().unwrap();
+        assert_eq!(&data[..2], &[0x22, 0x11]);
+
+        // Evicting the dirty block moves the data to L2, not the MMU
+        cache.get8(0x5000, &mut Some(0)).unwrap();
+        assert!(cache.caches[0].lookup(0x1000).is_none());
+        assert_eq!(cache.mmu().get8(0x1000), 0);
+        let l2_index = cache.caches[1].lookup(0x1000).unwrap();
+        let data = cache.caches[1].blocks[l2_index].data.as_ref().unwrap();
+        assert_eq!(&data[..2], &[0x22, 0x11]);
+
+        // Reloading the block brings the data back up
+        let mut stall_count = Some(0);
+        assert_eq!(cache.get16(0x1000, &mut stall_count).unwrap(), 0x1122);
+        assert_eq!(stall_count, Some(8));
+        assert_eq!(cache.get_history()[0].num_writeback, 1);
+    }
+
+    #[test]
+    fn test_write_through_recency() {
+        let mut cache = InclusiveCache::builder()
+            .level(CachePolicy::make(128, 64, 1, 1))
+            .level(CachePolicy::make(128, 64, 2, 8))
+            .write_hit(WriteHitPolicy::WriteThrough)
+            .build()
+            .unwrap();
+        cache.mmu().allocate_page(0);
+        // X and Z conflict at L1; all three conflict at the 2-way L2
+        let (x, y, z) = (0x00, 0x40, 0x80);
+        cache.get8(x, &mut Some(0)).unwrap();
+        cache.get8(y, &mut Some(0)).unwrap();
+        // Hits at L1, and the write-through touches X at L2 as well
+        cache.set8(x, 1, &mut Some(0)).unwrap();
+        cache.get8(z, &mut Some(0)).unwrap();
+
+        // So Y, not X, is the L2 victim
+        assert!(cache.caches[1].lookup(x).is_some());
+        assert!(cache.caches[1].lookup(y).is_none());
+        assert!(cache.caches[1].lookup(z).is_some());
+    }
+
+    #[test]
+    fn test_split_l1() {
+        let mut cache = InclusiveCache {
+            icache: Some(Cache::make(CachePolicy::default())),
+            ..Default::default()
+        };
+        cache.mmu().allocate_page(0x1000);
+        cache.mmu().allocate_page(0x2000);
+
+        cache.fetch(0x1000, &mut Some(0), &mut Some(0)).unwrap();
+        cache.fetch(0x1000, &mut Some(0), &mut Some(0)).unwrap();
+        cache.get8(0x2000, &mut Some(0)).unwrap();
+
+        let icache_history = cache.get_icache_history().unwrap();
+        assert_eq!(icache_history.num_miss, 1);
+        assert_eq!(icache_history.num_hit, 1);
+        // Fetches don't pollute the L1-D cache
+        let history = cache.get_history();
+        assert_eq!(history[0].num_miss, 1);
+        assert_eq!(history[0].num_hit, 0);
+        assert!(cache.caches[0].lookup(0x1000).is_none());
+        // The lower levels are shared
+        assert!(cache.caches[1].lookup(0x1000).is_some());
+        assert!(cache.caches[1].lookup(0x2000).is_some());
+    }
+
+    #[test]
+    fn test_stale_instructions() {
+        use crate::cpu::{CPUPolicy, CPUState};
+        use crate::single_cycle;
+        use crate::system_call::SyscallIo;
+        use crate::test_utils::load_program;
+
+        let program = [
+            0x000012b7, // lui t0, 0x1
+            0x0002a023, // sw x0, 0(t0)
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        mem.icache = Some(Cache::make(CachePolicy::default()));
+        mem.check_stale_instructions = true;
+
+        single_cycle::run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        // The program overwrites its own first instruction
+        assert_eq!(mem.num_stale_instructions, 1);
+    }
+
+    /// Alternates reads between 2 blocks conflicting at L1 and L2,
+    /// and returns the number of L3 accesses
+    fn count_l3_accesses(victim_level: Option<usize>) -> i32 {
+        let mut cache = InclusiveCache::make(
+            vec![
+                CachePolicy::make(1024, 64, 1, 1),
+                CachePolicy::make(4 * 1024, 64, 1, 4),
+                CachePolicy::make(64 * 1024, 64, 4, 20),
+            ],
+            WriteHitPolicy::default(),
+            WriteMissPolicy::default(),
+            100,
+            false,
+        );
+        if let Some(k) = victim_level {
+            cache.attach_victim_cache(k);
+        }
+        cache.mmu().allocate_page(0x1000);
+        cache.mmu().allocate_page(0x2000);
+        for _ in 0..10 {
+            cache.get8(0x1000, &mut Some(0)).unwrap();
+            cache.get8(0x2000, &mut Some(0)).unwrap();
+        }
+        cache.verify_inclusiveness();
+
+        let history = cache.get_history()[2];
+        history.num_hit + history.num_miss
+    }
+
+    #[test]
+    fn test_l2_victim_cache() {
+        assert_eq!(count_l3_accesses(None), 20);
+        // Only the compulsory misses reach L3
+        assert_eq!(count_l3_accesses(Some(1)), 2);
+    }
+
+    #[test]
+    fn test_builder() {
+        let l1 = CachePolicy::make(16 * 1024, 64, 1, 1);
+        let l2 = CachePolicy::make(128 * 1024, 64, 8, 8);
+        let mut built = InclusiveCache::builder()
+            .level(l1)
+            .level(l2)
+            .write_hit(WriteHitPolicy::WriteThrough)
+            .miss_penalty(100)
+            .victim_cache(0)
+            .build()
+            .unwrap();
+        let mut made = InclusiveCache::make(
+            vec![l1, l2],
+            WriteHitPolicy::WriteThrough,
+            WriteMissPolicy::WriteAllocate,
+            100,
+            true,
+        );
+
+        assert_eq!(built.n, made.n);
+        assert_eq!(built.write_hit_policy, made.write_hit_policy);
+        assert_eq!(built.write_miss_policy, made.write_miss_policy);
+        assert_eq!(built.miss_penalty, made.miss_penalty);
+        assert!(built.victim_caches[0].is_some());
+        assert!(built.victim_caches[1].is_none());
+
+        // Both behave the same
+        for cache in [&mut built, &mut made] {
+            for address in [0x1000, 0x5000, 0x1000, 0x9000] {
+                cache.mmu().allocate_page(address);
+                cache.get8(address, &mut Some(0)).unwrap();
+            }
+        }
+        assert_eq!(built.get_history(), made.get_history());
+
+        // Validation
+        let invalid = CachePolicy::make(3 * 64, 64, 1, 1);
+        assert!(InclusiveCache::builder().level(invalid).build().is_err());
+        assert!(InclusiveCache::builder()
+            .level(l1)
+            .victim_cache(1)
+            .build()
+            .is_err());
+        assert!(InclusiveCache::builder()
+            .level(l1)
+            .victim_cache(0)
+            .hold_data(true)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_row_buffer_model() {
+        use crate::memory::dram::RowBufferModel;
+
+        // Every block read misses L1, stalling until it's filled.
+        // Returns the total stall count along with the AMAT
+        let total_stall = |addresses: &[u32], model: Option<RowBufferModel>| {
+            let mut builder = InclusiveCache::builder()
+                .level(CachePolicy::make(1024, 64, 1, 1))
+                .miss_penalty(100);
+            if let Some(model) = model {
+                builder = builder.memory_model(Box::new(model));
+            }
+            let mut cache = builder.build().unwrap();
+            let mut total = 0;
+            for &address in addresses {
+                cache.mmu().allocate_page(address);
+                let mut stall_count = Some(0);
+                cache.get8(address, &mut stall_count).unwrap();
+                total += stall_count.unwrap();
+            }
+            (total, cache.get_amat())
+        };
+        // 16 blocks within a 2 KiB row, and 16 in distinct rows
+        let sequential: Vec<u32> = (0..16).map(|i| 0x10000 + 64 * i).collect();
+        let random: Vec<u32> =
+            (0..16).map(|i| 0x10000 + 0x800 * ((7 * i) % 16)).collect();
+
+        // The AMAT agrees with the stalls: an L1 hit, then the memory
+        let model = || Some(RowBufferModel::make(2048, 20, 100));
+        assert_eq!(
+            total_stall(&sequential, model()),
+            (100 + 15 * 20, 1.0 + (100 + 15 * 20) as f64 / 16.0)
+        );
+        assert_eq!(total_stall(&random, model()), (16 * 100, 1.0 + 100.0));
+        // The fixed penalty can't tell them apart
+        assert_eq!(total_stall(&sequential, None), (16 * 100, 1.0 + 100.0));
+        assert_eq!(total_stall(&random, None), (16 * 100, 1.0 + 100.0));
+    }
+
+    /// Reads each of 16 blocks twice through two levels, returning the AMAT
+    fn amat_with_latencies(dram_latency: i32, l2_penalty: i32) -> f64 {
+        let mut cache = InclusiveCache::builder()
+            .level(CachePolicy::make(1024, 64, 1, 1))
+            .level(CachePolicy::make(4096, 64, 4, 8))
+            .miss_penalty(dram_latency)
+            .level_penalty(1, l2_penalty)
+            .build()
+            .unwrap();
+        cache.mmu().allocate_page(0x10000);
+        for _ in 0..2 {
+            for address in (0x10000..0x10400).step_by(64) {
+                cache.get8(address, &mut Some(0)).unwrap();
+            }
+        }
+        cache.get_amat()
+    }
+
+    #[test]
+    fn test_dram_latency() {
+        // Half of the L1 accesses miss, and all of the L2 ones:
+        // AMAT = 1 + 0.5 * (8 + 1.0 * (penalty + DRAM))
+        assert_eq!(amat_with_latencies(100, 0), 1.0 + 0.5 * (8.0 + 100.0));
+        assert_eq!(amat_with_latencies(200, 0), 1.0 + 0.5 * (8.0 + 200.0));
+        // The last-level miss penalty adds up with the DRAM latency
+        assert_eq!(amat_with_latencies(100, 20), 1.0 + 0.5 * (8.0 + 120.0));
+        assert_eq!(
+            amat_with_latencies(200, 20) - amat_with_latencies(100, 20),
+            0.5 * 100.0
+        );
+
+        // A cold access stalls for the penalty and the DRAM access
+        let mut cache = InclusiveCache::builder()
+            .level(CachePolicy::default())
+            .miss_penalty(100)
+            .level_penalty(0, 20)
+            .build()
+            .unwrap();
+        cache.mmu().allocate_page(0x1000);
+        let mut stall_count = Some(0);
+        cache.get8(0x1000, &mut stall_count).unwrap();
+        assert_eq!(stall_count, Some(120));
+
+        // Validation
+        assert!(InclusiveCache::builder()
+            .level(CachePolicy::default())
+            .level_penalty(1, 10)
+            .build()
+            .is_err());
+        assert!(InclusiveCache::builder()
+            .level(CachePolicy::default())
+            .level_penalty(0, -1)
+            .build()
+            .is_err());
+    }
+
+    /// Reads 64 sequential blocks with the given prefetch degree
+    /// and returns the L1 history
+    fn stream_with_prefetch(degree: usize) -> CacheHistory {
+        let mut cache = InclusiveCache::builder()
+            .level(CachePolicy::default())
+            .miss_penalty(100)
+            .prefetch_degree(degree)
+            .build()
+            .unwrap();
+        for address in (0x10000..0x20000).step_by(0x1000) {
+            cache.mmu().allocate_page(address);
+        }
+        for address in (0x10000..0x11000).step_by(64) {
+            cache.get8(address, &mut Some(0)).unwrap();
+        }
+        cache.get_history()[0]
+    }
+
+    #[test]
+    fn test_prefetch_degree() {
+        let histories: Vec<_> = [0, 1, 2, 4, 8]
+            .map(stream_with_prefetch)
+            .into_iter()
+            .collect();
+        for history in &histories {
+            // Every block is either missed or prefetched in time
+            assert_eq!(history.num_miss + history.num_useful_prefetch, 64);
+        }
+        let useful: Vec<_> =
+            histories.iter().map(|h| h.num_useful_prefetch).collect();
+        assert_eq!(useful, [0, 32, 42, 51, 56]);
+        // Only prefetches past the end of the stream are useless,
+        // which are still in the cache
+        assert_eq!(histories[4].num_useless_prefetch, 0);
+        assert_eq!(histories[4].num_prefetch, 64);
+    }
+
+    /// Reads every stride bytes of 16 pages twice with the given
+    /// prefetch degree, and returns (AMAT, average stall count)
+    fn strided_amat(degree: usize, stride: usize) -> (f64, f64) {
+        let mut cache = InclusiveCache::builder()
+            .level(CachePolicy::make(1024, 64, 1, 1))
+            .miss_penalty(100)
+            .prefetch_degree(degree)
+            .build()
+            .unwrap();
+        for address in (0x10000..0x30000).step_by(0x1000) {
+            cache.mmu().allocate_page(address);
+        }
+        let (mut total, mut count) = (0, 0);
+        for _ in 0..2 {
+            for address in (0x10000..0x20000).step_by(stride) {
+                let mut stall_count = Some(0);
+                cache.get8(address, &mut stall_count).unwrap();
+                total += stall_count.unwrap();
+                count += 1;
+            }
+        }
+        (cache.get_amat(), total as f64 / count as f64)
+    }
+
+    #[test]
+    fn test_prefetch_amat() {
+        for stride in [16, 64, 128] {
+            let (amat, stall) = strided_amat(0, stride);
+            let (prefetch_amat, prefetch_stall) = strided_amat(4, stride);
+            // A miss stalls for the miss penalty alone, without the
+            // hit latency (1), so the AMAT is above by the miss rate
+            assert!((0.0..=1.0).contains(&(amat - stall)), "{}", stride);
+            assert!(
+                (0.0..=1.0).contains(&(prefetch_amat - prefetch_stall)),
+                "{}",
+                stride
+            );
+            // Prefetched blocks are hits
+            assert!(prefetch_amat < amat, "{}", stride);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Memory-mapped I/O devices
+
+use std::io::{self, BufRead, Write};
+
+/// Default base address of the console device
+pub const CONSOLE_BASE: u32 = 0x10000000;
+
+/// Default base address of the timer device
+pub const TIMER_BASE: u32 = 0x02000000;
+/// Size of the timer device, holding mtime and then mtimecmp
+pub const TIMER_SIZE: u32 = 16;
+
+/// A device mapped into the address space,
+/// which bypasses the caches and the page table
+pub trait MmioDevice {
+    /// Reads the byte at the given offset from the base address
+    fn read8(&mut self, offset: u32) -> io::Result<u8>;
+    /// Writes the byte at the given offset from the base address
+    fn write8(&mut self, offset: u32, value: u8) -> io::Result<()>;
+
+    /// Tells the device the number of cycles executed so far
+    fn tick(&mut self, _cycle: u64) {}
+}
+
+/// A UART-like console with a single data register at offset 0:
+/// writing it outputs a byte, and reading it inputs a byte (0 on EOF)
+pub struct ConsoleDevice {
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+}
+
+impl ConsoleDevice {
+    /// Make a console reading from and writing to the given streams
+    pub fn make(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Self { input, output }
+    }
+
+    /// Make a console reading from stdin and writing to stdout
+    pub fn stdio() -> Self {
+        Self::make(
+            Box::new(io::BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        )
+    }
+}
+
+impl MmioDevice for ConsoleDevice {
+    fn read8(&mut self, offset: u32) -> io::Result<u8> {
+        if offset != 0 {
+            return Ok(0);
+        }
+        let mut buffer = [0u8; 1];
+        match self.input.read(&mut buffer)? {
+            1 => Ok(buffer[0]),
+            _ => Ok(0),
+        }
+    }
+
+    fn write8(&mut self, offset: u32, value: u8) -> io::Result<()> {
+        if offset != 0 {
+            return Ok(());
+        }
+        self.output.write_all(&[value])?;
+        self.output.flush()
+    }
+}
+
+/// A machine timer with two 64-bit little-endian registers:
+/// mtime at offset 0, which counts the cycles executed
+/// and ignores writes, and mtimecmp at offset 8.
+/// Timer interrupts aren't modeled, so mtimecmp is only stored
+#[derive(Default)]
+pub struct TimerDevice {
+    pub mtime: u64,
+    pub mtimecmp: u64,
+}
+
+impl MmioDevice for TimerDevice {
+    fn read8(&mut self, offset: u32) -> io::Result<u8> {
+        let (register, shift) = match offset {
+            0..=7 => (self.mtime, 8 * offset),
+            8..=15 => (self.mtimecmp, 8 * (offset - 8)),
+            _ => return Ok(0),
+        };
+        Ok((register >> shift) as u8)
+    }
+
+    fn write8(&mut self, offset: u32, value: u8) -> io::Result<()> {
+        if !(8..=15).contains(&offset) {
+            return Ok(());
+        }
+        let shift = 8 * (offset - 8);
+        self.mtimecmp =
+            (self.mtimecmp & !(0xff << shift)) | ((value as u64) << shift);
+        Ok(())
+    }
+
+    fn tick(&mut self, cycle: u64) {
+        self.mtime = cycle;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SimulatorError;
+    use crate::memory::inclusive::InclusiveCache;
+    use crate::memory::StorageInterface;
+    use std::{cell::RefCell, rc::Rc};
+
+    /// A sink shared with the test
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A sink that's been closed, such as a broken pipe
+    struct ClosedSink;
+
+    impl Write for ClosedSink {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_console() {
+        let buffer = Rc::new(RefCell::new(Vec::new()));
+        let console = ConsoleDevice::make(
+            Box::new(&b"ok"[..]),
+            Box::new(SharedBuffer(Rc::clone(&buffer))),
+        );
+
+        let mut mem = InclusiveCache::default();
+        mem.mmu.add_device(CONSOLE_BASE, 1, Box::new(console));
+
+        for &byte in b"hi\n" {
+            mem.set8(CONSOLE_BASE, byte, &mut Some(0)).unwrap();
+        }
+        assert_eq!(*buffer.borrow(), b"hi\n");
+
+        // Neither the page table nor the caches are touched
+        assert!(!mem.mmu.page_exists(CONSOLE_BASE));
+        assert_eq!(mem.total_penalty, 0);
+        // Outside the device the address is still unallocated
+        assert!(mem.set8(CONSOLE_BASE + 1, 0, &mut Some(0)).is_err());
+
+        // Input comes from the given reader, then 0 on EOF
+        for expected in [b'o', b'k', 0] {
+            let byte = mem.get8(CONSOLE_BASE, &mut Some(0)).unwrap();
+            assert_eq!(byte, expected);
+        }
+    }
+
+    #[test]
+    fn test_console_closed() {
+        let console =
+            ConsoleDevice::make(Box::new(io::empty()), Box::new(ClosedSink));
+        let mut mem = InclusiveCache::default();
+        mem.mmu.add_device(CONSOLE_BASE, 1, Box::new(console));
+
+        let result = mem.set8(CONSOLE_BASE, b'!', &mut Some(0));
+        assert!(matches!(result, Err(SimulatorError::Io(_))));
+    }
+
+    #[test]
+    fn test_timer() {
+        use crate::cpu::{CPUPolicy, CPUState};
+        use crate::single_cycle;
+        use crate::system_call::SyscallIo;
+        use crate::test_utils::load_program;
+
+        let program = [
+            0x02000337, // lui t1, 0x2000
+            0x00032503, // lw a0, 0(t1)
+            0x00a00293, // addi t0, x0, 10
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ee3, // bne t0, x0, -4
+            0x00032583, // lw a1, 0(t1)
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        mem.mmu.add_device(
+            TIMER_BASE,
+            TIMER_SIZE,
+            Box::<TimerDevice>::default(),
+        );
+        single_cycle::run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+
+        // One cycle per instruction from the first load to the second
+        let before = cpu.gpr[10].read();
+        let after = cpu.gpr[11].read();
+        assert_eq!(before, 2);
+        assert_eq!(after - before, 1 + 2 * 10 + 1);
+
+        // mtimecmp holds what's written, whereas mtime ignores writes
+        mem.set32(TIMER_BASE + 8, 0x12345678, &mut Some(0)).unwrap();
+        mem.set32(TIMER_BASE, 0, &mut Some(0)).unwrap();
+        assert_eq!(mem.get32(TIMER_BASE + 8, &mut None).unwrap(), 0x12345678);
+        assert_eq!(mem.get32(TIMER_BASE + 12, &mut None).unwrap(), 0);
+        assert_eq!(mem.get32(TIMER_BASE, &mut None).unwrap(), after);
+    }
+}
@@ -1,6 +1,11 @@
 //! Memory management unit implemented
 //! with a two-level page table
 
+use super::dram::MemoryModel;
+use super::mmio::MmioDevice;
+use crate::error::{MemoryError, SimulatorResult};
+use std::io;
+
 const WORD_WIDTH: usize = 32;
 const FIRST_LEVEL_WIDTH: usize = 10;
 const SECOND_LEVEL_WIDTH: usize = 10;
@@ -8,17 +13,64 @@ const PAGE_WIDTH: usize = 12;
 
 const FIRST_LEVEL_SIZE: usize = 1 << FIRST_LEVEL_WIDTH;
 const SECOND_LEVEL_SIZE: usize = 1 << SECOND_LEVEL_WIDTH;
-const PAGE_SIZE: usize = 1 << PAGE_WIDTH;
+/// Granularity of allocation
+pub const PAGE_SIZE: usize = 1 << PAGE_WIDTH;
+
+/// Maximum length of a string read by read_cstr, excluding the NUL
+pub const MAX_CSTR_LEN: usize = 1 << 16;
+
+/// Pattern filling freshly allocated pages in poison mode
+pub const POISON_BYTE: u8 = 0xCC;
 
 // Defines page type
 type PageType = Box<[u8; PAGE_SIZE]>;
 
+/// A write that falls inside a watched address range
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchpointHit {
+    pub address: u32,
+    pub value: u8,
+    /// PC of the instruction doing the write, if known
+    pub pc: Option<u32>,
+}
+
+/// Summary of the allocated memory
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MmuStats {
+    /// Number of allocated second-level tables
+    pub num_tables: usize,
+    /// Number of allocated pages
+    pub num_pages: usize,
+    /// Total bytes of the allocated pages
+    pub resident_bytes: usize,
+    /// Address ranges [lo, hi] covered by the pages,
+    /// with adjacent pages merged
+    pub ranges: Vec<(u32, u32)>,
+}
+
 /// Memory management unit
 pub struct MMU {
     // Address are in u32
     // data[x][y][z] stores the byte (u8) at (x << 22) | (y << 12) | z
     // Allocate stuff lazily
     data: Vec<Option<Vec<Option<PageType>>>>,
+
+    /// Fill freshly allocated pages with POISON_BYTE instead of 0
+    pub poison: bool,
+
+    /// Watched address ranges [lo, hi], both inclusive
+    watchpoints: Vec<(u32, u32)>,
+    /// Writes caught by the watchpoints
+    watchpoint_hits: Vec<WatchpointHit>,
+    /// PC of the instruction currently accessing the memory
+    current_pc: Option<u32>,
+
+    /// Memory-mapped devices as (base address, size, device)
+    devices: Vec<(u32, u32, Box<dyn MmioDevice>)>,
+
+    /// Latency model of the accesses reaching the memory.
+    /// Without one, the owner's fixed miss penalty applies
+    pub memory_model: Option<Box<dyn MemoryModel>>,
 }
 
 impl MMU {
@@ -26,9 +78,103 @@ impl MMU {
     pub fn make() -> Self {
         Self {
             data: vec![None; FIRST_LEVEL_SIZE],
+            poison: false,
+            watchpoints: Vec::new(),
+            watchpoint_hits: Vec::new(),
+            current_pc: None,
+            devices: Vec::new(),
+            memory_model: None,
         }
     }
 
+    /// Map a device to the address range [base, base + size)
+    pub fn add_device(
+        &mut self,
+        base: u32,
+        size: u32,
+        device: Box<dyn MmioDevice>,
+    ) {
+        assert!(size > 0 && base.checked_add(size - 1).is_some());
+        self.devices.push((base, size, device));
+    }
+
+    /// Returns the device mapped at the given address
+    /// along with the offset into it
+    fn device_at(
+        &mut self,
+        address: u32,
+    ) -> Option<(&mut Box<dyn MmioDevice>, u32)> {
+        self.devices
+            .iter_mut()
+            .find(|(base, size, _)| *base <= address && address - *base < *size)
+            .map(|(base, _, device)| (device, address - *base))
+    }
+
+    /// Reads a byte from a device,
+    /// or returns None if no device is mapped at the address
+    pub fn device_get8(&mut self, address: u32) -> Option<io::Result<u8>> {
+        self.device_at(address)
+            .map(|(device, offset)| device.read8(offset))
+    }
+
+    /// Writes a byte to a device,
+    /// or returns None if no device is mapped at the address
+    pub fn device_set8(
+        &mut self,
+        address: u32,
+        byte: u8,
+    ) -> Option<io::Result<()>> {
+        self.device_at(address)
+            .map(|(device, offset)| device.write8(offset, byte))
+    }
+
+    /// Tells the devices the number of cycles executed so far
+    pub fn set_current_cycle(&mut self, cycle: u64) {
+        for (_, _, device) in self.devices.iter_mut() {
+            device.tick(cycle);
+        }
+    }
+
+    /// Latency of an access reaching the memory,
+    /// or None if there is no memory model
+    pub fn access_latency(&mut self, address: u32) -> Option<i32> {
+        self.memory_model
+            .as_mut()
+            .map(|model| model.latency(address))
+    }
+
+    /// Average latency of the accesses reaching the memory so far,
+    /// or None if there is no memory model or no such access
+    pub fn average_access_latency(&self) -> Option<f64> {
+        self.memory_model
+            .as_ref()
+            .and_then(|model| model.average_latency())
+    }
+
+    /// Forgets the accesses the memory model has seen
+    pub fn reset_access_stats(&mut self) {
+        if let Some(model) = self.memory_model.as_mut() {
+            model.reset_stats();
+        }
+    }
+
+    /// Watch writes to the address range [lo, hi]
+    pub fn add_watchpoint(&mut self, lo: u32, hi: u32) {
+        assert!(lo <= hi);
+        self.watchpoints.push((lo, hi));
+    }
+
+    /// Returns the writes caught by the watchpoints so far
+    pub fn watchpoint_hits(&self) -> &[WatchpointHit] {
+        &self.watchpoint_hits
+    }
+
+    /// Record the PC of the instruction accessing the memory,
+    /// which is reported along with watchpoint hits
+    pub fn set_current_pc(&mut self, pc: u32) {
+        self.current_pc = Some(pc);
+    }
+
     /// The first-level index of the address
     pub fn get_first_level_index(address: u32) -> usize {
         (address >> (WORD_WIDTH - FIRST_LEVEL_WIDTH)) as usize
@@ -52,9 +198,9 @@ impl MMU {
 
         if let Some(second_level) = &self.data[i] {
             // If the second level exists, check if the page exists
-            return second_level[j].is_some();
+            second_level[j].is_some()
         } else {
-            return false;
+            false
         }
     }
 
@@ -71,11 +217,14 @@ impl MMU {
             self.data[i] = Some(vec![None; SECOND_LEVEL_SIZE]);
         }
 
+        // Untouched bytes of a new page
+        let fill = if self.poison { POISON_BYTE } else { 0 };
+
         // Now the second level must exist
         if let Some(second_level) = &mut self.data[i] {
             // Allocate the page if it doesn't exist
             if second_level[j].is_none() {
-                second_level[j] = Some(Box::new([0; PAGE_SIZE]));
+                second_level[j] = Some(Box::new([fill; PAGE_SIZE]));
                 true
             } else {
                 false
@@ -87,6 +236,18 @@ impl MMU {
 
     /// Set the byte starting at the given address
     pub fn set8(&mut self, address: u32, byte: u8) -> bool {
+        if self
+            .watchpoints
+            .iter()
+            .any(|&(lo, hi)| lo <= address && address <= hi)
+        {
+            self.watchpoint_hits.push(WatchpointHit {
+                address,
+                value: byte,
+                pc: self.current_pc,
+            });
+        }
+
         let (i, j, k) = (
             Self::get_first_level_index(address),
             Self::get_second_level_index(address),
@@ -99,7 +260,46 @@ impl MMU {
                 return true;
             }
         }
-        return false;
+        false
+    }
+
+    /// Write a byte slice starting at the given address,
+    /// allocating the spanned pages
+    pub fn write_bytes(&mut self, address: u32, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let last = address
+            .checked_add((data.len() - 1) as u32)
+            .expect("Memory address out of bounds");
+
+        for &(lo, hi) in &self.watchpoints {
+            for hit in lo.max(address)..=hi.min(last) {
+                self.watchpoint_hits.push(WatchpointHit {
+                    address: hit,
+                    value: data[(hit - address) as usize],
+                    pc: self.current_pc,
+                });
+            }
+        }
+
+        // Copy page by page
+        let mut address = address;
+        let mut data = data;
+        while !data.is_empty() {
+            self.allocate_page(address);
+            let (i, j, k) = (
+                Self::get_first_level_index(address),
+                Self::get_second_level_index(address),
+                Self::get_page_offset(address),
+            );
+            let len = data.len().min(PAGE_SIZE - k);
+            let page = self.data[i].as_mut().unwrap()[j].as_mut().unwrap();
+            page[k..k + len].copy_from_slice(&data[..len]);
+
+            data = &data[len..];
+            address = address.wrapping_add(len as u32);
+        }
     }
 
     /// Get the byte starting at the given address
@@ -120,25 +320,122 @@ impl MMU {
         panic!("[get_byte] Page doesn't exist")
     }
 
-    pub fn dump(&self) {
-        todo!();
+    /// Get the byte at the given address for inspection,
+    /// or None if its page doesn't exist.
+    /// Never allocates nor touches devices
+    pub fn try_get8(&self, address: u32) -> Option<u8> {
+        let (i, j, k) = (
+            Self::get_first_level_index(address),
+            Self::get_second_level_index(address),
+            Self::get_page_offset(address),
+        );
+        let page = self.data[i].as_ref()?[j].as_ref()?;
+        Some(page[k])
+    }
+
+    /// Get the little-endian word at the given address for inspection,
+    /// or None if it's misaligned or its page doesn't exist
+    pub fn try_get32(&self, address: u32) -> Option<u32> {
+        if !address.is_multiple_of(4) {
+            return None;
+        }
+        // An aligned word never straddles pages
+        let mut bytes = [0; 4];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = self.try_get8(address + i as u32)?;
+        }
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// Read a NUL-terminated string, excluding the NUL
+    pub fn read_cstr(&mut self, address: u32) -> SimulatorResult<Vec<u8>> {
+        let mut result = Vec::new();
+        let mut current = address;
+        while result.len() <= MAX_CSTR_LEN {
+            if !self.page_exists(current) {
+                return Err(MemoryError::ReadUnallocated(current).into());
+            }
+            match self.get8(current) {
+                0 => return Ok(result),
+                byte => result.push(byte),
+            }
+            current = current
+                .checked_add(1)
+                .ok_or(MemoryError::UnterminatedString(address))?;
+        }
+        Err(MemoryError::UnterminatedString(address).into())
+    }
+
+    /// Summarizes the allocated memory without copying any page
+    pub fn memory_stats(&self) -> MmuStats {
+        let mut stats = MmuStats::default();
+        for (i, second_level) in self.data.iter().enumerate() {
+            let Some(second_level) = second_level else {
+                continue;
+            };
+            stats.num_tables += 1;
+            for (j, page) in second_level.iter().enumerate() {
+                if page.is_none() {
+                    continue;
+                }
+                stats.num_pages += 1;
+                stats.resident_bytes += PAGE_SIZE;
+
+                let lo = ((i << (SECOND_LEVEL_WIDTH + PAGE_WIDTH))
+                    | (j << PAGE_WIDTH)) as u32;
+                let hi = lo + (PAGE_SIZE - 1) as u32;
+                match stats.ranges.last_mut() {
+                    // Merge with the previous page
+                    Some(range) if range.1.checked_add(1) == Some(lo) => {
+                        range.1 = hi;
+                    }
+                    _ => stats.ranges.push((lo, hi)),
+                }
+            }
+        }
+        stats
+    }
+
+    /// Whether both MMUs have the same pages with the same content
+    pub fn same_contents(&self, other: &MMU) -> bool {
+        self.data == other.data
+    }
+
+    /// Allocated pages with their base addresses, in ascending order
+    pub fn dump(&self) -> Vec<(u32, &[u8])> {
+        let mut pages = Vec::new();
+        for (i, second_level) in self.data.iter().enumerate() {
+            let Some(second_level) = second_level else {
+                continue;
+            };
+            for (j, page) in second_level.iter().enumerate() {
+                if let Some(page) = page {
+                    let base = ((i << (SECOND_LEVEL_WIDTH + PAGE_WIDTH))
+                        | (j << PAGE_WIDTH))
+                        as u32;
+                    pages.push((base, &page[..]));
+                }
+            }
+        }
+        pages
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::SimulatorError;
 
     #[test]
     fn test_page_exists() {
         let mut memory = MMU::make();
         let address = 0x12345678;
 
-        assert_eq!(memory.page_exists(address), false);
+        assert!(!memory.page_exists(address));
 
         memory.allocate_page(address);
 
-        assert_eq!(memory.page_exists(address), true);
+        assert!(memory.page_exists(address));
     }
 
     #[test]
@@ -146,8 +443,8 @@ mod tests {
         let mut memory = MMU::make();
         let address = 0x12345678;
 
-        assert_eq!(memory.allocate_page(address), true);
-        assert_eq!(memory.allocate_page(address), false);
+        assert!(memory.allocate_page(address));
+        assert!(!memory.allocate_page(address));
     }
 
     #[test]
@@ -174,13 +471,136 @@ mod tests {
         assert_eq!(memory.get8(address), byte);
     }
 
+    #[test]
+    fn test_try_get() {
+        let mut memory = MMU::make();
+        let address = 0x12345678;
+        memory.write_bytes(address, &[0x78, 0x56, 0x34, 0x12]);
+
+        assert_eq!(memory.try_get32(address), Some(0x12345678));
+        assert_eq!(memory.try_get8(address + 1), Some(0x56));
+        // Misaligned
+        assert_eq!(memory.try_get32(address + 1), None);
+        // A hole
+        assert_eq!(memory.try_get32(0x5000), None);
+        assert_eq!(memory.try_get8(0x5000), None);
+        assert!(!memory.page_exists(0x5000));
+    }
+
+    #[test]
+    fn test_write_bytes() {
+        let data: Vec<u8> =
+            (0..10 * 1024).map(|i| (i * 7 % 251) as u8).collect();
+        let address = 0x12345ff0;
+
+        let mut bulk = MMU::make();
+        bulk.write_bytes(address, &data);
+
+        let mut per_byte = MMU::make();
+        for (i, &byte) in data.iter().enumerate() {
+            per_byte.allocate_page(address + i as u32);
+            per_byte.set8(address + i as u32, byte);
+        }
+
+        assert_eq!(bulk.memory_stats(), per_byte.memory_stats());
+        for i in 0..data.len() as u32 + 32 {
+            let address = address - 16 + i;
+            if per_byte.page_exists(address) {
+                assert_eq!(bulk.get8(address), per_byte.get8(address));
+            } else {
+                assert!(!bulk.page_exists(address));
+            }
+        }
+    }
+
+    #[test]
+    fn test_read_cstr() {
+        let mut memory = MMU::make();
+        memory.write_bytes(0x1000, b"hello\0\0");
+        assert_eq!(memory.read_cstr(0x1000).unwrap(), b"hello");
+        // Empty string
+        assert_eq!(memory.read_cstr(0x1005).unwrap(), b"");
+
+        // Missing terminator up to the end of the allocated memory
+        memory.write_bytes(0x2ffe, b"ab");
+        let result = memory.read_cstr(0x2ffe);
+        assert!(matches!(
+            result,
+            Err(SimulatorError::Memory(MemoryError::ReadUnallocated(0x3000)))
+        ));
+
+        // Runaway string within the allocated memory
+        memory.write_bytes(0x10000, &[b'x'; MAX_CSTR_LEN + 1]);
+        let result = memory.read_cstr(0x10000);
+        assert!(matches!(
+            result,
+            Err(SimulatorError::Memory(MemoryError::UnterminatedString(
+                0x10000
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let mut memory = MMU::make();
+        assert_eq!(memory.memory_stats(), MmuStats::default());
+
+        // Two adjacent pages, and two pages far apart
+        memory.allocate_page(0x1000);
+        memory.allocate_page(0x2fff);
+        memory.allocate_page(0x12345678);
+        memory.allocate_page(0xfffff000);
+
+        let stats = memory.memory_stats();
+        assert_eq!(stats.num_tables, 3);
+        assert_eq!(stats.num_pages, 4);
+        assert_eq!(stats.resident_bytes, 4 * PAGE_SIZE);
+        assert_eq!(
+            stats.ranges,
+            vec![
+                (0x1000, 0x2fff),
+                (0x12345000, 0x12345fff),
+                (0xfffff000, 0xffffffff),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut memory = MMU::make();
+        memory.allocate_page(0x1000);
+        memory.add_watchpoint(0x1010, 0x101f);
+
+        memory.set_current_pc(0x400);
+        memory.set8(0x100f, 1);
+        memory.set8(0x1010, 2);
+        memory.set8(0x101f, 3);
+        memory.set8(0x1020, 4);
+
+        assert_eq!(
+            memory.watchpoint_hits(),
+            &[
+                WatchpointHit {
+                    address: 0x1010,
+                    value: 2,
+                    pc: Some(0x400),
+                },
+                WatchpointHit {
+                    address: 0x101f,
+                    value: 3,
+                    pc: Some(0x400),
+                },
+            ]
+        );
+    }
+
     #[test]
     fn test_by_hand() {
         let mut memory = MMU::make();
 
-        assert_eq!(memory.page_exists(0x1000), false);
-        assert_eq!(memory.allocate_page(0x1000), true);
-        assert_eq!(memory.page_exists(0x2000), false);
+        assert!(!memory.page_exists(0x1000));
+        assert!(memory.allocate_page(0x1000));
+        assert!(!memory.page_exists(0x2000));
 
         {
             // set_byte and get_byte
@@ -190,19 +610,19 @@ mod tests {
 
             // Insert all bytes into memory,
             // starting with 0x1000
-            for i in 0..s.len() {
+            for (i, &byte) in s.iter().enumerate() {
                 // Get the current address
-                let current_address = (0x1000 as u32) + (i as u32);
-                let res = memory.set8(current_address, s[i]);
-                assert_eq!(res, true)
+                let current_address = 0x1000_u32 + (i as u32);
+                let res = memory.set8(current_address, byte);
+                assert!(res)
             }
 
             // Ensure content
-            for i in 0..s.len() {
+            for (i, &byte) in s.iter().enumerate() {
                 // Get the current address
-                let current_address = (0x1000 as u32) + (i as u32);
+                let current_address = 0x1000_u32 + (i as u32);
                 let res = memory.get8(current_address);
-                assert_eq!(res, s[i]);
+                assert_eq!(res, byte);
             }
         }
     }
@@ -1,26 +1,117 @@
 //! Memory structure
 
 pub mod cache;
+pub mod dram;
 pub mod exclusive;
 pub mod inclusive;
+pub mod mmio;
 pub mod mmu;
+pub mod multicore;
+pub mod nine;
+pub mod passthrough;
+pub mod trace;
 
 use cache::Cache;
 use cache::CacheHistory;
 use mmu::MMU;
+use trace::TraceRecorder;
 
 use cache::Block;
 
+use crate::error::{MemoryError, SimulatorResult};
+use crate::instruction::compressed;
+
 /// Memory interface implementation
 pub trait StorageInterface {
-    fn get8(&mut self, address: u32, stall_count: &mut Option<i32>) -> u8 {
-        self.penalize_worst();
-        self.access(address, AccessType::Read, stall_count);
-        self.mmu().get8(address)
+    /// Note that a multi-byte access passes the stall counter
+    /// only to its first byte, so it's penalized exactly once
+    fn get8(
+        &mut self,
+        address: u32,
+        stall_count: &mut Option<i32>,
+    ) -> SimulatorResult<u8> {
+        self.read8(address, AccessType::Read, stall_count)
     }
-    fn set8(&mut self, address: u32, value: u8, stall_count: &mut Option<i32>) {
-        self.penalize_worst();
+
+    /// Read a byte with the given access type,
+    /// which is either a read or an execute
+    fn read8(
+        &mut self,
+        address: u32,
+        access_type: AccessType,
+        stall_count: &mut Option<i32>,
+    ) -> SimulatorResult<u8> {
+        // Devices bypass the caches
+        if let Some(value) = self.mmu().device_get8(address) {
+            return Ok(value?);
+        }
+        if !self.mmu().page_exists(address) {
+            return Err(MemoryError::ReadUnallocated(address).into());
+        }
+        if stall_count.is_some() {
+            self.penalize_worst();
+        }
+        self.access(address, access_type, stall_count);
+        Ok(self.load8(address))
+    }
+
+    /// Fetch an instruction, tagged as an execute access.
+    /// A compressed instruction is only a halfword, zero-extended
+    fn fetch(
+        &mut self,
+        address: u32,
+        stall_count: &mut Option<i32>,
+        stall_count_worst: &mut Option<i32>,
+    ) -> SimulatorResult<u32> {
+        let total_worst_penalty = *self.total_worst_penalty();
+        // The lowest byte tells the length
+        let length = self
+            .mmu()
+            .try_get8(address)
+            .map_or(4, |byte| compressed::length(byte as u32));
+        if stall_count.is_some() {
+            self.record(AccessType::Execute, address, length);
+        }
+        let mut raw_inst =
+            self.read8(address, AccessType::Execute, stall_count)? as u32;
+        for i in 1..length {
+            let byte =
+                self.read8(address + i, AccessType::Execute, &mut None)?;
+            raw_inst |= (byte as u32) << (8 * i);
+        }
+        self.report_worst(total_worst_penalty, stall_count_worst);
+        Ok(raw_inst)
+    }
+    fn set8(
+        &mut self,
+        address: u32,
+        value: u8,
+        stall_count: &mut Option<i32>,
+    ) -> SimulatorResult<()> {
+        // Devices bypass the caches
+        if let Some(result) = self.mmu().device_set8(address, value) {
+            return Ok(result?);
+        }
+        if !self.mmu().page_exists(address) {
+            return Err(MemoryError::WriteUnallocated(address).into());
+        }
+        if stall_count.is_some() {
+            self.penalize_worst();
+        }
         self.access(address, AccessType::Write, stall_count);
+        self.store8(address, value);
+        Ok(())
+    }
+
+    /// Read the data of a byte just accessed,
+    /// which lives in the MMU unless the caches hold data
+    fn load8(&mut self, address: u32) -> u8 {
+        self.mmu().get8(address)
+    }
+
+    /// Write the data of a byte just accessed,
+    /// which lives in the MMU unless the caches hold data
+    fn store8(&mut self, address: u32, value: u8) {
         self.mmu().set8(address, value);
     }
 
@@ -44,21 +135,24 @@ pub trait StorageInterface {
         stall_count: &mut Option<i32>,
     ) -> Option<usize> {
         if k == self.n() {
-            // Access MMU, which is the worst case
+            // Access MMU, which is the worst case.
+            // The memory model sees every access, tracked or not
+            let latency = self.mmu().access_latency(address);
             if let Some(stall_count) = stall_count {
-                *stall_count = self.miss_penalty();
+                *stall_count = latency.unwrap_or(self.miss_penalty());
             }
             None
         } else {
             // Attempt to access the k-th level cache
             let target_index: Option<usize>;
-            if let Some(hit_index) = self.lookup(k, address) {
+            let hit_index = self.lookup(k, address);
+            if let Some(hit_index) = hit_index {
                 // A hit at this level
                 target_index = Some(hit_index);
                 if let Some(stall_count) = stall_count {
                     *stall_count = self.penalty(k);
                     // Record the hit
-                    self.caches(k).record_hit();
+                    self.caches(k).record_hit(address);
                 }
 
                 self.handle_hit(k, address, access_type, stall_count);
@@ -67,21 +161,25 @@ pub trait StorageInterface {
                 if let Some(stall_count) = stall_count {
                     *stall_count = self.penalty(k);
                     // Record the miss
-                    self.caches(k).record_miss();
+                    self.caches(k).record_miss(address);
                 }
 
                 target_index =
                     self.handle_miss(k, address, access_type, stall_count);
             }
 
-            // Access the cache
+            // Access the cache, updating its recency.
+            // Accesses propagated to lower levels (fills, write-throughs
+            // and write-backs) reuse the counter of the access causing them,
+            // so they touch lower-level blocks just like direct accesses
             if let Some(target_index) = target_index {
                 let ref_counter = *self.ref_counter();
-                self.caches(k).access_index(
-                    target_index,
-                    access_type,
-                    ref_counter,
-                );
+                let cache = self.caches(k);
+                if hit_index.is_some() {
+                    cache.access_index(target_index, access_type, ref_counter);
+                } else {
+                    cache.install_index(target_index, access_type, ref_counter);
+                }
             }
 
             target_index
@@ -122,9 +220,13 @@ pub trait StorageInterface {
         let address: u32;
         {
             // Borrow the cache at this level
-            let cache = &self.caches(k);
+            let cache = self.caches(k);
             block_size = cache.policy.block_size;
             address = cache.get_address(block);
+            if block.dirty {
+                cache.history.num_writeback += 1;
+                cache.history.num_combined_write += block.num_writes;
+            }
         }
         for i in 0..block_size {
             self.access_inner(
@@ -134,15 +236,26 @@ pub trait StorageInterface {
                 &mut None,
             );
         }
+        if k + 1 < self.n() {
+            self.caches(k + 1).record_write(address);
+        }
     }
 
-    fn get16(&mut self, address: u32, stall_count: &mut Option<i32>) -> u16 {
-        self.get8(address, stall_count) as u16
-            | ((self.get8(address + 1, &mut None) as u16) << 8)
+    fn get16(
+        &mut self,
+        address: u32,
+        stall_count: &mut Option<i32>,
+    ) -> SimulatorResult<u16> {
+        Ok(self.get8(address, stall_count)? as u16
+            | ((self.get8(address + 1, &mut None)? as u16) << 8))
     }
-    fn get32(&mut self, address: u32, stall_count: &mut Option<i32>) -> u32 {
-        self.get16(address, stall_count) as u32
-            | ((self.get16(address + 2, &mut None) as u32) << 16)
+    fn get32(
+        &mut self,
+        address: u32,
+        stall_count: &mut Option<i32>,
+    ) -> SimulatorResult<u32> {
+        Ok(self.get16(address, stall_count)? as u32
+            | ((self.get16(address + 2, &mut None)? as u32) << 16))
     }
 
     fn set16(
@@ -150,8 +263,8 @@ pub trait StorageInterface {
         address: u32,
         value: u16,
         stall_count: &mut Option<i32>,
-    ) {
-        self.set8(address, value as u8, stall_count);
+    ) -> SimulatorResult<()> {
+        self.set8(address, value as u8, stall_count)?;
         self.set8(address + 1, (value >> 8) as u8, &mut None)
     }
     fn set32(
@@ -159,8 +272,8 @@ pub trait StorageInterface {
         address: u32,
         value: u32,
         stall_count: &mut Option<i32>,
-    ) {
-        self.set16(address, value as u16, stall_count);
+    ) -> SimulatorResult<()> {
+        self.set16(address, value as u16, stall_count)?;
         self.set16(address + 2, (value >> 16) as u16, &mut None)
     }
 
@@ -170,16 +283,19 @@ pub trait StorageInterface {
         step: u32,
         stall_count: &mut Option<i32>,
         stall_count_worst: &mut Option<i32>,
-    ) -> u32 {
-        if let Some(stall_count_worst) = stall_count_worst {
-            *stall_count_worst = self.miss_penalty() as i32;
+    ) -> SimulatorResult<u32> {
+        let total_worst_penalty = *self.total_worst_penalty();
+        if stall_count.is_some() {
+            self.record(AccessType::Read, address, step);
         }
-        match step {
-            1 => self.get8(address, stall_count) as u32,
-            2 => self.get16(address, stall_count) as u32,
-            4 => self.get32(address, stall_count),
+        let value = match step {
+            1 => self.get8(address, stall_count)? as u32,
+            2 => self.get16(address, stall_count)? as u32,
+            4 => self.get32(address, stall_count)?,
             _ => panic!("Invalid step size"),
-        }
+        };
+        self.report_worst(total_worst_penalty, stall_count_worst);
+        Ok(value)
     }
 
     fn set(
@@ -189,16 +305,53 @@ pub trait StorageInterface {
         value: u32,
         stall_count: &mut Option<i32>,
         stall_count_worst: &mut Option<i32>,
-    ) {
-        if let Some(stall_count_worst) = stall_count_worst {
-            *stall_count_worst += self.miss_penalty() as i32;
+    ) -> SimulatorResult<()> {
+        let total_worst_penalty = *self.total_worst_penalty();
+        if stall_count.is_some() {
+            self.record(AccessType::Write, address, step);
         }
         match step {
-            1 => self.set8(address, value as u8, stall_count),
-            2 => self.set16(address, value as u16, stall_count),
-            4 => self.set32(address, value, stall_count),
+            1 => self.set8(address, value as u8, stall_count)?,
+            2 => self.set16(address, value as u16, stall_count)?,
+            4 => self.set32(address, value, stall_count)?,
             _ => panic!("Invalid step size"),
         }
+        if self.n() > 0 {
+            self.caches(0).record_write(address);
+        }
+        self.report_worst(total_worst_penalty, stall_count_worst);
+        Ok(())
+    }
+
+    /// Reports the worst-case penalty charged since the total was
+    /// the given one, so that an access reports exactly what it charged:
+    /// one miss penalty however many bytes it spans,
+    /// and none for a device
+    fn report_worst(
+        &mut self,
+        total_worst_penalty: i32,
+        stall_count_worst: &mut Option<i32>,
+    ) {
+        if let Some(stall_count_worst) = stall_count_worst {
+            *stall_count_worst =
+                *self.total_worst_penalty() - total_worst_penalty;
+        }
+    }
+
+    /// The trace recorder, if recording is enabled
+    fn trace(&mut self) -> Option<&mut TraceRecorder> {
+        None
+    }
+
+    /// Records a demand access to the trace, if any.
+    /// Device accesses bypass the caches, so they're left out
+    fn record(&mut self, access_type: AccessType, address: u32, size: u32) {
+        if !self.mmu().page_exists(address) {
+            return;
+        }
+        if let Some(trace) = self.trace() {
+            trace.record(access_type, address, size);
+        }
     }
 
     fn caches(&mut self, k: usize) -> &mut Cache;
@@ -228,6 +381,27 @@ pub trait StorageInterface {
         *self.total_worst_penalty() += self.miss_penalty();
     }
 
+    /// Invalidates all caches and clears the histories.
+    /// Note that the content of the MMU is kept
+    fn reset(&mut self) {
+        for k in 0..self.n() {
+            self.caches(k).reset();
+        }
+        *self.total_penalty() = 0;
+        *self.total_worst_penalty() = 0;
+        *self.ref_counter() = 0;
+        self.mmu().reset_access_stats();
+    }
+
+    /// Clears the cache histories, keeping the cached blocks,
+    /// so that later accesses are measured on a warm cache
+    fn reset_history(&mut self) {
+        for k in 0..self.n() {
+            self.caches(k).history = CacheHistory::default();
+        }
+        self.mmu().reset_access_stats();
+    }
+
     /// Return the list of cache histories
     fn get_history(&mut self) -> Vec<CacheHistory> {
         let mut histories = Vec::new();
@@ -237,49 +411,140 @@ pub trait StorageInterface {
         histories
     }
 
+    /// Computes the AMAT level by level, from the main memory up.
+    /// The main memory takes the average latency of its memory model
+    /// if it has one, and the fixed miss penalty otherwise
     fn get_amat(&mut self) -> f64 {
-        let mut result = self.miss_penalty() as f64;
+        let mut result = self
+            .mmu()
+            .average_access_latency()
+            .unwrap_or(self.miss_penalty() as f64);
         for k in (0..self.n()).rev() {
-            let cache = &self.caches(k);
-            eprintln!("k = {}: {:?}", k, cache.history);
-            result = cache.policy.hit_latency as f64
-                + cache.get_miss_rate() * result;
+            result = self.level_amat(k, result);
         }
-        eprintln!();
         result
     }
+
+    /// AMAT of the accesses reaching level k,
+    /// given the AMAT of those missing it.
+    /// Implementations override this to account for
+    /// the mechanisms attached to the level
+    fn level_amat(&mut self, k: usize, lower_amat: f64) -> f64 {
+        let cache = &self.caches(k);
+        cache.policy.hit_latency as f64 + cache.get_miss_rate() * lower_amat
+    }
 }
 
 /// Reference: <https://inst.eecs.berkeley.edu/~cs61c/su20/pdfs/lectures/lec15.pdf>
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
 pub enum WriteHitPolicy {
+    #[default]
     WriteBack,
     WriteThrough,
 }
 
-impl Default for WriteHitPolicy {
-    fn default() -> Self {
-        WriteHitPolicy::WriteBack
-        // WriteHitPolicy::WriteThrough
-    }
-}
-
 /// Reference: <https://inst.eecs.berkeley.edu/~cs61c/su20/pdfs/lectures/lec15.pdf>
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
 pub enum WriteMissPolicy {
+    #[default]
     WriteAllocate,
     WriteNoAllocate,
 }
 
-impl Default for WriteMissPolicy {
-    fn default() -> Self {
-        WriteMissPolicy::WriteAllocate
-        // WriteMissPolicy::WriteNoAllocate
-    }
-}
-
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum AccessType {
     Read,
     Write,
+    /// Instruction fetch, which is otherwise a read
+    Execute,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::inclusive::InclusiveCache;
+    use super::*;
+
+    /// Reads from a cold line with the given width
+    /// and returns (stall count, worst stall count, total worst penalty)
+    fn cold_read(step: u32) -> (i32, i32, i32) {
+        let address = 0x1000;
+        let mut cache = InclusiveCache::default();
+        cache.mmu().allocate_page(address);
+
+        let mut stall_count = Some(0);
+        let mut stall_count_worst = Some(0);
+        cache
+            .get(address, step, &mut stall_count, &mut stall_count_worst)
+            .unwrap();
+        (
+            stall_count.unwrap(),
+            stall_count_worst.unwrap(),
+            cache.total_worst_penalty,
+        )
+    }
+
+    /// Writes to a cold line with the given width
+    /// and returns (stall count, worst stall count, total worst penalty)
+    fn cold_write(step: u32) -> (i32, i32, i32) {
+        let address = 0x1000;
+        let mut cache = InclusiveCache::default();
+        cache.mmu().allocate_page(address);
+
+        let mut stall_count = Some(0);
+        let mut stall_count_worst = Some(0);
+        cache
+            .set(address, step, 0, &mut stall_count, &mut stall_count_worst)
+            .unwrap();
+        (
+            stall_count.unwrap(),
+            stall_count_worst.unwrap(),
+            cache.total_worst_penalty,
+        )
+    }
+
+    #[test]
+    fn test_worst_penalty_per_access() {
+        let miss_penalty = InclusiveCache::default().miss_penalty;
+        let expected = (miss_penalty, miss_penalty, miss_penalty);
+        for step in [1, 2, 4] {
+            assert_eq!(cold_read(step), expected);
+            assert_eq!(cold_write(step), expected);
+        }
+
+        // A word fetch, then a compressed one
+        let mut cache = InclusiveCache::default();
+        cache.mmu().allocate_page(0x1000);
+        cache.mmu().set8(0x1000, 0x13);
+        cache.mmu().set8(0x1004, 0x01);
+        for (i, address) in [0x1000, 0x1004].into_iter().enumerate() {
+            let mut stall_count_worst = Some(0);
+            cache
+                .fetch(address, &mut Some(0), &mut stall_count_worst)
+                .unwrap();
+            assert_eq!(stall_count_worst, Some(miss_penalty));
+            assert_eq!(
+                cache.total_worst_penalty,
+                (i as i32 + 1) * miss_penalty
+            );
+        }
+    }
+
+    #[test]
+    fn test_device_worst_penalty() {
+        use super::mmio::ConsoleDevice;
+
+        let mut cache = InclusiveCache::default();
+        let device = ConsoleDevice::make(
+            Box::new(std::io::empty()),
+            Box::new(Vec::new()),
+        );
+        cache.mmu().add_device(0x1000, 4, Box::new(device));
+        // Devices bypass the caches, so nothing is charged
+        let mut stall_count_worst = Some(0);
+        cache
+            .set(0x1000, 4, 0x21, &mut Some(0), &mut stall_count_worst)
+            .unwrap();
+        assert_eq!(stall_count_worst, Some(0));
+        assert_eq!(cache.total_worst_penalty, 0);
+    }
 }
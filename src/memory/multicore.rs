@@ -0,0 +1,274 @@
+//! Two-core cache implementation with MSI coherence
+
+use super::cache::Cache;
+use super::cache::CachePolicy;
+use super::mmu::MMU;
+use super::AccessType;
+use super::StorageInterface;
+
+/// Number of cores sharing the lower hierarchy
+pub const NUM_CORES: usize = 2;
+
+/// MSI state of a block in a private L1.
+/// With write-back L1s, it's encoded in the block itself:
+/// Modified is valid and dirty, Shared is valid and clean
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MsiState {
+    Modified,
+    Shared,
+    Invalid,
+}
+
+/// Coherence traffic between the private L1s
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct CoherenceHistory {
+    /// Copies invalidated on behalf of a write by the other core
+    pub num_invalidation: i32,
+    /// Modified copies downgraded to Shared
+    /// on behalf of a read by the other core
+    pub num_downgrade: i32,
+}
+
+/// Two cores, each with a private L1, sharing an L2 and the MMU.
+/// The L1s are kept coherent with MSI by snooping each other,
+/// while the L2 is non-inclusive, filling on misses
+/// but never invalidating the L1s.
+/// Each core accesses the memory through its own port
+pub struct MultiCoreCache {
+    pub l1: [Cache; NUM_CORES],
+    pub l2: Cache,
+    pub mmu: MMU,
+
+    pub miss_penalty: i32,
+    pub total_penalty: i32,
+    pub total_worst_penalty: i32,
+
+    pub ref_counter: i32,
+
+    pub coherence: CoherenceHistory,
+}
+
+impl Default for MultiCoreCache {
+    /// Make the first 2 levels of the default hierarchies,
+    /// with the L1 duplicated per core
+    fn default() -> Self {
+        Self::make(
+            CachePolicy::make(16 * 1024, 64, 1, 1),
+            CachePolicy::make(128 * 1024, 64, 8, 8),
+            100,
+        )
+    }
+}
+
+impl MultiCoreCache {
+    pub fn make(l1: CachePolicy, l2: CachePolicy, miss_penalty: i32) -> Self {
+        Self {
+            l1: [Cache::make(l1), Cache::make(l1)],
+            l2: Cache::make(l2),
+            mmu: MMU::make(),
+            miss_penalty,
+            total_penalty: 0,
+            total_worst_penalty: 0,
+            ref_counter: 0,
+            coherence: CoherenceHistory::default(),
+        }
+    }
+
+    /// The memory as seen by the given core
+    pub fn core(&mut self, core: usize) -> CorePort<'_> {
+        assert!(core < NUM_CORES);
+        CorePort { cache: self, core }
+    }
+
+    /// MSI state of the block with the given address
+    /// in the L1 of the given core
+    pub fn state(&self, core: usize, address: u32) -> MsiState {
+        let l1 = &self.l1[core];
+        match l1.lookup(address).map(|i| l1.blocks[i].dirty) {
+            Some(true) => MsiState::Modified,
+            Some(false) => MsiState::Shared,
+            None => MsiState::Invalid,
+        }
+    }
+}
+
+/// Accesses from one core of a MultiCoreCache,
+/// where level 0 is the private L1 and level 1 the shared L2
+pub struct CorePort<'a> {
+    cache: &'a mut MultiCoreCache,
+    core: usize,
+}
+
+impl CorePort<'_> {
+    /// Snoops the L1 of the other core for the given address.
+    /// A Modified copy is written back to L2 first;
+    /// then a write invalidates the copy,
+    /// whereas a read leaves it Shared
+    fn snoop(&mut self, address: u32, access_type: AccessType) {
+        let other = &mut self.cache.l1[1 - self.core];
+        let Some(i) = other.lookup(address) else {
+            return;
+        };
+        let block = other.blocks[i].clone();
+        let block_address = other.get_address(&block);
+        let block_size = other.policy.block_size;
+
+        if block.dirty {
+            other.history.num_writeback += 1;
+            other.history.num_combined_write += block.num_writes;
+            for offset in 0..block_size {
+                self.access_inner(
+                    1,
+                    block_address + offset as u32,
+                    AccessType::Write,
+                    &mut None,
+                );
+            }
+            self.cache.l2.record_write(block_address);
+        }
+
+        let other = &mut self.cache.l1[1 - self.core];
+        if access_type == AccessType::Write {
+            other.reset_block(i);
+            self.cache.coherence.num_invalidation += 1;
+        } else if block.dirty {
+            other.blocks[i].dirty = false;
+            other.blocks[i].num_writes = 0;
+            self.cache.coherence.num_downgrade += 1;
+        }
+    }
+}
+
+impl StorageInterface for CorePort<'_> {
+    fn n(&self) -> usize {
+        2
+    }
+    fn caches(&mut self, k: usize) -> &mut Cache {
+        match k {
+            0 => &mut self.cache.l1[self.core],
+            1 => &mut self.cache.l2,
+            _ => panic!("Invalid cache level: {}", k),
+        }
+    }
+    fn mmu(&mut self) -> &mut MMU {
+        &mut self.cache.mmu
+    }
+    fn ref_counter(&mut self) -> &mut i32 {
+        &mut self.cache.ref_counter
+    }
+
+    fn total_penalty(&mut self) -> &mut i32 {
+        &mut self.cache.total_penalty
+    }
+    fn total_worst_penalty(&mut self) -> &mut i32 {
+        &mut self.cache.total_worst_penalty
+    }
+    fn miss_penalty(&self) -> i32 {
+        self.cache.miss_penalty
+    }
+
+    /// A write hit on a Shared block upgrades it to Modified,
+    /// invalidating the other copy
+    fn handle_hit(
+        &mut self,
+        k: usize,
+        address: u32,
+        access_type: AccessType,
+        _: &mut Option<i32>,
+    ) {
+        if k == 0 && access_type == AccessType::Write {
+            self.snoop(address, access_type);
+        }
+    }
+
+    /// Snoops the other L1 on an L1 miss,
+    /// then fills the block from the next level
+    fn handle_miss(
+        &mut self,
+        k: usize,
+        address: u32,
+        access_type: AccessType,
+        stall_count: &mut Option<i32>,
+    ) -> Option<usize> {
+        assert!(k < self.n());
+
+        if k == 0 {
+            self.snoop(address, access_type);
+        }
+
+        // Make a new block and replace some
+        // evicted one
+        let block = self.caches(k).make_block(address);
+
+        // Read the block from the next level
+        self.access_inner(k + 1, address, AccessType::Read, stall_count);
+
+        // Replace the block with the least recent reference
+        let index_to_replace = self.caches(k).get_index_to_replace(block.index);
+        assert!(self.lookup(k, address).is_none());
+        let replaced_block = std::mem::replace(
+            &mut self.caches(k).blocks[index_to_replace],
+            block,
+        );
+
+        if replaced_block.valid && replaced_block.dirty {
+            self.write_to_next_level(k, &replaced_block);
+        }
+
+        Some(index_to_replace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_msi_transitions() {
+        let mut cache = MultiCoreCache::default();
+        cache.mmu.allocate_page(0x1000);
+
+        // Core 0 writes the line
+        cache.core(0).set32(0x1000, 42, &mut Some(0)).unwrap();
+        assert_eq!(cache.state(0, 0x1000), MsiState::Modified);
+        assert_eq!(cache.state(1, 0x1000), MsiState::Invalid);
+
+        // Core 1 reads it, so core 0 writes it back and shares it
+        let value = cache.core(1).get32(0x1000, &mut Some(0)).unwrap();
+        assert_eq!(value, 42);
+        assert_eq!(cache.state(0, 0x1000), MsiState::Shared);
+        assert_eq!(cache.state(1, 0x1000), MsiState::Shared);
+        assert_eq!(cache.coherence.num_downgrade, 1);
+        assert_eq!(cache.l1[0].history.num_writeback, 1);
+        let l2_index = cache.l2.lookup(0x1000).unwrap();
+        assert!(cache.l2.blocks[l2_index].dirty);
+
+        // Core 1 writes it, invalidating the copy of core 0
+        cache.core(1).set8(0x1000, 7, &mut Some(0)).unwrap();
+        assert_eq!(cache.state(0, 0x1000), MsiState::Invalid);
+        assert_eq!(cache.state(1, 0x1000), MsiState::Modified);
+        assert_eq!(cache.coherence.num_invalidation, 1);
+
+        // Core 0 misses on it again
+        let mut stall_count = Some(0);
+        assert_eq!(cache.core(0).get8(0x1000, &mut stall_count).unwrap(), 7);
+        assert_eq!(stall_count, Some(cache.l2.policy.hit_latency));
+        assert_eq!(cache.core(0).get_history()[0].num_miss, 2);
+        assert_eq!(cache.coherence.num_downgrade, 2);
+    }
+
+    #[test]
+    fn test_private_lines() {
+        let mut cache = MultiCoreCache::default();
+        cache.mmu.allocate_page(0x1000);
+
+        // Each core writes its own line, so nothing is snooped
+        for _ in 0..4 {
+            cache.core(0).set32(0x1000, 1, &mut Some(0)).unwrap();
+            cache.core(1).set32(0x1040, 2, &mut Some(0)).unwrap();
+        }
+        assert_eq!(cache.coherence, CoherenceHistory::default());
+        assert_eq!(cache.state(0, 0x1000), MsiState::Modified);
+        assert_eq!(cache.state(1, 0x1040), MsiState::Modified);
+    }
+}
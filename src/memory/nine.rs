@@ -0,0 +1,219 @@
+//! Non-inclusive non-exclusive (NINE) cache implementation
+
+use super::cache::Cache;
+use super::cache::CachePolicy;
+use super::mmu::MMU;
+use super::AccessType;
+use super::StorageInterface;
+
+/// Non-inclusive non-exclusive cache implementation.
+/// A miss fills every level it passes through,
+/// but no level is ever invalidated on behalf of another,
+/// so a block may live in any subset of the levels.
+/// We maintain n (k >= 0) caches and 1 MMU
+pub struct NineCache {
+    pub n: usize,
+    pub caches: Vec<Cache>,
+    pub mmu: MMU,
+
+    pub miss_penalty: i32,
+    pub total_penalty: i32,
+    pub total_worst_penalty: i32,
+
+    pub ref_counter: i32,
+}
+
+impl Default for NineCache {
+    /// Make a 3-level NINE cache
+    /// with policies adhering to the assignment specification
+    fn default() -> Self {
+        Self::make(
+            vec![
+                CachePolicy::make(16 * 1024, 64, 1, 1),
+                CachePolicy::make(128 * 1024, 64, 8, 8),
+                CachePolicy::make(2 * 1024 * 1024, 64, 16, 20),
+            ],
+            100,
+        )
+    }
+}
+
+impl NineCache {
+    /// Create a NINE cache
+    /// from a vector of cache policies for each level,
+    pub fn make(policies: Vec<CachePolicy>, miss_penalty: i32) -> Self {
+        let caches: Vec<_> =
+            policies.iter().map(|policy| Cache::make(*policy)).collect();
+        Self {
+            n: policies.len(),
+            caches,
+            mmu: MMU::make(),
+            miss_penalty,
+            total_penalty: 0,
+            total_worst_penalty: 0,
+            ref_counter: 0,
+        }
+    }
+
+    /// Reports how the valid blocks at each level but the last
+    /// relate to the next level, as the pair
+    /// (number also present at the next level, number absent from it).
+    /// Nothing is asserted, since any relationship is allowed
+    pub fn observe_inclusion(&self) -> Vec<(usize, usize)> {
+        let mut result = Vec::new();
+        for k in 0..self.n.saturating_sub(1) {
+            let (mut present, mut absent) = (0, 0);
+            for block in self.caches[k].blocks.iter() {
+                if !block.valid {
+                    continue;
+                }
+                let address = self.caches[k].get_address(block);
+                match self.caches[k + 1].is_in_cache(address) {
+                    true => present += 1,
+                    false => absent += 1,
+                }
+            }
+            result.push((present, absent));
+        }
+        result
+    }
+}
+
+impl StorageInterface for NineCache {
+    fn n(&self) -> usize {
+        self.n
+    }
+    fn caches(&mut self, k: usize) -> &mut Cache {
+        self.caches.get_mut(k).unwrap()
+    }
+    fn mmu(&mut self) -> &mut MMU {
+        &mut self.mmu
+    }
+    fn ref_counter(&mut self) -> &mut i32 {
+        &mut self.ref_counter
+    }
+
+    fn total_penalty(&mut self) -> &mut i32 {
+        &mut self.total_penalty
+    }
+    fn total_worst_penalty(&mut self) -> &mut i32 {
+        &mut self.total_worst_penalty
+    }
+    fn miss_penalty(&self) -> i32 {
+        self.miss_penalty
+    }
+
+    /// Fills the block from the next level,
+    /// leaving the copies at the other levels alone
+    fn handle_miss(
+        &mut self,
+        k: usize,
+        address: u32,
+        _: AccessType,
+        stall_count: &mut Option<i32>,
+    ) -> Option<usize> {
+        assert!(k < self.n());
+
+        // Make a new block and replace some
+        // evicted one
+        let block = self.caches[k].make_block(address);
+
+        // Read the block from the next level,
+        // which fills it there as well on a miss
+        self.access_inner(k + 1, address, AccessType::Read, stall_count);
+
+        // Replace the block with the least recent reference
+        let index_to_replace = self.caches[k].get_index_to_replace(block.index);
+        assert!(self.lookup(k, address).is_none());
+        let replaced_block = std::mem::replace(
+            &mut self.caches[k].blocks[index_to_replace],
+            block,
+        );
+
+        // Only dirty blocks need to go down,
+        // without invalidating anything at the upper levels
+        if replaced_block.valid && replaced_block.dirty {
+            self.write_to_next_level(k, &replaced_block);
+        }
+
+        Some(index_to_replace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::inclusive::InclusiveCache;
+    use crate::memory::{WriteHitPolicy, WriteMissPolicy};
+    use crate::run_wrapper::{parse_operations, replay_operations};
+
+    /// Reads pseudo-random words, so nothing is ever dirty
+    fn read_only_trace() -> String {
+        let mut seed: u32 = 12345;
+        let mut trace = String::new();
+        for _ in 0..20000 {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            let address = 0x10000 + (seed >> 10) % 0x2000 / 4 * 4;
+            trace += &format!("r {:#010x} 4\n", address);
+        }
+        trace
+    }
+
+    #[test]
+    fn test_against_inclusive() {
+        let policies = vec![
+            CachePolicy::make(1024, 64, 1, 1),
+            CachePolicy::make(2048, 64, 2, 8),
+            CachePolicy::make(4096, 64, 4, 20),
+        ];
+        let operations = parse_operations(&read_only_trace());
+
+        let mut nine = NineCache::make(policies.clone(), 100);
+        replay_operations(&mut nine, &operations);
+        let mut inclusive = InclusiveCache::make(
+            policies,
+            WriteHitPolicy::default(),
+            WriteMissPolicy::default(),
+            100,
+            false,
+        );
+        replay_operations(&mut inclusive, &operations);
+
+        let nine_history = nine.get_history();
+        let inclusive_history = inclusive.get_history();
+        // The upper levels see the same stream
+        for k in 0..2 {
+            assert_eq!(nine_history[k].num_hit, inclusive_history[k].num_hit);
+            assert_eq!(nine_history[k].num_miss, inclusive_history[k].num_miss);
+        }
+        // Fills stay clean, so a read-only trace never writes back,
+        // whereas the inclusive cache writes filled blocks back to L3,
+        // touching its blocks and thus its hit rate
+        assert!(nine_history.iter().all(|h| h.num_writeback == 0));
+        assert!(inclusive_history[1].num_writeback > 0);
+        assert_ne!(nine_history[2].num_hit, inclusive_history[2].num_hit);
+        assert_ne!(nine.get_amat(), inclusive.get_amat());
+    }
+
+    #[test]
+    fn test_observe_inclusion() {
+        // L2 holds a single block, fewer than L1
+        let mut cache = NineCache::make(
+            vec![
+                CachePolicy::make(128, 64, 2, 1),
+                CachePolicy::make(64, 64, 1, 8),
+            ],
+            100,
+        );
+        cache.mmu().allocate_page(0x1000);
+        assert_eq!(cache.observe_inclusion(), vec![(0, 0)]);
+
+        cache.get8(0x1000, &mut Some(0)).unwrap();
+        assert_eq!(cache.observe_inclusion(), vec![(1, 0)]);
+        // L2 evicts 0x1000, which stays in L1
+        cache.get8(0x1040, &mut Some(0)).unwrap();
+        assert_eq!(cache.observe_inclusion(), vec![(1, 1)]);
+        cache.get8(0x1000, &mut Some(0)).unwrap();
+        assert_eq!(cache.get_history()[0].num_hit, 1);
+    }
+}
@@ -0,0 +1,103 @@
+//! Memory without any cache
+
+use super::cache::Cache;
+use super::mmu::MMU;
+use super::trace::TraceRecorder;
+use super::AccessType;
+use super::StorageInterface;
+
+/// Memory without a cache hierarchy,
+/// where every access goes straight to the MMU at a fixed latency.
+/// Useful for functional testing, as no cache is modeled at all
+pub struct PassthroughMemory {
+    pub mmu: MMU,
+
+    /// Latency of every access
+    pub latency: i32,
+    pub total_penalty: i32,
+    pub total_worst_penalty: i32,
+
+    pub ref_counter: i32,
+
+    /// Records demand accesses, if enabled
+    pub trace: Option<TraceRecorder>,
+}
+
+impl Default for PassthroughMemory {
+    /// Make a passthrough memory as slow as
+    /// the main memory of the default hierarchies
+    fn default() -> Self {
+        Self::make(100)
+    }
+}
+
+impl PassthroughMemory {
+    pub fn make(latency: i32) -> Self {
+        Self {
+            mmu: MMU::make(),
+            latency,
+            total_penalty: 0,
+            total_worst_penalty: 0,
+            ref_counter: 0,
+            trace: None,
+        }
+    }
+}
+
+impl StorageInterface for PassthroughMemory {
+    fn n(&self) -> usize {
+        0
+    }
+    fn caches(&mut self, _: usize) -> &mut Cache {
+        unreachable!("A passthrough memory has no caches")
+    }
+    fn mmu(&mut self) -> &mut MMU {
+        &mut self.mmu
+    }
+    fn ref_counter(&mut self) -> &mut i32 {
+        &mut self.ref_counter
+    }
+
+    fn total_penalty(&mut self) -> &mut i32 {
+        &mut self.total_penalty
+    }
+    fn total_worst_penalty(&mut self) -> &mut i32 {
+        &mut self.total_worst_penalty
+    }
+    fn miss_penalty(&self) -> i32 {
+        self.latency
+    }
+
+    fn trace(&mut self) -> Option<&mut TraceRecorder> {
+        self.trace.as_mut()
+    }
+
+    fn handle_miss(
+        &mut self,
+        _: usize,
+        _: u32,
+        _: AccessType,
+        _: &mut Option<i32>,
+    ) -> Option<usize> {
+        unreachable!("A passthrough memory has no caches")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_latency() {
+        let mut mem = PassthroughMemory::make(7);
+        mem.mmu().allocate_page(0x1000);
+        let mut stall_count = Some(0);
+        mem.set32(0x1000, 0x12345678, &mut stall_count).unwrap();
+        assert_eq!(stall_count, Some(7));
+        let mut stall_count = Some(0);
+        assert_eq!(mem.get32(0x1000, &mut stall_count).unwrap(), 0x12345678);
+        assert_eq!(stall_count, Some(7));
+        assert!(mem.get_history().is_empty());
+        assert_eq!(mem.get_amat(), 7.0);
+    }
+}
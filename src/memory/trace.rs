@@ -0,0 +1,43 @@
+//! Memory access trace recording, for replay with `run_trace`
+
+use super::AccessType;
+use std::io::{self, Write};
+
+/// Records the address stream of demand accesses
+#[derive(Clone, Default)]
+pub struct TraceRecorder {
+    /// Also record instruction fetches
+    pub include_fetches: bool,
+    /// Recorded (access type, address, size) triples
+    pub accesses: Vec<(AccessType, u32, u32)>,
+}
+
+impl TraceRecorder {
+    pub fn make(include_fetches: bool) -> Self {
+        Self {
+            include_fetches,
+            accesses: Vec::new(),
+        }
+    }
+
+    /// Records an access of the given size in bytes
+    pub fn record(&mut self, access_type: AccessType, address: u32, size: u32) {
+        if access_type == AccessType::Execute && !self.include_fetches {
+            return;
+        }
+        self.accesses.push((access_type, address, size));
+    }
+
+    /// Writes the trace, one "r|w|x 0xADDR size" line per access
+    pub fn write(&self, output: &mut impl Write) -> io::Result<()> {
+        for (access_type, address, size) in &self.accesses {
+            let op = match access_type {
+                AccessType::Read => 'r',
+                AccessType::Write => 'w',
+                AccessType::Execute => 'x',
+            };
+            writeln!(output, "{} {:#010x} {}", op, address, size)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! Multi-cycle implementation
+//!
+//! Each instruction goes through IF, ID, EX, MEM and WB,
+//! one stage per cycle, skipping MEM unless it loads or stores.
+//! It thus takes 4 cycles, or 5 for loads and stores.
+//! Functionally, it executes just like the single-cycle implementation
+
+use crate::cpu::{CPUHistory, CPUState};
+use crate::error::SimulatorResult;
+use crate::instruction::DecodeCache;
+use crate::memory::StorageInterface;
+use crate::single_cycle;
+use crate::system_call::SyscallIo;
+use std::io::{BufRead, Write};
+
+/// Returns the exiting PC address
+pub fn run(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<u32> {
+    Ok(run_with_stats(cpu, mem, io)?.0)
+}
+
+/// Returns the exiting PC address along with the history of execution
+pub fn run_with_stats(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<(u32, CPUHistory)> {
+    let mut decoder = DecodeCache::default();
+    loop {
+        let accesses = cpu.history.mix.memory_accesses();
+        let exit_pc = single_cycle::step(cpu, mem, io, &mut decoder)?;
+        // The step counts a single cycle; ID, EX and WB take one each,
+        // and so does MEM for the instructions accessing memory
+        let mem_cycles = cpu.history.mix.memory_accesses() - accesses;
+        cpu.update_cycle_count(3 + mem_cycles);
+        if let Some(exit_pc) = exit_pc {
+            return Ok((exit_pc, cpu.history));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPUPolicy;
+    use crate::single_cycle;
+    use crate::test_utils::load_program;
+
+    #[test]
+    fn test_matches_single_cycle() {
+        let program = [
+            0x000012b7, // lui t0, 0x1
+            0x0002a303, // lw t1, 0(t0)
+            0x1062a023, // sw t1, 256(t0)
+            0x00000463, // beq x0, x0, 8
+            0x00100393, // addi t2, x0, 1
+            0x0040006f, // jal x0, 4
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut single = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut single, &program);
+        let (single_pc, single_history) = single_cycle::run_with_stats(
+            &mut single,
+            &mut mem,
+            &mut SyscallIo::stdio(),
+        )
+        .unwrap();
+
+        let mut multi = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut multi, &program);
+        let (multi_pc, multi_history) =
+            run_with_stats(&mut multi, &mut mem, &mut SyscallIo::stdio())
+                .unwrap();
+
+        assert_eq!(single_pc, multi_pc);
+        for i in 0..32 {
+            assert_eq!(single.gpr[i].read(), multi.gpr[i].read(), "x{}", i);
+        }
+        assert_eq!(single_history.inst_count, multi_history.inst_count);
+        assert_eq!(single_history.mix, multi_history.mix);
+
+        // One cycle per instruction against 4, plus MEM for loads and stores
+        let mix = multi_history.mix;
+        assert_eq!(single_history.cycle_count, 7);
+        assert_eq!(
+            multi_history.cycle_count,
+            4 * single_history.cycle_count + mix.loads + mix.stores
+        );
+        assert_eq!(multi_history.cycle_count, 30);
+    }
+
+    #[test]
+    fn test_trap_and_hang() {
+        use crate::error::SimulatorError;
+        use crate::test_utils::PROGRAM_BASE;
+
+        let program = [
+            0xffffffff, // (illegal)
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+            0x00150513, // addi a0, a0, 1 (trap handler)
+            0x30200073, // mret
+        ];
+        let policy = CPUPolicy {
+            implementation: crate::cpu::Implementation::MultiCycle,
+            trap_vector: Some(PROGRAM_BASE + 12),
+            ..Default::default()
+        };
+        policy.validate().unwrap();
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        assert_eq!(cpu.gpr[10].read(), 1);
+        assert_eq!(cpu.mepc.read(), PROGRAM_BASE);
+
+        let program = [
+            0x00000063, // beq x0, x0, 0
+        ];
+        let policy = CPUPolicy {
+            trap_vector: None,
+            hang_threshold: Some(10),
+            ..policy
+        };
+        policy.validate().unwrap();
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        let error =
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap_err();
+        assert!(matches!(error, SimulatorError::Hang(PROGRAM_BASE)));
+        assert_eq!(cpu.history.inst_count, 10);
+    }
+}
@@ -3,20 +3,17 @@
 
 const PREDICTOR_BUFFER_SIZE: usize = 4096;
 
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Clone, Copy, PartialEq, Default)]
 pub enum PredictorHeuristic {
     AlwaysNotTaken,
+    #[default]
     BufferedPrediction,
 }
 
-impl Default for PredictorHeuristic {
-    fn default() -> Self {
-        PredictorHeuristic::BufferedPrediction
-    }
-}
-
-#[derive(Clone, Copy)]
-enum PredictorState {
+/// 2-bit saturating counter of a buffer entry
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+pub enum PredictorState {
     StronglyTaken = 0,
     WeaklyTaken = 1,
     WeaklyNotTaken = 2,
@@ -39,6 +36,11 @@ impl BranchPredictor {
         }
     }
 
+    /// Returns the counter consulted for the branch at the given PC
+    pub fn state(&self, pc: u32) -> PredictorState {
+        self.buffer[(pc as usize) % PREDICTOR_BUFFER_SIZE]
+    }
+
     pub fn predict(&self, pc: u32) -> bool {
         if self.heuristic != PredictorHeuristic::BufferedPrediction {
             // Always not taken
@@ -81,3 +83,61 @@ impl BranchPredictor {
         }
     }
 }
+
+/// Describes a resolved branch for verbose logging,
+/// with the counter states before and after the update
+pub fn format_outcome(
+    pc: u32,
+    predicted: bool,
+    taken: bool,
+    before: PredictorState,
+    after: PredictorState,
+) -> String {
+    let direction = |taken: bool| match taken {
+        true => "taken",
+        false => "not taken",
+    };
+    format!(
+        "[VERBOSE] Branch at {:#010x}: predicted {}, actually {}{}; {:?} -> {:?}",
+        pc,
+        direction(predicted),
+        direction(taken),
+        match predicted == taken {
+            true => "",
+            false => " (mispredicted)",
+        },
+        before,
+        after
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mispredicted_outcome() {
+        let pc = 0x1000;
+        let mut predictor =
+            BranchPredictor::new(PredictorHeuristic::BufferedPrediction);
+        let predicted = predictor.predict(pc);
+        let before = predictor.state(pc);
+        predictor.update(pc, false);
+        let after = predictor.state(pc);
+        assert!(predicted);
+        assert_eq!(before, PredictorState::WeaklyTaken);
+        assert_eq!(after, PredictorState::WeaklyNotTaken);
+        assert_eq!(
+            format_outcome(pc, predicted, false, before, after),
+            "[VERBOSE] Branch at 0x00001000: predicted taken, \
+             actually not taken (mispredicted); WeaklyTaken -> WeaklyNotTaken"
+        );
+
+        let before = after;
+        predictor.update(pc, false);
+        let line =
+            format_outcome(pc, false, false, before, predictor.state(pc));
+        assert!(!line.contains("mispredicted"));
+        assert!(line.ends_with("WeaklyNotTaken -> StronglyNotTaken"));
+    }
+}
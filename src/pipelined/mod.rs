@@ -1,31 +1,102 @@
 //! Pipelined implementation
 
 use crate::{
-    cpu::CPUState,
-    instruction::{Instruction, Opcode, NOP},
-    memory::StorageInterface,
-    pipelined::pipeline::PipelineState,
+    cpu::CPUState, error::SimulatorResult, instruction::Opcode,
+    memory::StorageInterface, pipelined::pipeline::PipelineState,
+    system_call::SyscallIo,
 };
-use core::panic;
+use std::io::{BufRead, Write};
 
 pub mod branch_predictor;
 pub mod pipeline;
 pub mod stages;
 
 /// Returns the exiting PC address
-pub fn run(mut cpu: &mut CPUState, mem: &mut impl StorageInterface) -> u32 {
-    let mut current_state = PipelineState::default();
-    let mut next_state = PipelineState::default();
-
-    let mut branch_predictor =
-        branch_predictor::BranchPredictor::new(cpu.policy.heuristic);
-    let mut predicted_branch_taken: bool = false;
+pub fn run(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<u32> {
+    run_with_retire(cpu, mem, io, None)
+}
 
+/// Runs like run, calling on_retire, if any,
+/// once per instruction as it retires
+pub fn run_with_retire(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+    mut on_retire: Option<&mut dyn FnMut(RetireInfo)>,
+) -> SimulatorResult<u32> {
+    let mut pipeline = Pipeline::new(cpu.policy.heuristic);
     loop {
-        // Check for stack overflow
-        if cpu.stack_overflow() {
-            panic!("Stack overflow");
+        let on_retire = on_retire
+            .as_mut()
+            .map(|f| &mut **f as &mut dyn FnMut(RetireInfo));
+        if let Some(exit_pc) = pipeline.step(cpu, mem, io, on_retire)? {
+            return Ok(exit_pc);
+        }
+    }
+}
+
+/// An instruction leaving the WB stage
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetireInfo {
+    pub pc: u32,
+    pub raw_inst: u32,
+    /// Register written, as (rd, value), unless it's x0
+    pub reg_write: Option<(usize, u32)>,
+    /// Memory written, as (address, value, size in bytes)
+    pub mem_write: Option<(u32, u32, u32)>,
+}
+
+/// Pipelined execution, stepped one cycle at a time
+pub struct Pipeline {
+    /// Pipeline registers at the start of the next cycle
+    pub state: PipelineState,
+    next_state: PipelineState,
+    branch_predictor: branch_predictor::BranchPredictor,
+    predicted_branch_taken: bool,
+    /// Set once an exit system call is executed;
+    /// we then stop fetching and let the pipeline drain
+    draining: bool,
+}
+
+impl Pipeline {
+    pub fn new(heuristic: branch_predictor::PredictorHeuristic) -> Self {
+        Self {
+            state: PipelineState::default(),
+            next_state: PipelineState::default(),
+            branch_predictor: branch_predictor::BranchPredictor::new(heuristic),
+            predicted_branch_taken: false,
+            draining: false,
         }
+    }
+
+    /// Whether every instruction in flight has retired,
+    /// so the architectural state is consistent
+    pub fn is_drained(&self) -> bool {
+        self.state.is_drained()
+    }
+
+    /// Simulates a single cycle, calling on_retire, if any,
+    /// for the instruction retiring in this cycle.
+    /// Returns the exiting PC address if the exit system call
+    /// is committed in this cycle
+    pub fn step(
+        &mut self,
+        cpu: &mut CPUState,
+        mem: &mut impl StorageInterface,
+        io: &mut SyscallIo<impl BufRead, impl Write>,
+        on_retire: Option<&mut dyn FnMut(RetireInfo)>,
+    ) -> SimulatorResult<Option<u32>> {
+        let current_state = self.state;
+        let next_state = &mut self.next_state;
+        let branch_predictor = &mut self.branch_predictor;
+
+        // Check for stack overflow
+        cpu.check_stack_overflow()?;
+        cpu.check_instruction_limit()?;
 
         // Print the initial PC of this cycle
         if cpu.policy.verbose {
@@ -37,29 +108,50 @@ pub fn run(mut cpu: &mut CPUState, mem: &mut impl StorageInterface) -> u32 {
 
         if current_state.load_hazard() {
             // Must insert a NOP
-            next_state.id_ex.inst = Instruction::default();
-            cpu.update_inst_count(-1);
+            next_state.flush_id_ex();
             if cpu.policy.verbose {
                 eprintln!("[VERBOSE] Inserting NOP due to load hazard");
             }
-        } else {
-            stages::instruction_fetch(&mut cpu, mem, &mut next_state);
-            stages::instruction_decode(&cpu, &current_state, &mut next_state);
+        } else if !self.draining {
+            // A single-ported memory serves the MEM stage first
+            let controls = current_state.ex_mem.inst.controls;
+            if cpu.policy.single_port
+                && (controls.mem_read || controls.mem_write)
+            {
+                next_state.flush_if_id();
+                cpu.history.structural_stall_count += 1;
+                if cpu.policy.verbose {
+                    eprintln!(
+                        "[VERBOSE] Inserting NOP due to structural hazard"
+                    );
+                }
+            } else {
+                stages::instruction_fetch(cpu, mem, next_state)?;
+            }
+            stages::instruction_decode(cpu, &current_state, next_state)?;
         }
 
-        stages::execute(&mut cpu, mem, &current_state, &mut next_state);
-        stages::memory_access(&mut cpu, mem, &current_state, &mut next_state);
-        stages::write_back(&mut cpu, &current_state);
+        stages::execute(cpu, mem, &current_state, next_state, io)?;
+        stages::memory_access(cpu, mem, &current_state, next_state)?;
+        stages::write_back(cpu, &current_state, on_retire);
 
-        let exec_inst = next_state.ex_mem.inst;
-        if exec_inst.opcode == Opcode::System && next_state.ex_mem.op2 == 3 {
-            return next_state.ex_mem.pc;
+        // The exit system call is committed
+        // only after everything before it is written back
+        let exit_pc = current_state.mem_wb.exit_pc;
+
+        if next_state.ex_mem.exit_pc.is_some() {
+            // Flush everything after the exit system call
+            next_state.flush_if_id();
+            next_state.flush_id_ex();
+            self.draining = true;
         }
 
+        let exec_inst = next_state.ex_mem.inst;
+
         let exec_result = next_state.ex_mem.exec_result;
         if exec_inst.controls.branch {
             // Do branch, conditional or unconditional
-            let imm = exec_inst.attributes.imm.unwrap() as i32;
+            let imm = exec_inst.attributes.imm.unwrap();
             let exec_pc = next_state.ex_mem.pc;
             let actual_new_pc: u32;
             let branch_taken: bool;
@@ -69,19 +161,32 @@ pub fn run(mut cpu: &mut CPUState, mem: &mut impl StorageInterface) -> u32 {
                 actual_new_pc = match exec_inst.opcode {
                     Opcode::Jalr => (exec_result as u32) & !1u32,
                     Opcode::Branch => next_state.ex_mem.taken_pc.unwrap(),
-                    _ => ((exec_pc as i32) + imm) as u32,
+                    _ => exec_pc.wrapping_add(imm),
                 };
             } else {
                 // Branch not taken
                 branch_taken = false;
-                actual_new_pc = exec_pc + 4;
+                actual_new_pc = exec_pc.wrapping_add(exec_inst.length);
             }
 
             let mut do_jump: bool = true;
             if exec_inst.opcode == Opcode::Branch {
                 // Update the branch predictor
+                let before = branch_predictor.state(exec_pc);
                 branch_predictor.update(exec_pc, branch_taken);
-                if branch_taken == predicted_branch_taken {
+                if cpu.policy.verbose {
+                    eprintln!(
+                        "{}",
+                        branch_predictor::format_outcome(
+                            exec_pc,
+                            self.predicted_branch_taken,
+                            branch_taken,
+                            before,
+                            branch_predictor.state(exec_pc),
+                        )
+                    );
+                }
+                if branch_taken == self.predicted_branch_taken {
                     do_jump = false;
                 }
             }
@@ -97,28 +202,39 @@ pub fn run(mut cpu: &mut CPUState, mem: &mut impl StorageInterface) -> u32 {
 
                 cpu.pc.write(actual_new_pc);
                 // Flush
-                next_state.if_id.raw_inst = NOP;
-                next_state.id_ex.inst = Instruction::default();
-                // We're replacing 2 instructions with NOP
-                cpu.update_inst_count(-2);
+                next_state.flush_if_id();
+                next_state.flush_id_ex();
             }
         }
 
-        predicted_branch_taken = false;
+        // The branch resolved in EX is older than the one predicted in ID,
+        // so a misprediction takes priority: it flushes ID/EX above,
+        // and no prediction may redirect the PC it just corrected
+        self.predicted_branch_taken = false;
         // Try branch prediction
         let id_inst = next_state.id_ex.inst;
-        if id_inst.opcode == Opcode::Branch {
-            match branch_predictor.predict(next_state.id_ex.pc) {
+        if next_state.id_ex.valid && id_inst.opcode == Opcode::Branch {
+            let prediction = branch_predictor.predict(next_state.id_ex.pc);
+            if cpu.policy.verbose {
+                eprintln!(
+                    "[VERBOSE] Predicting branch at {:#010x}: {}; {:?}",
+                    next_state.id_ex.pc,
+                    match prediction {
+                        true => "taken",
+                        false => "not taken",
+                    },
+                    branch_predictor.state(next_state.id_ex.pc)
+                );
+            }
+            match prediction {
                 true => {
                     // Predicted taken; let's do this
                     // Jump to taken_pc
                     cpu.pc.write(next_state.id_ex.taken_pc.unwrap());
                     // Flush
-                    next_state.if_id.raw_inst = NOP;
-                    // We're dumping 1 instruction
-                    cpu.update_inst_count(-1);
+                    next_state.flush_if_id();
                     // Set the taken flag
-                    predicted_branch_taken = true;
+                    self.predicted_branch_taken = true;
                 }
                 false => {
                     // Do nothing
@@ -127,6 +243,498 @@ pub fn run(mut cpu: &mut CPUState, mem: &mut impl StorageInterface) -> u32 {
         }
 
         // Advance the pipeline state
-        current_state = next_state;
+        self.state = *next_state;
+
+        Ok(exit_pc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPUPolicy;
+    use crate::memory::inclusive::InclusiveCache;
+    use crate::test_utils::{
+        load_program, run_both, run_both_elf, PROGRAM_BASE,
+    };
+
+    #[test]
+    fn test_exit_with_forwarded_operands() {
+        let program = [
+            0x00100893, // addi a7, x0, 1
+            0x00288893, // addi a7, a7, 2
+            0x00900293, // addi t0, x0, 9
+            0x00000073, // ecall
+            0x00100313, // addi t1, x0, 1
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+
+        let exit_pc = run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        assert_eq!(exit_pc, PROGRAM_BASE + 12);
+        // a7 is forwarded from the previous instruction
+        assert_eq!(cpu.gpr[17].read(), 3);
+        // The instruction right before the exit is written back
+        assert_eq!(cpu.gpr[5].read(), 9);
+        // The instruction right after the exit is never committed
+        assert_eq!(cpu.gpr[6].read(), 0);
+    }
+
+    #[test]
+    fn test_step_until_drained() {
+        let program = [
+            0x00500293, // addi t0, x0, 5
+            0x00128313, // addi t1, t0, 1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+            0x00100393, // addi t2, x0, 1
+            0x00200393, // addi t2, x0, 2
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let mut io = SyscallIo::stdio();
+
+        let mut pipeline = Pipeline::new(cpu.policy.heuristic);
+        let exit_pc = loop {
+            if let Some(exit_pc) =
+                pipeline.step(&mut cpu, &mut mem, &mut io, None).unwrap()
+            {
+                break exit_pc;
+            }
+            assert!(!pipeline.is_drained());
+        };
+        assert_eq!(exit_pc, PROGRAM_BASE + 12);
+
+        for _ in 0..5 {
+            if pipeline.is_drained() {
+                break;
+            }
+            pipeline.step(&mut cpu, &mut mem, &mut io, None).unwrap();
+        }
+        assert!(pipeline.is_drained());
+        assert_eq!(cpu.gpr[5].read(), 5);
+        assert_eq!(cpu.gpr[6].read(), 6);
+        assert_eq!(cpu.gpr[17].read(), 3);
+        // Nothing after the exit retires, however long we keep stepping
+        assert_eq!(cpu.gpr[7].read(), 0);
+        pipeline.step(&mut cpu, &mut mem, &mut io, None).unwrap();
+        assert_eq!(cpu.gpr[7].read(), 0);
+        assert_eq!(cpu.history.inst_count, 4);
+    }
+
+    #[test]
+    fn test_nops_in_flight() {
+        let program = [
+            0x00500293, // addi t0, x0, 5
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let mut io = SyscallIo::stdio();
+
+        // The NOPs fill all four registers at some point,
+        // yet they're real instructions still to retire
+        let mut pipeline = Pipeline::new(cpu.policy.heuristic);
+        while pipeline
+            .step(&mut cpu, &mut mem, &mut io, None)
+            .unwrap()
+            .is_none()
+        {
+            assert!(!pipeline.is_drained());
+        }
+        assert_eq!(cpu.history.inst_count, 7);
+    }
+
+    #[test]
+    fn test_wrong_path_faults() {
+        use crate::error::{MemoryError, SimulatorError};
+        use crate::instruction::NOP;
+        // Data right after an unconditional jump
+        run_both(&[
+            0x0080006f, // jal x0, 8
+            0xffffffff, // Not an instruction
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ]);
+
+        // Fetching past the end of the last page
+        let mut program = vec![NOP; 1024];
+        program[0] = 0x7f90006f; // jal x0, 4088
+        program[1022] = 0x00300893; // addi a7, x0, 3
+        program[1023] = 0x00000073; // ecall
+        run_both(&program);
+
+        // Faults on the right path are still raised
+        let run_to_error = |program: &[u32]| {
+            let mut cpu = CPUState::make(CPUPolicy::default());
+            let mut mem = load_program(&mut cpu, program);
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap_err()
+        };
+        assert!(matches!(
+            run_to_error(&[NOP, 0xffffffff]),
+            SimulatorError::InvalidInstructionAt(pc, 0xffffffff)
+                if pc == PROGRAM_BASE + 4
+        ));
+        assert!(matches!(
+            run_to_error(&[NOP; 1024]),
+            SimulatorError::Memory(MemoryError::ReadUnallocated(0x2000))
+        ));
+    }
+
+    #[test]
+    fn test_jal_link_forwarding() {
+        let program = [
+            0x00c000ef, // jal ra, 12
+            0x00100393, // addi t2, x0, 1
+            0x00200393, // addi t2, x0, 2
+            0x00008293, // addi t0, ra, 0
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+
+        run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        assert_eq!(cpu.gpr[1].read(), PROGRAM_BASE + 4);
+        // The link value is forwarded to the jump target
+        assert_eq!(cpu.gpr[5].read(), PROGRAM_BASE + 4);
+        // The skipped instructions are flushed
+        assert_eq!(cpu.gpr[7].read(), 0);
+    }
+
+    #[test]
+    fn test_jalr_link_forwarding() {
+        let program = [
+            0x00001337, // lui t1, 0x1
+            0x010300e7, // jalr ra, 16(t1)
+            0x00100393, // addi t2, x0, 1
+            0x00200393, // addi t2, x0, 2
+            0x00008293, // addi t0, ra, 0
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+
+        run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        assert_eq!(cpu.gpr[1].read(), PROGRAM_BASE + 8);
+        // The link value is forwarded to the jump target
+        assert_eq!(cpu.gpr[5].read(), PROGRAM_BASE + 8);
+        // The skipped instructions are flushed
+        assert_eq!(cpu.gpr[7].read(), 0);
+    }
+
+    #[test]
+    fn test_upper_immediate_forwarding() {
+        let program = [
+            0x123452b7, // lui t0, 0x12345
+            0x67828313, // addi t1, t0, 0x678
+            0x00001397, // auipc t2, 0x1
+            0x00538e33, // add t3, t2, t0
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+
+        run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        // Both results are forwarded straight from EX
+        assert_eq!(cpu.gpr[6].read(), 0x12345678);
+        assert_eq!(cpu.gpr[7].read(), PROGRAM_BASE + 8 + 0x1000);
+        assert_eq!(cpu.gpr[28].read(), PROGRAM_BASE + 8 + 0x12346000);
+    }
+
+    /// Runs the program with the default policy and a data page at 0x2000,
+    /// checking the final state, and returns the cycle count
+    fn run_cycles(
+        program: &[u32],
+        check: impl FnOnce(&CPUState, &mut InclusiveCache),
+    ) -> i32 {
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, program);
+        mem.mmu.allocate_page(0x2000);
+        run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        check(&cpu, &mut mem);
+        cpu.history.cycle_count
+    }
+
+    #[test]
+    fn test_load_into_x0() {
+        let program = |second: u32| {
+            [
+                0x000012b7, // lui t0, 0x1
+                second,     // Swapped in below
+                0x00500513, // addi a0, x0, 5
+                0x00000593, // addi a1, x0, 0
+                0x00300893, // addi a7, x0, 3
+                0x00000073, // ecall
+            ]
+        };
+        let check = |cpu: &CPUState, _: &mut InclusiveCache| {
+            // The loaded word is neither written nor forwarded
+            assert_eq!(cpu.gpr[0].read(), 0);
+            assert_eq!(cpu.gpr[10].read(), 5);
+            assert_eq!(cpu.gpr[11].read(), 0);
+        };
+        // lw x0, 0(t0) reads x0 right after, yet doesn't stall like a load-use
+        assert_eq!(
+            run_cycles(&program(0x0002a003), check),
+            run_cycles(&program(0x00000013), check)
+        );
+    }
+
+    #[test]
+    fn test_hint_after_load() {
+        let program = |third: u32| {
+            [
+                0x000012b7, // lui t0, 0x1
+                0x0002a303, // lw t1, 0(t0)
+                third,      // Swapped in below
+                0x00500513, // addi a0, x0, 5
+                0x00300893, // addi a7, x0, 3
+                0x00000073, // ecall
+            ]
+        };
+        let check = |cpu: &CPUState, _: &mut InclusiveCache| {
+            assert_eq!(cpu.gpr[0].read(), 0);
+            assert_eq!(cpu.gpr[10].read(), 5);
+        };
+        // addi x0, t1, 0 reads t1 right after, yet doesn't stall like a load-use
+        let hint = run_cycles(&program(0x00030013), check);
+        assert_eq!(hint, run_cycles(&program(0x00000013), check));
+        // whereas addi t2, t1, 0 does
+        assert_eq!(run_cycles(&program(0x00030393), check), hint + 1);
+    }
+
+    #[test]
+    fn test_division_into_x0() {
+        use crate::error::SimulatorError;
+        let program = [
+            0x00700293, // addi t0, x0, 7
+            0x00500313, // addi t1, x0, 5
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000313, // addi t1, x0, 0
+            0x0262c033, // div x0, t0, t1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let policy = CPUPolicy {
+            trap_division_by_zero: true,
+            ..Default::default()
+        };
+        // The zeroed divisor is forwarded, even though the quotient is dropped
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        assert!(matches!(
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()),
+            Err(SimulatorError::DivisionByZero(pc)) if pc == PROGRAM_BASE + 24
+        ));
+    }
+
+    #[test]
+    fn test_store_data_forwarding() {
+        let program = |last_store: u32| {
+            [
+                0x00002337, // lui t1, 0x2
+                0x00700293, // addi t0, x0, 7
+                0x005282b3, // add t0, t0, t0
+                0x00532023, // sw t0, 0(t1)
+                0x00032383, // lw t2, 0(t1)
+                last_store, // Swapped in below
+                0x00432503, // lw a0, 4(t1)
+                0x00300893, // addi a7, x0, 3
+                0x00000073, // ecall
+            ]
+        };
+        let check = |cpu: &CPUState, mem: &mut InclusiveCache| {
+            // The sum is forwarded from EX
+            assert_eq!(mem.mmu.get8(0x2000), 14);
+            assert_eq!(mem.mmu.get8(0x2004), 14);
+            assert_eq!(cpu.gpr[10].read(), 14);
+        };
+        // sw t2, 4(t1) gets the loaded data in MEM, without stalling
+        assert_eq!(
+            run_cycles(&program(0x00732223), check),
+            run_cycles(&program(0x00532223), check)
+        );
+    }
+
+    #[test]
+    fn test_structural_hazard() {
+        let run_history = |single_port: bool| {
+            let program = [
+                0x00002337, // lui t1, 0x2
+                0x00400293, // addi t0, x0, 4
+                0x00532023, // sw t0, 0(t1)
+                0x00032383, // lw t2, 0(t1)
+                0xfff28293, // addi t0, t0, -1
+                0xfe029ae3, // bne t0, x0, -12
+                0x00300893, // addi a7, x0, 3
+                0x00000073, // ecall
+            ];
+            let policy = CPUPolicy {
+                single_port,
+                ..Default::default()
+            };
+            let mut cpu = CPUState::make(policy);
+            let mut mem = load_program(&mut cpu, &program);
+            mem.mmu.allocate_page(0x2000);
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+            assert_eq!(cpu.gpr[5].read(), 0);
+            assert_eq!(cpu.gpr[7].read(), 1);
+            assert_eq!(mem.mmu.get8(0x2000), 1);
+            cpu.history
+        };
+        let dual_port = run_history(false);
+        let single_port = run_history(true);
+        assert_eq!(dual_port.structural_stall_count, 0);
+        // Each of the 8 loads and stores delays a fetch
+        assert_eq!(single_port.structural_stall_count, 8);
+        assert_eq!(single_port.inst_count, dual_port.inst_count);
+        assert_eq!(single_port.cycle_count, dual_port.cycle_count + 8);
+    }
+
+    #[test]
+    fn test_branch_heavy_count() {
+        use crate::cpu::InstructionMix;
+
+        let program = [
+            0x0080006f, // jal x0, 8
+            0x00100313, // addi t1, x0, 1
+            0x00500293, // addi t0, x0, 5
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ee3, // bne t0, x0, -4
+            0x00000463, // beq x0, x0, 8
+            0x00100313, // addi t1, x0, 1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let mut io = SyscallIo::stdio();
+
+        // Counted as they retire, so the count never drops,
+        // even while the pipeline starts with a flush
+        let mut pipeline = Pipeline::new(cpu.policy.heuristic);
+        let mut inst_count = 0;
+        while pipeline
+            .step(&mut cpu, &mut mem, &mut io, None)
+            .unwrap()
+            .is_none()
+        {
+            assert!(cpu.history.inst_count >= inst_count);
+            inst_count = cpu.history.inst_count;
+        }
+
+        // jal, addi, 5 x (addi, bne), beq, addi, ecall
+        let expected = InstructionMix {
+            branches: 6,
+            jumps: 1,
+            alu: 7,
+            system: 1,
+            ..Default::default()
+        };
+        assert_eq!(cpu.history.inst_count, 15);
+        assert_eq!(cpu.history.mix, expected);
+        assert_eq!(cpu.gpr[6].read(), 0);
+        let cpi = cpu.history.cycle_count as f64 / 15.0;
+        assert!(cpi >= 1.0 && cpi.is_finite());
+    }
+
+    #[test]
+    fn test_retire_callback() {
+        use crate::single_cycle;
+
+        let program = [
+            0x00002337, // lui t1, 0x2
+            0x00400293, // addi t0, x0, 4
+            0x00532023, // sw t0, 0(t1)
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ce3, // bne t0, x0, -8
+            0x0080006f, // jal x0, 8
+            0x00100393, // addi t2, x0, 1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut reference = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut reference, &program);
+        mem.mmu.allocate_page(0x2000);
+        single_cycle::run(&mut reference, &mut mem, &mut SyscallIo::stdio())
+            .unwrap();
+
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        mem.mmu.allocate_page(0x2000);
+        let mut retired = Vec::new();
+        let mut on_retire = |info: RetireInfo| retired.push(info);
+        let exit_pc = run_with_retire(
+            &mut cpu,
+            &mut mem,
+            &mut SyscallIo::stdio(),
+            Some(&mut on_retire),
+        )
+        .unwrap();
+
+        // Exactly the committed instructions, in program order
+        assert_eq!(retired.len() as i32, reference.history.inst_count);
+        assert_eq!(retired.len() as i32, cpu.history.inst_count);
+        assert_eq!(retired.last().unwrap().pc, exit_pc);
+        assert!(retired.iter().all(|info| info.pc != PROGRAM_BASE + 24));
+        let stores: Vec<_> =
+            retired.iter().filter_map(|info| info.mem_write).collect();
+        assert_eq!(
+            stores,
+            [
+                (0x2000, 4, 4),
+                (0x2000, 3, 4),
+                (0x2000, 2, 4),
+                (0x2000, 1, 4)
+            ]
+        );
+        assert_eq!(retired[1].reg_write, Some((5, 4)));
+        assert_eq!(retired[2].raw_inst, 0x00532023);
+        assert_eq!(retired[2].reg_write, None);
+    }
+
+    #[test]
+    fn test_back_to_back_branches() {
+        // Predicted taken at first, but not taken
+        run_both(&[
+            0x00100293, // addi t0, x0, 1
+            0x00028663, // beq t0, x0, 12
+            0x00029663, // bne t0, x0, 12
+            0x00100513, // addi a0, x0, 1
+            0x00200513, // addi a0, x0, 2
+            0x00550593, // addi a1, a0, 5
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ]);
+        // Taken, mispredicted when always predicting not taken
+        run_both(&[
+            0x00100293, // addi t0, x0, 1
+            0x00029463, // bne t0, x0, 8
+            0x00000663, // beq x0, x0, 12
+            0x00028463, // beq t0, x0, 8
+            0x00700513, // addi a0, x0, 7
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ]);
+    }
+
+    #[test]
+    fn test_programs_match_single_cycle() {
+        for entry in std::fs::read_dir("test-riscv").unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|ext| ext == "riscv") {
+                run_both_elf(path.to_str().unwrap());
+            }
+        }
     }
 }
@@ -1,4 +1,5 @@
 //! Pipeline state
+use crate::error::{MemoryError, SimulatorError};
 use crate::instruction::Instruction;
 
 /// Pipeline state = 4 pipeline registers
@@ -11,19 +12,58 @@ pub struct PipelineState {
 }
 
 impl PipelineState {
+    /// Replaces the instruction in IF/ID with a bubble
+    pub fn flush_if_id(&mut self) {
+        use crate::instruction::NOP;
+        self.if_id.raw_inst = NOP;
+        self.if_id.valid = false;
+        self.if_id.fault = None;
+    }
+
+    /// Replaces the instruction in ID/EX with a bubble
+    pub fn flush_id_ex(&mut self) {
+        self.id_ex.inst = Instruction::default();
+        self.id_ex.valid = false;
+        self.id_ex.fault = None;
+    }
+
+    /// Whether all four pipeline registers hold bubbles,
+    /// i.e., nothing is in flight, not even a NOP
+    pub fn is_drained(&self) -> bool {
+        !self.if_id.valid
+            && !self.id_ex.valid
+            && !self.ex_mem.valid
+            && !self.mem_wb.valid
+    }
+
     /// Load-use hazard
     /// Let's modify the definition a little bit:
     /// We care about those instructions where the
     /// write-back result is determined AFTER the MEM stage.
-    /// Thus we'll include LUI, AUIPC, JAL, JALR additionally.
+    /// Thus we'll include JAL, JALR additionally.
     pub fn load_hazard(&self) -> bool {
         use crate::instruction::Opcode::*;
         match self.id_ex.inst.opcode {
-            Lui | AuiPc | Jal | Jalr | Load => {
-                let if_id_inst = Instruction::new(self.if_id.raw_inst);
+            // Nothing waits on a result destined for x0
+            _ if !writes_register(&self.id_ex.inst) => false,
+            // A bubble depends on nothing
+            Jal | Jalr | Load | Amo if !self.if_id.valid => false,
+            Jal | Jalr | Load | Amo => {
+                // An invalid instruction is reported in the ID stage
+                let Ok(if_id_inst) = Instruction::try_new(self.if_id.raw_inst)
+                else {
+                    return false;
+                };
+                // A NOP never uses its operands
+                if if_id_inst.is_nop() {
+                    return false;
+                }
+                // Store data is forwarded in the MEM stage instead
+                let rs2_used = !matches!(if_id_inst.opcode, Store | Amo);
                 if_id_inst.attributes.rs1 == self.id_ex.inst.attributes.rd
-                    || if_id_inst.attributes.rs2
-                        == self.id_ex.inst.attributes.rd
+                    || (rs2_used
+                        && if_id_inst.attributes.rs2
+                            == self.id_ex.inst.attributes.rd)
             }
             _ => false,
         }
@@ -32,16 +72,18 @@ impl PipelineState {
     /// Operand 1 can be forwarded from previous execution result
     /// See P&H p. 300
     pub fn ex_hazard_op1(&self) -> bool {
-        self.id_ex.inst.attributes.rs1 != Some(0)
-            && self.ex_mem.inst.controls.reg_write
+        !self.id_ex.inst.is_nop()
+            && self.id_ex.inst.attributes.rs1 != Some(0)
+            && writes_register(&self.ex_mem.inst)
             && self.ex_mem.inst.attributes.rd == self.id_ex.inst.attributes.rs1
     }
 
     /// Operand 2 can be forwarded from previous execution result
     /// See P&H p. 300
     pub fn ex_hazard_op2(&self) -> bool {
-        self.id_ex.inst.attributes.rs2 != Some(0)
-            && self.ex_mem.inst.controls.reg_write
+        !self.id_ex.inst.is_nop()
+            && self.id_ex.inst.attributes.rs2 != Some(0)
+            && writes_register(&self.ex_mem.inst)
             && self.ex_mem.inst.attributes.rd == self.id_ex.inst.attributes.rs2
     }
 
@@ -49,8 +91,9 @@ impl PipelineState {
     /// Precontidion: ex_hazard_op1 is false
     /// See P&H p. 301
     pub fn mem_hazard_op1(&self) -> bool {
-        self.id_ex.inst.attributes.rs1 != Some(0)
-            && self.mem_wb.inst.controls.reg_write
+        !self.id_ex.inst.is_nop()
+            && self.id_ex.inst.attributes.rs1 != Some(0)
+            && writes_register(&self.mem_wb.inst)
             && self.mem_wb.inst.attributes.rd == self.id_ex.inst.attributes.rs1
     }
 
@@ -58,17 +101,27 @@ impl PipelineState {
     /// Precontidion: ex_hazard_op2 is false
     /// See P&H p. 301
     pub fn mem_hazard_op2(&self) -> bool {
-        self.id_ex.inst.attributes.rs2 != Some(0)
-            && self.mem_wb.inst.controls.reg_write
+        !self.id_ex.inst.is_nop()
+            && self.id_ex.inst.attributes.rs2 != Some(0)
+            && writes_register(&self.mem_wb.inst)
             && self.mem_wb.inst.attributes.rd == self.id_ex.inst.attributes.rs2
     }
 
+    /// Store data can be forwarded from the previous memory access result,
+    /// which is how a store gets data loaded right before it
+    pub fn store_data_hazard(&self) -> bool {
+        self.ex_mem.inst.controls.mem_write
+            && self.ex_mem.inst.attributes.rs2 != Some(0)
+            && writes_register(&self.mem_wb.inst)
+            && self.mem_wb.inst.attributes.rd == self.ex_mem.inst.attributes.rs2
+    }
+
     /// Operand 1 was just written
     /// Must forward this explicitly due to load-use hazard
     /// See P&H p. 301
     pub fn wb_hazard_op1(&self, inst: &Instruction) -> bool {
         inst.attributes.rs1 != Some(0)
-            && self.mem_wb.inst.controls.reg_write
+            && writes_register(&self.mem_wb.inst)
             && inst.attributes.rs1 == self.mem_wb.inst.attributes.rd
     }
 
@@ -77,11 +130,42 @@ impl PipelineState {
     /// See P&H p. 301
     pub fn wb_hazard_op2(&self, inst: &Instruction) -> bool {
         inst.attributes.rs2 != Some(0)
-            && self.mem_wb.inst.controls.reg_write
+            && writes_register(&self.mem_wb.inst)
             && inst.attributes.rs2 == self.mem_wb.inst.attributes.rd
     }
 }
 
+/// Whether the instruction produces a value worth forwarding.
+/// A write to x0 (e.g., `lw x0, ...`) is discarded by write-back,
+/// so its value must never reach a dependent instruction
+fn writes_register(inst: &Instruction) -> bool {
+    inst.controls.reg_write && inst.attributes.rd != Some(0)
+}
+
+/// Fault in fetching or decoding an instruction,
+/// which may be on the wrong path of a branch or jump.
+/// It's held in the pipeline registers, in place of a NOP,
+/// and raised only once the instruction reaches EX unflushed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PendingFault {
+    /// The fetch failed
+    Fetch(MemoryError),
+    /// The raw instruction fetched can't be decoded
+    Decode(u32),
+}
+
+impl PendingFault {
+    /// The error to raise for the instruction at the PC
+    pub fn to_error(self, pc: u32) -> SimulatorError {
+        match self {
+            PendingFault::Fetch(e) => SimulatorError::Memory(e),
+            PendingFault::Decode(raw_inst) => {
+                SimulatorError::InvalidInstructionAt(pc, raw_inst)
+            }
+        }
+    }
+}
+
 /// IF/ID register
 #[derive(Clone, Copy)]
 pub struct IFIDRegister {
@@ -90,6 +174,12 @@ pub struct IFIDRegister {
 
     /// Raw instruction
     pub raw_inst: u32,
+
+    /// Whether this holds a fetched instruction rather than a bubble
+    pub valid: bool,
+
+    /// Fault in fetching the instruction, if any
+    pub fault: Option<PendingFault>,
 }
 
 impl Default for IFIDRegister {
@@ -98,12 +188,14 @@ impl Default for IFIDRegister {
         Self {
             pc: 0,
             raw_inst: NOP,
+            valid: false,
+            fault: None,
         }
     }
 }
 
 /// ID/EX register
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct IDEXRegister {
     /// Program counter
     pub pc: u32,
@@ -118,22 +210,16 @@ pub struct IDEXRegister {
 
     /// PC if branch is taken
     pub taken_pc: Option<u32>,
-}
 
-impl Default for IDEXRegister {
-    fn default() -> Self {
-        Self {
-            pc: 0,
-            inst: Instruction::default(),
-            op1: 0,
-            op2: 0,
-            taken_pc: None,
-        }
-    }
+    /// Whether this holds a fetched instruction rather than a bubble
+    pub valid: bool,
+
+    /// Fault in fetching or decoding the instruction, if any
+    pub fault: Option<PendingFault>,
 }
 
 /// EX/MEM register
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct EXMEMRegister {
     /// Program counter
     pub pc: u32,
@@ -150,25 +236,16 @@ pub struct EXMEMRegister {
     /// PC if branch is taken
     pub taken_pc: Option<u32>,
 
-    /// PC of exitting
+    /// PC of exitting,
+    /// set iff the instruction is an exit system call
     pub exit_pc: Option<u32>,
-}
 
-impl Default for EXMEMRegister {
-    fn default() -> Self {
-        Self {
-            pc: 0,
-            inst: Instruction::default(),
-            exec_result: 0,
-            op2: 0,
-            taken_pc: None,
-            exit_pc: None,
-        }
-    }
+    /// Whether this holds a fetched instruction rather than a bubble
+    pub valid: bool,
 }
 
 /// MEM/WB register
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Default)]
 pub struct MEMWBRegister {
     /// Program counter
     pub pc: u32,
@@ -185,14 +262,15 @@ pub struct MEMWBRegister {
     /// Actual write back result,
     /// which is computed during the MEM stage
     pub wb_result: u32,
-}
 
-impl Default for MEMWBRegister {
-    fn default() -> Self {
-        Self {
-            pc: 0,
-            inst: Instruction::default(),
-            wb_result: 0,
-        }
-    }
+    /// PC of exitting,
+    /// carried over from the EX/MEM register
+    pub exit_pc: Option<u32>,
+
+    /// Memory written during the MEM stage,
+    /// as (address, value, size in bytes)
+    pub mem_write: Option<(u32, u32, u32)>,
+
+    /// Whether this holds a fetched instruction rather than a bubble
+    pub valid: bool,
 }
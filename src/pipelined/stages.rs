@@ -1,24 +1,34 @@
 //! 5 stages adapted for piplined execution
 
-use super::pipeline::PipelineState;
+use super::pipeline::{PendingFault, PipelineState};
+use super::RetireInfo;
 use crate::cpu::CPUState;
-use crate::instruction::Opcode;
+use crate::error::{SimulatorError, SimulatorResult};
+use crate::instruction::{compressed, Function, Instruction, Opcode, NOP};
 use crate::memory::StorageInterface;
 use crate::stages_simple;
+use crate::system_call::SyscallIo;
+use std::io::{BufRead, Write};
 
 /// IF stage
 pub fn instruction_fetch(
     cpu: &mut CPUState,
     mem: &mut impl StorageInterface,
     next_state: &mut PipelineState,
-) {
-    // Increment PC by 4
+) -> SimulatorResult<()> {
+    // Fetch the raw instruction.
+    // A failed fetch may be on the wrong path, so it's held as a NOP
+    // until it reaches EX, and fetching goes on past it
     let pc = cpu.pc.read();
-    let new_pc = pc + 4;
-    cpu.pc.write(new_pc);
+    let (raw_inst, fault) = match stages_simple::instruction_fetch(pc, cpu, mem)
+    {
+        Ok(raw_inst) => (raw_inst, None),
+        Err(SimulatorError::Memory(e)) => (NOP, Some(PendingFault::Fetch(e))),
+        Err(e) => return Err(e),
+    };
 
-    // Fetch the raw instruction
-    let raw_inst = stages_simple::instruction_fetch(pc, cpu, mem);
+    // Increment PC past it, wrapping around like the single-cycle one
+    cpu.pc.write(pc.wrapping_add(compressed::length(raw_inst)));
 
     if cpu.policy.verbose {
         // Print the PC and the raw instruction
@@ -28,6 +38,10 @@ pub fn instruction_fetch(
     // Update IF/ID register
     next_state.if_id.pc = pc;
     next_state.if_id.raw_inst = raw_inst;
+    next_state.if_id.valid = true;
+    next_state.if_id.fault = fault;
+
+    Ok(())
 }
 
 /// ID stage
@@ -35,42 +49,56 @@ pub fn instruction_decode(
     cpu: &CPUState,
     current_state: &PipelineState,
     next_state: &mut PipelineState,
-) {
+) -> SimulatorResult<()> {
     // Fetch the raw instruction
     let raw_inst = current_state.if_id.raw_inst;
 
-    // Decode the instruction
-    let inst = stages_simple::instruction_decode(raw_inst);
+    // Decode the instruction; bubbles are always NOPs,
+    // and so are faulting instructions, held like a failed fetch
+    let pc = current_state.if_id.pc;
+    let mut fault = current_state.if_id.fault;
+    let inst = match current_state.if_id.valid {
+        true => match stages_simple::instruction_decode(pc, raw_inst) {
+            Ok(inst) => inst,
+            Err(SimulatorError::InvalidInstructionAt(_, raw_inst)) => {
+                fault = Some(PendingFault::Decode(raw_inst));
+                Instruction::default()
+            }
+            Err(e) => return Err(e),
+        },
+        false => Instruction::default(),
+    };
 
     // WB hazard -> Data in the register
-    let op1: i32;
-    if current_state.wb_hazard_op1(&inst) {
-        op1 = current_state.mem_wb.wb_result as i32;
+    let op1 = if current_state.wb_hazard_op1(&inst) {
+        current_state.mem_wb.wb_result as i32
     } else {
-        op1 = cpu.gpr[inst.attributes.rs1.unwrap_or(0) as usize].read() as i32;
-    }
+        cpu.read_reg(inst.attributes.rs1.unwrap_or(0) as usize) as i32
+    };
 
-    let op2: i32;
-    if current_state.wb_hazard_op2(&inst) {
-        op2 = current_state.mem_wb.wb_result as i32;
+    let op2 = if current_state.wb_hazard_op2(&inst) {
+        current_state.mem_wb.wb_result as i32
     } else {
-        op2 = cpu.gpr[inst.attributes.rs2.unwrap_or(0) as usize].read() as i32;
-    }
+        cpu.read_reg(inst.attributes.rs2.unwrap_or(0) as usize) as i32
+    };
 
-    let pc = current_state.if_id.pc;
     next_state.id_ex.pc = pc;
     next_state.id_ex.inst = inst;
+    next_state.id_ex.valid = current_state.if_id.valid;
+    next_state.id_ex.fault = fault;
     next_state.id_ex.op1 = op1;
     next_state.id_ex.op2 = op2;
 
     if inst.opcode == Opcode::Branch {
         // Also precompute the branch target when needed
-        let imm = inst.attributes.imm.unwrap() as i32;
-        next_state.id_ex.taken_pc = Some(((pc as i32) + imm) as u32);
+        let imm = inst.attributes.imm.unwrap();
+        next_state.id_ex.taken_pc = Some(pc.wrapping_add(imm));
     } else {
         // No precomputed branch target
         next_state.id_ex.taken_pc = None;
     }
+
+    Ok(())
 }
 
 /// EX stage
@@ -79,10 +107,16 @@ pub fn execute(
     mem: &mut impl StorageInterface,
     current_state: &PipelineState,
     next_state: &mut PipelineState,
-) {
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<()> {
     let pc = current_state.id_ex.pc;
     let inst = current_state.id_ex.inst;
 
+    // The instruction is on the right path after all
+    if let Some(fault) = current_state.id_ex.fault {
+        return Err(fault.to_error(pc));
+    }
+
     let op1: i32;
     // EX hazard -> MEM hazard -> Data in the register
     if current_state.ex_hazard_op1() {
@@ -103,13 +137,21 @@ pub fn execute(
         op2 = current_state.id_ex.op2;
     }
 
-    let exec_result = stages_simple::execute(cpu, mem, &inst, op1, op2);
+    let exec_result =
+        stages_simple::execute(pc, cpu, mem, &inst, op1, op2, io)?;
 
     next_state.ex_mem.pc = pc;
     next_state.ex_mem.inst = inst;
     next_state.ex_mem.exec_result = exec_result;
     next_state.ex_mem.op2 = op2;
     next_state.ex_mem.taken_pc = current_state.id_ex.taken_pc;
+    next_state.ex_mem.valid = current_state.id_ex.valid;
+
+    // Exit system call: a7 (op2) is already forwarded at this point
+    let is_exit = inst.opcode == Opcode::System && cpu.policy.is_exit(op2);
+    next_state.ex_mem.exit_pc = is_exit.then_some(pc);
+
+    Ok(())
 }
 
 /// MEM stage
@@ -118,23 +160,65 @@ pub fn memory_access(
     mem: &mut impl StorageInterface,
     current_state: &PipelineState,
     next_state: &mut PipelineState,
-) {
+) -> SimulatorResult<()> {
     let pc = current_state.ex_mem.pc;
     let inst = current_state.ex_mem.inst;
     let exec_result = current_state.ex_mem.exec_result;
-    let op2 = current_state.ex_mem.op2;
+    // Store data hazard -> Operand 2 from MEM/WB
+    let op2 = if current_state.store_data_hazard() {
+        current_state.mem_wb.wb_result as i32
+    } else {
+        current_state.ex_mem.op2
+    };
 
     next_state.mem_wb.pc = pc;
     next_state.mem_wb.inst = inst;
     next_state.mem_wb.wb_result =
-        stages_simple::memory_access(pc, &inst, cpu, mem, exec_result, op2);
+        stages_simple::memory_access(pc, &inst, cpu, mem, exec_result, op2)?;
+    next_state.mem_wb.exit_pc = current_state.ex_mem.exit_pc;
+    next_state.mem_wb.valid = current_state.ex_mem.valid;
+    // A failed SC writes nothing
+    let stored = inst.controls.mem_write
+        && !(inst.function == Function::SCW
+            && next_state.mem_wb.wb_result != 0);
+    next_state.mem_wb.mem_write = stored.then(|| {
+        let step = inst.controls.mem_step;
+        let value = match step {
+            4 => op2 as u32,
+            _ => op2 as u32 & ((1 << (8 * step)) - 1),
+        };
+        (exec_result as u32, value, step)
+    });
+
+    Ok(())
 }
 
-/// WB stage
-pub fn write_back(cpu: &mut CPUState, current_state: &PipelineState) {
+/// WB stage, where instructions retire in program order.
+/// Bubbles never reach on_retire
+pub fn write_back(
+    cpu: &mut CPUState,
+    current_state: &PipelineState,
+    on_retire: Option<&mut dyn FnMut(RetireInfo)>,
+) {
+    // A bubble is not part of the program
+    if !current_state.mem_wb.valid {
+        return;
+    }
     let pc = current_state.mem_wb.pc;
     let inst = current_state.mem_wb.inst;
     let wb_result = current_state.mem_wb.wb_result;
 
     stages_simple::write_back(pc, &inst, cpu, wb_result);
+
+    let Some(on_retire) = on_retire else {
+        return;
+    };
+    let rd = inst.attributes.rd.unwrap_or(0) as usize;
+    on_retire(RetireInfo {
+        pc,
+        raw_inst: inst.raw_inst,
+        reg_write: (inst.controls.reg_write && rd != 0)
+            .then_some((rd, wb_result)),
+        mem_write: current_state.mem_wb.mem_write,
+    });
 }
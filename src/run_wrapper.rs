@@ -1,50 +1,277 @@
 //! A simulator wrapper
 
-use crate::cpu::{CPUPolicy, CPUState, Implementation};
+use crate::cpu::{CPUHistory, CPUPolicy, CPUState, CacheMode, Implementation};
 use crate::elf_helper;
+use crate::error::{MemoryError, SimulatorError, SimulatorResult};
+use crate::instruction::{self, compressed, Instruction, Opcode};
 use crate::loader;
+use crate::memory::cache::CacheHistory;
 use crate::memory::inclusive::InclusiveCache;
+use crate::memory::mmio::{ConsoleDevice, TimerDevice, TIMER_SIZE};
+use crate::memory::mmu::MMU;
+use crate::memory::passthrough::PassthroughMemory;
+use crate::memory::trace::TraceRecorder;
 use crate::memory::StorageInterface;
+use crate::multi_cycle;
 use crate::pipelined;
 use crate::single_cycle;
+use crate::stages_simple;
+use crate::system_call::SyscallIo;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufWriter, Write};
 
-const STACK_BASE: u32 = 0x80000000;
-const STACK_SIZE: u32 = 0x400000;
+/// Where the stack of a simulated program starts, growing downwards
+pub const STACK_BASE: u32 = 0x80000000;
+/// Size of the stack of a simulated program
+pub const STACK_SIZE: u32 = 0x400000;
 
 // (Ideal CPI, CPI, CPI (no caching), (CPI(no caching) / CPI))
 type RunStats = (f64, f64, f64, f64);
 
+/// Statistics of a run, including those of the memory
+pub struct RunReport {
+    pub stats: RunStats,
+    pub instruction_count: i32,
+    pub cache_history: Vec<CacheHistory>,
+    /// Computed only when asked for
+    pub amat: Option<f64>,
+    /// Hash of the final architectural state, see CPUState::state_hash
+    pub state_hash: u64,
+}
+
+/// Static instruction counts of a program
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgramStats {
+    /// Words in the executable segments that decode
+    pub inst_count: u64,
+    /// Words in the executable segments that don't, e.g., embedded data
+    pub invalid_count: u64,
+    /// Instructions with each opcode
+    pub opcode_counts: HashMap<Opcode, u64>,
+}
+
+impl ProgramStats {
+    /// Number of instructions with the given opcode
+    pub fn count(&self, opcode: Opcode) -> u64 {
+        self.opcode_counts.get(&opcode).copied().unwrap_or(0)
+    }
+}
+
+/// Counts the instructions of the given ELF file without running it,
+/// decoding the executable loadable segments word by word.
+/// Compressed instructions aren't told apart from the words they're in
+pub fn analyze(elf_file: &str) -> SimulatorResult<ProgramStats> {
+    use object::read::elf::ProgramHeader;
+    let (elf_reader, elf_data) = elf_helper::parse_elf_file(elf_file)?;
+    let endian = elf_helper::get_elf_endian(&elf_reader)?;
+
+    let mut stats = ProgramStats::default();
+    for segment in elf_helper::get_elf_segments(&elf_reader, &elf_data)? {
+        if segment.p_type(endian) != object::elf::PT_LOAD
+            || segment.p_flags(endian) & object::elf::PF_X == 0
+        {
+            continue;
+        }
+        // Only the bytes in the file; the rest is zeros
+        let address = segment.p_vaddr(endian);
+        let offset = segment.p_offset(endian) as usize;
+        let size = segment.p_filesz(endian) as usize;
+        let bytes = elf_data.get(offset..offset + size).ok_or_else(|| {
+            SimulatorError::InvalidFormat(format!(
+                "Segment at {:#010x} extends past the end of the file",
+                address
+            ))
+        })?;
+        for (_, inst) in instruction::decode_all(bytes, address) {
+            match inst {
+                Ok(inst) => {
+                    stats.inst_count += 1;
+                    *stats.opcode_counts.entry(inst.opcode).or_default() += 1;
+                }
+                Err(_) => stats.invalid_count += 1,
+            }
+        }
+    }
+    Ok(stats)
+}
+
 /// Run simulation on the given ELF file
-/// and return the exit PC
+/// with the given program arguments (excluding argv[0])
 pub fn run(
     elf_file: &str,
+    args: &[&str],
     policy: CPUPolicy,
 ) -> Result<RunStats, Box<dyn std::error::Error>> {
+    run_with_io(elf_file, args, policy, &mut SyscallIo::stdio())
+}
+
+/// Run simulation on the given ELF file,
+/// with system calls reading from and printing to the given io
+pub fn run_with_io(
+    elf_file: &str,
+    args: &[&str],
+    policy: CPUPolicy,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> Result<RunStats, Box<dyn std::error::Error>> {
+    let elf_bytes = std::fs::read(elf_file)?;
+    let argv = argv_of(elf_file, args);
+    Ok(run_bytes_with_io(&elf_bytes, &argv, policy, io)?)
+}
+
+/// Run simulation on the given ELF file,
+/// reporting the statistics of the memory as well
+pub fn run_with_report(
+    elf_file: &str,
+    args: &[&str],
+    policy: CPUPolicy,
+) -> Result<RunReport, Box<dyn std::error::Error>> {
+    let elf_bytes = std::fs::read(elf_file)?;
+    let argv = argv_of(elf_file, args);
+    let mut io = SyscallIo::stdio();
+    Ok(run_elf(&elf_bytes, &argv, policy, &mut io, true)?)
+}
+
+/// Program arguments with the ELF file as argv[0]
+fn argv_of<'a>(elf_file: &'a str, args: &[&'a str]) -> Vec<&'a str> {
+    std::iter::once(elf_file)
+        .chain(args.iter().copied())
+        .collect()
+}
+
+/// Run simulation on the given content of an ELF file
+/// with the given program arguments, including argv[0]
+pub fn run_bytes(
+    elf_bytes: &[u8],
+    argv: &[&str],
+    policy: CPUPolicy,
+) -> SimulatorResult<RunStats> {
+    run_bytes_with_io(elf_bytes, argv, policy, &mut SyscallIo::stdio())
+}
+
+/// Run simulation on the given content of an ELF file,
+/// with system calls reading from and printing to the given io.
+/// A recorded trace is written next to argv[0]
+pub fn run_bytes_with_io(
+    elf_bytes: &[u8],
+    argv: &[&str],
+    policy: CPUPolicy,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<RunStats> {
+    Ok(run_elf(elf_bytes, argv, policy, io, false)?.stats)
+}
+
+/// Run simulation on the given content of an ELF file,
+/// computing the AMAT if with_amat is set
+fn run_elf(
+    elf_bytes: &[u8],
+    argv: &[&str],
+    policy: CPUPolicy,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+    with_amat: bool,
+) -> SimulatorResult<RunReport> {
+    policy.validate()?;
+
     // Load the ELF file
-    let (elf_reader, elf_data_origin) = elf_helper::parse_elf_file(elf_file)?;
+    let (elf_reader, elf_data_origin) =
+        elf_helper::parse_elf_bytes(elf_bytes.to_vec())?;
     let elf_data = &elf_data_origin;
 
     let mut cpu = CPUState::make(policy);
+    let trace = policy
+        .record_trace
+        .then(|| TraceRecorder::make(policy.trace_fetches));
 
-    let mut mem = InclusiveCache::default();
-    // let mut mem = ExclusiveCache::default();
+    match policy.cache {
+        CacheMode::Inclusive => {
+            let mut mem = InclusiveCache::default();
+            // let mut mem = ExclusiveCache::default();
+            mem.trace = trace;
+            let report = run_program(
+                &mut cpu,
+                &mut mem,
+                &elf_reader,
+                elf_data,
+                argv,
+                io,
+                with_amat,
+            )?;
+            // mem.verify_exclusiveness();
+            mem.verify_inclusiveness();
+            Ok(report)
+        }
+        CacheMode::Passthrough => {
+            let mut mem = PassthroughMemory {
+                trace,
+                ..Default::default()
+            };
+            run_program(
+                &mut cpu,
+                &mut mem,
+                &elf_reader,
+                elf_data,
+                argv,
+                io,
+                with_amat,
+            )
+        }
+    }
+}
+
+/// Pushes the program arguments (including argv[0]) onto the stack,
+/// unless there are none past argv[0], which leaves SP at the stack base
+fn push_argv(cpu: &mut CPUState, mmu: &mut MMU, argv: &[&str]) {
+    if argv.len() > 1 {
+        loader::setup_argv(cpu, mmu, argv);
+    }
+}
+
+/// Loads the ELF data into the given memory and runs the CPU on it,
+/// with the given program arguments, including argv[0]
+fn run_program(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    elf_reader: &elf_helper::ELFReaderType,
+    elf_data: &[u8],
+    argv: &[&str],
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+    with_amat: bool,
+) -> SimulatorResult<RunReport> {
+    let policy = cpu.policy;
     {
         // Borrow the MMU for initialization
-        let mmu = &mut mem.mmu;
+        let mmu = mem.mmu();
+        mmu.poison = policy.poison;
+        if let Some(base) = policy.console {
+            mmu.add_device(base, 1, Box::new(ConsoleDevice::stdio()));
+        }
+        if let Some(base) = policy.timer {
+            mmu.add_device(base, TIMER_SIZE, Box::<TimerDevice>::default());
+        }
         // Set stack
-        loader::set_stack(&mut cpu, mmu, STACK_BASE, STACK_SIZE);
+        loader::set_stack(cpu, mmu, STACK_BASE, STACK_SIZE);
         // Load ELF data into memory
-        loader::load_elf(&mut cpu, mmu, &elf_reader, elf_data)?;
+        loader::load_elf(cpu, mmu, elf_reader, elf_data)?;
+        // Push argv
+        push_argv(cpu, mmu, argv);
     }
 
     // Run the CPU
-    match policy.implementation {
-        Implementation::SingleCycle => single_cycle::run(&mut cpu, &mut mem),
-        Implementation::Pipelined => pipelined::run(&mut cpu, &mut mem),
-    };
+    simulate(cpu, mem, io)?;
+
+    if let Some(trace) = mem.trace() {
+        let trace_path = format!("{}.trace", argv.first().unwrap_or(&"a.out"));
+        trace.write(&mut BufWriter::new(File::create(&trace_path)?))?;
+        eprintln!(
+            "[TRACE] Wrote {} accesses to {}",
+            trace.accesses.len(),
+            trace_path
+        );
+    }
 
-    // mem.verify_exclusiveness();
-    mem.verify_inclusiveness();
+    if policy.dump_regs {
+        eprint!("{}", cpu.format_registers());
+    }
 
     let cycle_count_base = cpu.history.cycle_count;
     let cycle_count = cycle_count_base + cpu.history.mem_stall_count;
@@ -58,56 +285,838 @@ pub fn run(
     if policy.history {
         eprintln!("[HISTORY] # instructions = {}", instruction_count);
         eprintln!("[HISTORY] CPI = {:.2}, CPI (no caching) = {:.2}, CPI (ideal) = {:.2}", cpi, cpi_worst, cpi_ideal);
-        eprintln!("[HISTORY] {:?}", mem.get_history());
-        eprintln!("[HISTORY] AMAT = {:.2}", mem.get_amat());
+        let mix = cpu.history.mix;
+        eprintln!(
+            "[HISTORY] Loads = {}, stores = {}, branches = {}, jumps = {}, ALU = {}, system = {}, atomics = {}",
+            mix.loads, mix.stores, mix.branches, mix.jumps, mix.alu, mix.system, mix.atomics
+        );
+        eprintln!(
+            "[HISTORY] Peak stack usage = {} bytes",
+            cpu.peak_stack_usage()
+        );
+        if policy.single_port {
+            eprintln!(
+                "[HISTORY] Structural stalls = {}",
+                cpu.history.structural_stall_count
+            );
+        }
+    }
+    let state_hash = cpu.state_hash(mem.mmu());
+    if policy.history {
+        eprintln!("[HISTORY] State hash = {:#018x}", state_hash);
+    }
+    let cache_history = mem.get_history();
+    let amat = (policy.history || with_amat).then(|| mem.get_amat());
+    if policy.history {
+        eprintln!("[HISTORY] {:?}", cache_history);
+        eprintln!("[HISTORY] AMAT = {:.2}", amat.unwrap());
     }
 
-    Ok((cpi_ideal, cpi, cpi_worst, cpi_worst / cpi))
+    Ok(RunReport {
+        stats: (cpi_ideal, cpi, cpi_worst, cpi_worst / cpi),
+        instruction_count,
+        cache_history,
+        amat,
+        state_hash,
+    })
 }
 
-/// Fetch operations from the trace file
-pub fn fetch_operations(trace_path: &str) -> Vec<(char, u32)> {
-    let mut operations: Vec<(char, u32)> = Vec::new();
-    for line in std::fs::read_to_string(trace_path).unwrap().lines() {
-        // Parse the line into op and address
-        let (op, address_str): (char, String);
-        text_io::scan!(line.bytes() => "{} {}", op, address_str);
+/// Appends a row with the statistics of the given program to a CSV file,
+/// writing the header first if the file is new
+pub fn append_stats_csv(
+    path: &str,
+    program: &str,
+    report: &RunReport,
+) -> SimulatorResult<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    let is_new = file.metadata()?.len() == 0;
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(file);
 
+    if is_new {
+        let mut header: Vec<String> = [
+            "Program",
+            "Instructions",
+            "CPI (ideal)",
+            "CPI (caching)",
+            "CPI (no caching)",
+            "Ratio",
+        ]
+        .iter()
+        .map(|field| field.to_string())
+        .collect();
+        for k in 1..=report.cache_history.len() {
+            header.push(format!("L{} hits", k));
+            header.push(format!("L{} misses", k));
+        }
+        header.push("AMAT".to_string());
+        writer.write_record(&header)?;
+    }
+
+    let (cpi_ideal, cpi, cpi_worst, ratio) = report.stats;
+    let mut row = vec![
+        program.to_string(),
+        report.instruction_count.to_string(),
+        format!("{:.3}", cpi_ideal),
+        format!("{:.3}", cpi),
+        format!("{:.3}", cpi_worst),
+        format!("{:.3}", ratio),
+    ];
+    for history in report.cache_history.iter() {
+        row.push(history.num_hit.to_string());
+        row.push(history.num_miss.to_string());
+    }
+    row.push(
+        report
+            .amat
+            .map_or(String::new(), |amat| format!("{:.3}", amat)),
+    );
+    writer.write_record(&row)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Runs the CPU on the backend given by its policy,
+/// after functionally executing the first `fast_forward` instructions.
+/// The caches stay warm across the handoff,
+/// whereas the histories only cover the detailed simulation.
+/// Returns the exiting PC address
+pub fn simulate(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<u32> {
+    if cpu.policy.fast_forward > 0 {
+        let count = cpu.policy.fast_forward;
+        if let Some(exit_pc) = single_cycle::fast_forward(cpu, mem, io, count)?
+        {
+            return Ok(exit_pc);
+        }
+        // Keep the lowest SP seen, which is a property of the program
+        cpu.history = CPUHistory {
+            min_sp: cpu.history.min_sp,
+            ..Default::default()
+        };
+        mem.reset_history();
+    }
+
+    match cpu.policy.implementation {
+        Implementation::SingleCycle => single_cycle::run(cpu, mem, io),
+        Implementation::MultiCycle => multi_cycle::run(cpu, mem, io),
+        Implementation::Pipelined => pipelined::run(cpu, mem, io),
+    }
+}
+
+/// Decodes the instruction at the given PC for debugging,
+/// reading the MMU directly so that no cache or statistic is touched
+pub fn peek_instruction(mmu: &MMU, pc: u32) -> SimulatorResult<Instruction> {
+    let byte = |address: u32| {
+        mmu.try_get8(address)
+            .ok_or(MemoryError::ReadUnallocated(address))
+    };
+    let mut raw_inst = byte(pc)? as u32 | (byte(pc + 1)? as u32) << 8;
+    if !compressed::is_compressed(raw_inst) {
+        raw_inst |= (byte(pc + 2)? as u32) << 16 | (byte(pc + 3)? as u32) << 24;
+    }
+    stages_simple::instruction_decode(pc, raw_inst)
+}
+
+/// Parses trace lines of the form "OP 0xADDR [SIZE]",
+/// where OP is r(ead), w(rite) or x (fetch) and SIZE defaults to 1
+pub fn parse_operations(trace: &str) -> Vec<(char, u32, u32)> {
+    let mut operations: Vec<(char, u32, u32)> = Vec::new();
+    for line in trace.lines() {
+        let mut tokens = line.split_whitespace();
+        let (op, address_str) = match (tokens.next(), tokens.next()) {
+            (Some(op), Some(address_str)) => (op, address_str),
+            _ => continue,
+        };
+        let op = op.chars().next().unwrap();
         let address = u32::from_str_radix(&address_str[2..], 16).unwrap();
-        operations.push((op, address));
+        let size = tokens.next().map_or(1, |size| size.parse().unwrap());
+        operations.push((op, address, size));
     }
     operations
 }
 
-/// Run simulation on the given trace file
-pub fn run_trace(cache: &mut impl StorageInterface, trace_path: &str) -> f64 {
-    let operations = fetch_operations(trace_path);
+/// Fetch operations from the trace file
+pub fn fetch_operations(trace_path: &str) -> Vec<(char, u32, u32)> {
+    parse_operations(&std::fs::read_to_string(trace_path).unwrap())
+}
 
+/// Replays the given operations on the cache
+pub fn replay_operations(
+    cache: &mut impl StorageInterface,
+    operations: &[(char, u32, u32)],
+) {
     {
         // Borrow the MMU for initialization
         let mmu = &mut cache.mmu();
         // Allocate pages beforehand
-        for (_, address) in &operations {
+        for (_, address, size) in operations {
             mmu.allocate_page(*address);
+            mmu.allocate_page(address + size - 1);
         }
     }
 
     // Simulate the trace
-    for (op, address) in &operations {
+    for (op, address, size) in operations {
         let mut dummy: Option<i32> = Some(0);
+        let mut dummy_worst: Option<i32> = Some(0);
         match op {
+            // Pages are allocated beforehand
             'r' => {
-                cache.get8(*address, &mut dummy);
+                cache
+                    .get(*address, *size, &mut dummy, &mut dummy_worst)
+                    .unwrap();
             }
             'w' => {
-                cache.set8(*address, 0, &mut dummy);
+                cache
+                    .set(*address, *size, 0, &mut dummy, &mut dummy_worst)
+                    .unwrap();
+            }
+            'x' => {
+                cache.fetch(*address, &mut dummy, &mut dummy_worst).unwrap();
             }
             _ => {
                 panic!("Unknown operation");
             }
         }
     }
+}
+
+/// Run simulation on the given trace file
+pub fn run_trace(cache: &mut impl StorageInterface, trace_path: &str) -> f64 {
+    replay_operations(cache, &fetch_operations(trace_path));
 
     // Return the predicted AMAT
     cache.get_amat()
 }
+
+/// Replays the given operations on the cache,
+/// leaving the first warmup ones out of the histories
+pub fn replay_operations_warm(
+    cache: &mut impl StorageInterface,
+    operations: &[(char, u32, u32)],
+    warmup: usize,
+) {
+    let (warmup_operations, operations) =
+        operations.split_at(warmup.min(operations.len()));
+    replay_operations(cache, warmup_operations);
+    cache.reset_history();
+    replay_operations(cache, operations);
+}
+
+/// Run simulation on the given trace file,
+/// where the first warmup operations only warm the cache up
+pub fn run_trace_warm(
+    cache: &mut impl StorageInterface,
+    trace_path: &str,
+    warmup: usize,
+) -> f64 {
+    replay_operations_warm(cache, &fetch_operations(trace_path), warmup);
+
+    // Return the predicted AMAT
+    cache.get_amat()
+}
+
+/// Run simulation on each of the given trace files in turn.
+/// With reset_between, every trace starts on a cold cache;
+/// otherwise it inherits the blocks left by the previous ones.
+/// Returns the AMAT of each trace, followed by the aggregate AMAT,
+/// i.e., their average weighted by the number of operations.
+/// Fails without any trace files
+pub fn run_traces(
+    cache: &mut impl StorageInterface,
+    paths: &[&str],
+    reset_between: bool,
+) -> SimulatorResult<Vec<f64>> {
+    if paths.is_empty() {
+        return Err(SimulatorError::Other("No trace files given".to_string()));
+    }
+    let mut amats = Vec::new();
+    let (mut total_latency, mut total_count) = (0.0, 0);
+    for path in paths {
+        let operations = parse_operations(&std::fs::read_to_string(path)?);
+        match reset_between {
+            true => cache.reset(),
+            false => cache.reset_history(),
+        }
+        replay_operations(cache, &operations);
+
+        let amat = cache.get_amat();
+        total_latency += amat * operations.len() as f64;
+        total_count += operations.len();
+        amats.push(amat);
+    }
+    amats.push(total_latency / total_count as f64);
+    Ok(amats)
+}
+
+/// Counts the operations by bucket of the given size in bytes,
+/// returning (bucket base address, count) from the hottest bucket down.
+/// Fails on buckets of 0 bytes
+pub fn address_histogram(
+    operations: &[(char, u32, u32)],
+    granularity: u32,
+) -> SimulatorResult<Vec<(u32, u64)>> {
+    if granularity == 0 {
+        return Err(SimulatorError::Other(
+            "The histogram granularity must be positive".to_string(),
+        ));
+    }
+    let mut counts: HashMap<u32, u64> = HashMap::new();
+    for (_, address, _) in operations {
+        *counts
+            .entry(address / granularity * granularity)
+            .or_default() += 1;
+    }
+    let mut histogram: Vec<(u32, u64)> = counts.into_iter().collect();
+    histogram.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    Ok(histogram)
+}
+
+/// Run simulation on the given trace file,
+/// also returning the address histogram with the given granularity
+pub fn run_trace_with_histogram(
+    cache: &mut impl StorageInterface,
+    trace_path: &str,
+    granularity: u32,
+) -> SimulatorResult<(f64, Vec<(u32, u64)>)> {
+    let operations = fetch_operations(trace_path);
+    let histogram = address_histogram(&operations, granularity)?;
+    replay_operations(cache, &operations);
+    Ok((cache.get_amat(), histogram))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPUPolicy;
+    use crate::memory::cache::CachePolicy;
+    use crate::memory::{WriteHitPolicy, WriteMissPolicy};
+    use crate::single_cycle;
+    use crate::system_call::SyscallIo;
+    use crate::test_utils::{load_program, run_both, PROGRAM_BASE};
+
+    #[test]
+    fn test_run_with_io() {
+        let mut io = SyscallIo {
+            input: &b"42\n"[..],
+            output: Vec::new(),
+        };
+        run_with_io(
+            "test-riscv/test_syscall.riscv",
+            &[],
+            CPUPolicy::default(),
+            &mut io,
+        )
+        .unwrap();
+        let output = String::from_utf8_lossy(&io.output);
+        assert!(output.contains("The number is: 42"));
+    }
+
+    #[test]
+    fn test_push_argv() {
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mmu = MMU::make();
+        loader::set_stack(&mut cpu, &mut mmu, STACK_BASE, 0x1000);
+
+        // Nothing past argv[0]
+        push_argv(&mut cpu, &mut mmu, &["prog"]);
+        assert_eq!(cpu.read_reg(2), STACK_BASE);
+
+        push_argv(&mut cpu, &mut mmu, &["prog", "x"]);
+        assert!(cpu.read_reg(2) < STACK_BASE);
+        assert_eq!(cpu.read_reg(10), 2);
+    }
+
+    #[test]
+    fn test_run_bytes() {
+        let path = "test-riscv/quicksort.riscv";
+        let elf_bytes = std::fs::read(path).unwrap();
+        for implementation in [
+            Implementation::SingleCycle,
+            Implementation::MultiCycle,
+            Implementation::Pipelined,
+        ] {
+            let policy = CPUPolicy {
+                implementation,
+                ..Default::default()
+            };
+            let expected = run(path, &[], policy).unwrap();
+            assert_eq!(
+                run_bytes(&elf_bytes, &[path], policy).unwrap(),
+                expected
+            );
+        }
+        // Not an ELF file
+        assert!(run_bytes(b"\x7fELG", &[], CPUPolicy::default()).is_err());
+    }
+
+    /// Runs quicksort on the given memory,
+    /// returning the CPU, the statistics and the output
+    fn run_quicksort(
+        mem: &mut impl StorageInterface,
+    ) -> (CPUState, RunStats, Vec<u8>) {
+        let path = "test-riscv/quicksort.riscv";
+        let (elf_reader, elf_data) = elf_helper::parse_elf_file(path).unwrap();
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut io = SyscallIo {
+            input: &b""[..],
+            output: Vec::new(),
+        };
+        let report = run_program(
+            &mut cpu,
+            mem,
+            &elf_reader,
+            &elf_data,
+            &[path],
+            &mut io,
+            false,
+        )
+        .unwrap();
+        (cpu, report.stats, io.output)
+    }
+
+    #[test]
+    fn test_analyze() {
+        let stats = analyze("test-riscv/quicksort.riscv").unwrap();
+        assert!(stats.count(Opcode::Load) > 0);
+        assert!(stats.count(Opcode::Store) > 0);
+        assert!(stats.count(Opcode::Branch) > 0);
+        assert_eq!(stats.opcode_counts.values().sum::<u64>(), stats.inst_count);
+
+        assert!(analyze("test-riscv/missing.riscv").is_err());
+    }
+
+    #[test]
+    fn test_peek_instruction() {
+        use crate::instruction::Opcode;
+
+        let program = [
+            0x00500293, // addi t0, x0, 5
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+
+        let inst = peek_instruction(&mem.mmu, cpu.pc.read()).unwrap();
+        assert_eq!(inst.raw_inst, 0x00500293);
+        assert_eq!(inst.opcode, Opcode::OpImm);
+        assert_eq!(inst.attributes.rd, Some(5));
+        let inst = peek_instruction(&mem.mmu, PROGRAM_BASE + 8).unwrap();
+        assert_eq!(inst.opcode, Opcode::System);
+        // Neither the caches nor the counters see the reads
+        assert_eq!(mem.ref_counter, 0);
+        assert!(mem.get_history().iter().all(|h| h.num_miss == 0));
+        assert!(peek_instruction(&mem.mmu, 0x8000).is_err());
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let (reference, reference_stats, reference_output) =
+            run_quicksort(&mut InclusiveCache::default());
+        let mut mem = PassthroughMemory::default();
+        let (cpu, stats, output) = run_quicksort(&mut mem);
+        assert!(mem.get_history().is_empty());
+
+        for i in 0..32 {
+            assert_eq!(cpu.gpr[i].read(), reference.gpr[i].read(), "x{}", i);
+        }
+        assert_eq!(output, reference_output);
+        // Only the stalls differ, and every access stalls the most
+        assert_eq!(stats.0, reference_stats.0);
+        assert!(stats.1 > reference_stats.1);
+        assert_eq!(stats.1, stats.2);
+    }
+
+    #[test]
+    fn test_stats_csv() {
+        let path = std::env::temp_dir()
+            .join(format!("stats_{}.csv", std::process::id()));
+        let path = path.to_str().unwrap();
+        let program = "test-riscv/add.riscv";
+        let report =
+            run_with_report(program, &[], CPUPolicy::default()).unwrap();
+        assert!(report.amat.is_some());
+        append_stats_csv(path, program, &report).unwrap();
+
+        let content = std::fs::read_to_string(path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "Program,Instructions,CPI (ideal),CPI (caching),CPI (no caching),\
+             Ratio,L1 hits,L1 misses,L2 hits,L2 misses,L3 hits,L3 misses,AMAT"
+        );
+        let row: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(row.len(), 13);
+        assert_eq!(row[0], program);
+        assert_eq!(row[1], report.instruction_count.to_string());
+        assert_eq!(row[3], format!("{:.3}", report.stats.1));
+
+        // Later runs only append rows
+        append_stats_csv(path, program, &report).unwrap();
+        let content = std::fs::read_to_string(path).unwrap();
+        assert_eq!(content.lines().count(), 3);
+        assert_eq!(content.lines().nth(2), Some(lines[1]));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_max_instructions() {
+        use crate::error::SimulatorError;
+        let program = [
+            0x0000006f, // jal x0, 0
+        ];
+        for implementation in [
+            Implementation::SingleCycle,
+            Implementation::MultiCycle,
+            Implementation::Pipelined,
+        ] {
+            let policy = CPUPolicy {
+                implementation,
+                max_instructions: Some(100),
+                ..Default::default()
+            };
+            let mut cpu = CPUState::make(policy);
+            let mut mem = load_program(&mut cpu, &program);
+            let result = simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio());
+            assert!(matches!(
+                result,
+                Err(SimulatorError::InstructionLimit(100))
+            ));
+            assert_eq!(cpu.history.inst_count, 100);
+        }
+
+        // A program exiting in time is unaffected
+        let program = [
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let policy = CPUPolicy {
+            max_instructions: Some(2),
+            ..Default::default()
+        };
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        let exit_pc =
+            simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        assert_eq!(exit_pc, PROGRAM_BASE + 4);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        use crate::cpu::InstructionMix;
+        // Swaps 5 for 9 at 0x2000, with the first SC failing
+        // for lack of a reservation, which retries with LR
+        let program = [
+            0x000025b7, // lui a1, 0x2
+            0x00900613, // addi a2, x0, 9
+            0x00500693, // addi a3, x0, 5
+            0x00c0006f, // jal x0, 12
+            0x1005a2af, // lr.w t0, (a1)
+            0x00d29663, // bne t0, a3, 12
+            0x18c5a32f, // sc.w t1, a2, (a1)
+            0xfe031ae3, // bne t1, x0, -12
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for implementation in [
+            Implementation::SingleCycle,
+            Implementation::MultiCycle,
+            Implementation::Pipelined,
+        ] {
+            let policy = CPUPolicy {
+                implementation,
+                ..Default::default()
+            };
+            let mut cpu = CPUState::make(policy);
+            let mut mem = load_program(&mut cpu, &program);
+            mem.mmu.allocate_page(0x2000);
+            mem.mmu.write_bytes(0x2000, &5u32.to_le_bytes());
+
+            let exit_pc =
+                simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+            assert_eq!(exit_pc, PROGRAM_BASE + 0x24);
+            assert_eq!(mem.get32(0x2000, &mut None).unwrap(), 9);
+            assert_eq!(cpu.read_reg(5), 5);
+            assert_eq!(cpu.read_reg(6), 0);
+            assert_eq!(cpu.reservation, None);
+            let expected = InstructionMix {
+                branches: 3,
+                jumps: 1,
+                alu: 4,
+                system: 1,
+                atomics: 3,
+                ..Default::default()
+            };
+            assert_eq!(cpu.history.mix, expected);
+        }
+    }
+
+    #[test]
+    fn test_stack_overflow() {
+        let program = [
+            0x0000f137, // lui sp, 0xf
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00000013, // nop
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for implementation in [
+            Implementation::SingleCycle,
+            Implementation::MultiCycle,
+            Implementation::Pipelined,
+        ] {
+            let mut cpu = CPUState::make(CPUPolicy {
+                implementation,
+                ..Default::default()
+            });
+            let mut mem = load_program(&mut cpu, &program);
+            // SP ends up right at the limit, one byte past the stack
+            cpu.stack_base = 0x10000;
+            cpu.stack_size = 0x1000;
+            cpu.write_reg(2, 0x10000);
+            let error = simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap_err();
+            assert!(matches!(error, SimulatorError::StackOverflow(0xf000)));
+        }
+    }
+
+    #[test]
+    fn test_division_by_zero() {
+        let program = [
+            0x00700293, // addi t0, x0, 7
+            0x0262c533, // div a0, t0, t1
+            0x0262e5b3, // rem a1, t0, t1
+            0x0262d633, // divu a2, t0, t1
+            0x0262f6b3, // remu a3, t0, t1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        // As the spec defines it
+        for cpu in run_both(&program) {
+            assert_eq!(cpu.read_reg(10), u32::MAX);
+            assert_eq!(cpu.read_reg(11), 7);
+            assert_eq!(cpu.read_reg(12), u32::MAX);
+            assert_eq!(cpu.read_reg(13), 7);
+        }
+
+        for implementation in [
+            Implementation::SingleCycle,
+            Implementation::MultiCycle,
+            Implementation::Pipelined,
+        ] {
+            let policy = CPUPolicy {
+                implementation,
+                trap_division_by_zero: true,
+                ..Default::default()
+            };
+            let mut cpu = CPUState::make(policy);
+            let mut mem = load_program(&mut cpu, &program);
+            let error = simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap_err();
+            assert!(matches!(
+                error,
+                SimulatorError::DivisionByZero(pc) if pc == PROGRAM_BASE + 4
+            ));
+            assert_eq!(cpu.read_reg(10), 0);
+        }
+    }
+
+    #[test]
+    fn test_fast_forward() {
+        let program = [
+            0x000022b7, // lui t0, 0x2
+            0x00a00313, // addi t1, x0, 10
+            0x0062a023, // sw t1, 0(t0)
+            0x0002a383, // lw t2, 0(t0)
+            0x00750533, // add a0, a0, t2
+            0xfff30313, // addi t1, t1, -1
+            0xfe0318e3, // bne t1, x0, -16
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let simulate_with = |implementation, fast_forward| {
+            let policy = CPUPolicy {
+                implementation,
+                fast_forward,
+                ..Default::default()
+            };
+            let mut cpu = CPUState::make(policy);
+            let mut mem = load_program(&mut cpu, &program);
+            mem.mmu.allocate_page(0x2000);
+            let exit_pc =
+                simulate(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+            (exit_pc, cpu, mem.get_history())
+        };
+
+        for implementation in [
+            Implementation::SingleCycle,
+            Implementation::MultiCycle,
+            Implementation::Pipelined,
+        ] {
+            let (reference_exit_pc, reference, _) =
+                simulate_with(implementation, 0);
+            // Stops in the middle of the loop
+            let (exit_pc, cpu, cache_history) =
+                simulate_with(implementation, 20);
+            assert_eq!(exit_pc, reference_exit_pc);
+            for i in 0..32 {
+                assert_eq!(cpu.gpr[i].read(), reference.gpr[i].read());
+            }
+            assert_eq!(cpu.gpr[10].read(), 55);
+            // Only the detailed part is accounted for
+            assert_eq!(
+                cpu.history.inst_count,
+                reference.history.inst_count - 20
+            );
+            // The data block is already cached
+            assert_eq!(cache_history[0].num_miss, 0);
+        }
+
+        // Exiting while fast-forwarding
+        let (exit_pc, cpu, _) = simulate_with(Implementation::Pipelined, 100);
+        assert_eq!(exit_pc, PROGRAM_BASE + 32);
+        assert_eq!(cpu.gpr[10].read(), 55);
+    }
+
+    #[test]
+    fn test_trace_replay() {
+        let program = [
+            0x000022b7, // lui t0, 0x2
+            0x00300313, // addi t1, x0, 3
+            0x0062a023, // sw t1, 0(t0)
+            0x0002a383, // lw t2, 0(t0)
+            0x0042c503, // lbu a0, 4(t0)
+            0x00629423, // sh t1, 8(t0)
+            0xfff30313, // addi t1, t1, -1
+            0xfe0318e3, // bne t1, x0, -16
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        mem.mmu.allocate_page(0x2000);
+        mem.trace = Some(TraceRecorder::make(true));
+        single_cycle::run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+
+        let mut trace = Vec::new();
+        mem.trace.as_ref().unwrap().write(&mut trace).unwrap();
+        let trace = String::from_utf8(trace).unwrap();
+        assert!(trace.starts_with("x 0x00001000 4\n"));
+        assert!(trace.contains("w 0x00002000 4\n"));
+        assert!(trace.contains("r 0x00002004 1\n"));
+        assert!(trace.contains("w 0x00002008 2\n"));
+
+        let mut replayed = InclusiveCache::default();
+        replay_operations(&mut replayed, &parse_operations(&trace));
+        assert_eq!(replayed.get_history(), mem.get_history());
+    }
+
+    #[test]
+    fn test_address_histogram() {
+        let mut trace = String::new();
+        // A cold page, a hot one, and one in between
+        trace += "r 0x00001000 4\n";
+        for i in 0..10 {
+            trace += &format!("w {:#010x} 4\n", 0x5000 + 4 * i);
+        }
+        for i in 0..3 {
+            trace += &format!("x {:#010x} 4\n", 0x3ffc + 4 * i);
+        }
+        let operations = parse_operations(&trace);
+
+        let histogram = address_histogram(&operations, 4096).unwrap();
+        assert_eq!(
+            histogram,
+            vec![(0x5000, 10), (0x4000, 2), (0x1000, 1), (0x3000, 1)]
+        );
+        // Finer buckets split the hot page, with ties broken by address
+        let histogram = address_histogram(&operations, 16).unwrap();
+        assert_eq!(histogram[..3], [(0x5000, 4), (0x5010, 4), (0x4000, 2)]);
+        assert_eq!(histogram.len(), 6);
+        assert_eq!(histogram.iter().map(|(_, count)| count).sum::<u64>(), 14);
+        // Buckets of 0 bytes
+        assert!(address_histogram(&operations, 0).is_err());
+    }
+
+    #[test]
+    fn test_warmup() {
+        // Four distinct blocks, each read twice
+        let mut trace = String::new();
+        for _ in 0..2 {
+            for i in 0..4 {
+                trace += &format!("r {:#010x} 4\n", 0x1000 + 0x40 * i);
+            }
+        }
+        let operations = parse_operations(&trace);
+
+        let mut cold = InclusiveCache::default();
+        replay_operations(&mut cold, &operations);
+        let history = cold.get_history()[0];
+        assert_eq!(history.num_compulsory_miss, 4);
+        assert_eq!((history.num_hit, history.num_miss), (4, 4));
+
+        let mut warm = InclusiveCache::default();
+        replay_operations_warm(&mut warm, &operations, 4);
+        let history = warm.get_history()[0];
+        assert_eq!(history.num_compulsory_miss, 0);
+        assert_eq!((history.num_hit, history.num_miss), (4, 0));
+        assert_eq!(warm.caches[0].get_miss_rate(), 0.0);
+    }
+
+    #[test]
+    fn test_run_traces() {
+        // Two traces over the same four blocks, read twice each
+        let mut paths = Vec::new();
+        for name in ["first", "second"] {
+            let mut trace = String::new();
+            for _ in 0..2 {
+                for i in 0..4 {
+                    trace += &format!("r {:#010x} 4\n", 0x1000 + 0x40 * i);
+                }
+            }
+            let path = std::env::temp_dir().join(format!(
+                "run_traces_{}_{}.trace",
+                std::process::id(),
+                name
+            ));
+            std::fs::write(&path, trace).unwrap();
+            paths.push(path.to_str().unwrap().to_string());
+        }
+        let paths: Vec<&str> = paths.iter().map(|path| path.as_str()).collect();
+        let make_cache = || {
+            InclusiveCache::make(
+                vec![CachePolicy::make(1024, 64, 1, 1)],
+                WriteHitPolicy::default(),
+                WriteMissPolicy::default(),
+                100,
+                false,
+            )
+        };
+
+        // Half of the accesses miss on a cold cache
+        let isolated = run_traces(&mut make_cache(), &paths, true).unwrap();
+        assert_eq!(isolated, vec![51.0, 51.0, 51.0]);
+        // The second trace finds all its blocks cached
+        let shared = run_traces(&mut make_cache(), &paths, false).unwrap();
+        assert_eq!(shared, vec![51.0, 1.0, 26.0]);
+
+        for path in paths.iter() {
+            std::fs::remove_file(path).unwrap();
+        }
+        // Missing traces are reported
+        assert!(run_traces(&mut make_cache(), &paths, true).is_err());
+        // So is the lack of any
+        assert!(run_traces(&mut make_cache(), &[], true).is_err());
+    }
+}
@@ -1,28 +1,38 @@
 use sim_lib::{
-    cpu::{CPUPolicy, Implementation},
+    cpu::{CPUPolicy, CacheMode, Implementation},
+    memory::mmio::{CONSOLE_BASE, TIMER_BASE},
     pipelined::branch_predictor::PredictorHeuristic,
     run_wrapper,
 };
 use std::{env, error::Error};
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let mut args = env::args().skip(1);
+    let mut args = env::args().skip(1).peekable();
     let elf_file = args
         .next()
         .ok_or("You should specify exactly one ELF file")?;
 
     let mut policy = CPUPolicy::default();
+    // Arguments passed to the guest program
+    let mut guest_args: Vec<String> = Vec::new();
+    // CSV file to append the statistics to
+    let mut stats_csv: Option<String> = None;
 
     while let Some(arg) = args.next() {
         match arg.as_str() {
             "-v" => policy.verbose = true,
             "-h" => policy.history = true,
+            "-r" | "--dump-regs" => policy.dump_regs = true,
+            "--single-port" => policy.single_port = true,
+            "--guard-pages" => policy.guard_pages = true,
+            "--trap-div-by-zero" => policy.trap_division_by_zero = true,
             "-i" => {
                 let impl_arg = args
                     .next()
                     .ok_or("You should specify an implementation after -i")?;
                 policy.implementation = match impl_arg.as_str() {
                     "S" => Implementation::SingleCycle,
+                    "M" => Implementation::MultiCycle,
                     "P" => Implementation::Pipelined,
                     _ => {
                         return Err(
@@ -45,11 +55,114 @@ fn main() -> Result<(), Box<dyn Error>> {
                     }
                 };
             }
+            "-e" => {
+                let number_arg = args.next().ok_or(
+                    "You should specify a system call number after -e",
+                )?;
+                policy.exit_syscall = Some(number_arg.parse()?);
+            }
+            "-f" => {
+                let count_arg = args.next().ok_or(
+                    "You should specify an instruction count after -f",
+                )?;
+                policy.fast_forward = count_arg.parse()?;
+            }
+            "--hang-threshold" => {
+                let count_arg = args.next().ok_or(
+                    "You should specify a branch count after --hang-threshold",
+                )?;
+                policy.hang_threshold = Some(count_arg.parse()?);
+            }
+            "--max-instructions" => {
+                let count_arg = args.next().ok_or(
+                    "You should specify an instruction count after --max-instructions",
+                )?;
+                policy.max_instructions = Some(count_arg.parse()?);
+            }
+            "--entry" => {
+                let address_arg = args
+                    .next()
+                    .ok_or("You should specify an address after --entry")?;
+                let hex = address_arg
+                    .strip_prefix("0x")
+                    .ok_or("The entry address must be hexadecimal")?;
+                policy.entry_override = Some(u32::from_str_radix(hex, 16)?);
+            }
+            "--load-bias" => {
+                let bias_arg = args
+                    .next()
+                    .ok_or("You should specify an offset after --load-bias")?;
+                let hex = bias_arg
+                    .strip_prefix("0x")
+                    .ok_or("The load bias must be hexadecimal")?;
+                policy.load_bias = Some(u32::from_str_radix(hex, 16)?);
+            }
+            "-c" => {
+                // The address is optional
+                policy.console = Some(CONSOLE_BASE);
+                if let Some(address) = args.peek() {
+                    if let Some(hex) = address.strip_prefix("0x") {
+                        policy.console = Some(u32::from_str_radix(hex, 16)?);
+                        args.next();
+                    }
+                }
+            }
+            "--timer" => {
+                // The address is optional
+                policy.timer = Some(TIMER_BASE);
+                if let Some(address) = args.peek() {
+                    if let Some(hex) = address.strip_prefix("0x") {
+                        policy.timer = Some(u32::from_str_radix(hex, 16)?);
+                        args.next();
+                    }
+                }
+            }
+            "--cache" => {
+                let mode_arg = args
+                    .next()
+                    .ok_or("You should specify a cache mode after --cache")?;
+                policy.cache = match mode_arg.as_str() {
+                    "inclusive" => CacheMode::Inclusive,
+                    "none" => CacheMode::Passthrough,
+                    _ => {
+                        return Err(
+                            "Invalid cache mode specified after --cache".into(),
+                        )
+                    }
+                };
+            }
+            "--stats-csv" => {
+                stats_csv = Some(
+                    args.next()
+                        .ok_or("You should specify a path after --stats-csv")?,
+                );
+            }
+            "-t" => {
+                // Instruction fetches are optional
+                policy.record_trace = true;
+                if args.peek().is_some_and(|arg| arg == "x") {
+                    policy.trace_fetches = true;
+                    args.next();
+                }
+            }
+            "--" => {
+                guest_args.extend(args.by_ref());
+            }
             _ => return Err(format!("Unknown parameter: {}", arg).into()),
         }
     }
 
-    run_wrapper::run(&elf_file, policy)?;
+    let guest_args: Vec<&str> = guest_args.iter().map(|s| s.as_str()).collect();
+    match stats_csv {
+        Some(path) => {
+            let report =
+                run_wrapper::run_with_report(&elf_file, &guest_args, policy)?;
+            run_wrapper::append_stats_csv(&path, &elf_file, &report)?;
+        }
+        None => {
+            run_wrapper::run(&elf_file, &guest_args, policy)?;
+        }
+    }
 
     Ok(())
 }
@@ -1,63 +1,588 @@
 //! Single cycle implementation
 
-use crate::cpu::CPUState;
-use crate::instruction::Opcode;
+use crate::cpu::{CPUHistory, CPUState};
+use crate::error::{SimulatorError, SimulatorResult};
+use crate::instruction::{compressed, DecodeCache, Function, Opcode};
 use crate::memory::StorageInterface;
 use crate::stages_simple::*;
+use crate::system_call::SyscallIo;
+use std::io::{BufRead, Write};
 
 /// Returns the exiting PC address
-pub fn run(mut cpu: &mut CPUState, mem: &mut impl StorageInterface) -> u32 {
+pub fn run(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<u32> {
+    Ok(run_with_stats(cpu, mem, io)?.0)
+}
+
+/// Returns the exiting PC address along with the history of execution
+pub fn run_with_stats(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<(u32, CPUHistory)> {
+    run_with_decoder(cpu, mem, io, &mut DecodeCache::default())
+}
+
+/// Like run_with_stats, decoding through the given decode cache
+pub fn run_with_decoder(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+    decoder: &mut DecodeCache,
+) -> SimulatorResult<(u32, CPUHistory)> {
     loop {
-        // Detect stack overflow
-        if cpu.stack_overflow() {
-            panic!("Stack overflow");
+        if let Some(exit_pc) = step(cpu, mem, io, decoder)? {
+            return Ok((exit_pc, cpu.history));
+        }
+    }
+}
+
+/// Functionally executes up to count instructions,
+/// going through the caches but leaving the stall counts
+/// to be discarded by the caller.
+/// Returns the exiting PC address if the program exits meanwhile
+pub fn fast_forward(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+    count: u64,
+) -> SimulatorResult<Option<u32>> {
+    let mut decoder = DecodeCache::default();
+    for _ in 0..count {
+        if let Some(exit_pc) = step(cpu, mem, io, &mut decoder)? {
+            return Ok(Some(exit_pc));
         }
+    }
+    Ok(None)
+}
 
-        // Increment CPU cycle count
-        cpu.update_cycle_count(1);
+/// Executes a single instruction in a single cycle.
+/// Returns the exiting PC address if it exits the program
+pub(crate) fn step(
+    cpu: &mut CPUState,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+    decoder: &mut DecodeCache,
+) -> SimulatorResult<Option<u32>> {
+    // Detect stack overflow
+    cpu.check_stack_overflow()?;
+    cpu.check_instruction_limit()?;
 
-        // Read and increment PC
-        let pc = cpu.pc.read();
-        cpu.pc.write(pc + 4);
+    // Increment CPU cycle count
+    cpu.update_cycle_count(1);
 
+    let pc = cpu.pc.read();
+
+    if cpu.policy.verbose {
+        eprintln!("[VERBOSE] PC: {:#010x}", pc);
+    }
+
+    // IF
+    let raw_inst = instruction_fetch(pc, cpu, mem)?;
+    // Increment PC past the instruction, which wraps around like any
+    // address; running off the end then faults on fetching unallocated
+    // memory
+    cpu.pc.write(pc.wrapping_add(compressed::length(raw_inst)));
+    // ID, where an illegal instruction may trap
+    let decoded = instruction_decode_cached(pc, raw_inst, decoder);
+    let inst = match (decoded, cpu.policy.trap_vector) {
+        (Err(SimulatorError::InvalidInstructionAt(..)), Some(trap_vector)) => {
+            cpu.trap(pc, raw_inst, trap_vector);
+            return Ok(None);
+        }
+        (result, _) => result?,
+    };
+    // Return from the trap handler
+    if inst.function == Function::MRET && cpu.policy.trap_vector.is_some() {
+        cpu.update_inst_count(1);
+        cpu.history.mix.record(inst.opcode);
+        cpu.pc.write(cpu.trap_return_pc());
+        return Ok(None);
+    }
+    let (rs1, rs2) = register_read(&inst, cpu);
+    // EX
+    let exec_result = execute(pc, cpu, mem, &inst, rs1, rs2, io)?;
+    // MEM
+    let wb_result = memory_access(pc, &inst, cpu, mem, exec_result, rs2)?;
+    // WB
+    write_back(pc, &inst, cpu, wb_result);
+
+    // System call: exit
+    if inst.opcode == Opcode::System && cpu.policy.is_exit(rs2) {
+        return Ok(Some(pc));
+    }
+
+    // Update PC on branch
+    if inst.controls.branch
+        && !(inst.opcode == Opcode::Branch && exec_result != 0)
+    {
+        let imm = inst.attributes.imm.unwrap();
+        let new_pc = match inst.opcode {
+            Opcode::Jalr => (exec_result as u32) & !1u32,
+            _ => pc.wrapping_add(imm),
+        };
         if cpu.policy.verbose {
-            eprintln!("[VERBOSE] PC: {:#010x}", pc);
-        }
-
-        // IF
-        let raw_inst = instruction_fetch(pc, cpu, mem);
-        // ID
-        let inst = instruction_decode(raw_inst);
-        let (rs1, rs2) = register_read(&inst, &cpu);
-        // EX
-        let exec_result = execute(cpu, mem, &inst, rs1, rs2);
-        // MEM
-        let wb_result = memory_access(pc, &inst, cpu, mem, exec_result, rs2);
-        // WB
-        write_back(pc, &inst, &mut cpu, wb_result);
-
-        // System call: exit
-        if inst.opcode == Opcode::System && rs2 == 3 {
-            return pc;
-        }
-
-        // Update PC on branch
-        if inst.controls.branch
-            && !(inst.opcode == Opcode::Branch && exec_result != 0)
-        {
-            let imm = inst.attributes.imm.unwrap() as i32;
-            let new_pc = match inst.opcode {
-                Opcode::Jalr => (exec_result as u32) & !1u32,
-                _ => ((pc as i32) + imm) as u32,
-            };
-            if cpu.policy.verbose {
-                // Print the opcode that caused this branch
-                eprintln!(
-                    "[VERBOSE] Branching from {:#010x} to: {:#010x}",
-                    pc, new_pc
-                );
+            // Print the opcode that caused this branch
+            eprintln!(
+                "[VERBOSE] Branching from {:#010x} to: {:#010x}",
+                pc, new_pc
+            );
+        }
+        cpu.pc.write(new_pc);
+    }
+
+    // Only a branch to itself leaves the PC unchanged
+    match cpu.pc.read() == pc {
+        true => cpu.record_self_branch(pc)?,
+        false => cpu.self_branch_count = 0,
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CPUPolicy, InstructionMix};
+    use crate::error::{MemoryError, SimulatorError};
+    use crate::memory::inclusive::InclusiveCache;
+    use crate::test_utils::{load_program, run_both, PROGRAM_BASE};
+
+    #[test]
+    fn test_run_with_stats() {
+        let program = [
+            0x00500513, // addi a0, x0, 5
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+
+        let (exit_pc, history) =
+            run_with_stats(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap();
+        assert_eq!(exit_pc, 0x1008);
+        assert_eq!(history.inst_count, 3);
+        assert_eq!(history.cycle_count, 3);
+        assert_eq!(cpu.gpr[10].read(), 5);
+    }
+
+    #[test]
+    fn test_reset() {
+        let program = [
+            0x00500513, // addi a0, x0, 5
+            0x00a02023, // sw a0, 0(x0)
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        mem.mmu.allocate_page(0);
+
+        let first = run_with_stats(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+            .unwrap();
+        let first_cache_history = mem.get_history();
+
+        cpu.reset();
+        mem.reset();
+        cpu.pc.write(PROGRAM_BASE);
+
+        let second =
+            run_with_stats(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first_cache_history, mem.get_history());
+    }
+
+    #[test]
+    fn test_instruction_mix() {
+        let program = [
+            0x000012b7, // lui t0, 0x1
+            0x0002a303, // lw t1, 0(t0)
+            0x1062a023, // sw t1, 256(t0)
+            0x00000463, // beq x0, x0, 8
+            0x00100393, // addi t2, x0, 1
+            0x0040006f, // jal x0, 4
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let expected = InstructionMix {
+            loads: 1,
+            stores: 1,
+            branches: 1,
+            jumps: 1,
+            alu: 2,
+            system: 1,
+            atomics: 0,
+        };
+
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let (_, history) =
+            run_with_stats(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap();
+        assert_eq!(history.mix, expected);
+        assert_eq!(history.inst_count, 7);
+
+        // The pipelined backend agrees
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        crate::pipelined::run(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+            .unwrap();
+        assert_eq!(cpu.history.mix, expected);
+        assert_eq!(cpu.history.inst_count, 7);
+    }
+
+    #[test]
+    fn test_newlib_exit() {
+        let program = [
+            0x00000513, // li a0, 0
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+            0x00100313, // li t1, 1
+        ];
+        let policy = CPUPolicy {
+            exit_syscall: Some(93),
+            ..Default::default()
+        };
+
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        let (exit_pc, _) =
+            run_with_stats(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap();
+        assert_eq!(exit_pc, PROGRAM_BASE + 8);
+        assert_eq!(cpu.gpr[6].read(), 0);
+
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        let exit_pc =
+            crate::pipelined::run(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap();
+        assert_eq!(exit_pc, PROGRAM_BASE + 8);
+        assert_eq!(cpu.gpr[6].read(), 0);
+    }
+
+    #[test]
+    fn test_unsupported_privileged() {
+        let program = [
+            0x00100513, // li a0, 1
+            0x10500073, // wfi
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let error = run_with_stats(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+            .unwrap_err();
+        assert!(matches!(
+            &error,
+            SimulatorError::UnsupportedInstruction(name) if name == "WFI"
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Unsupported privileged instruction: WFI"
+        );
+
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let error =
+            crate::pipelined::run(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap_err();
+        assert!(matches!(error, SimulatorError::UnsupportedInstruction(_)));
+    }
+
+    #[test]
+    fn test_trap_illegal_instruction() {
+        let program = [
+            0x00000513, // addi a0, x0, 0
+            0xffffffff, // (illegal)
+            0xffffffff, // (illegal)
+            0x00700593, // addi a1, x0, 7
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+            0x00150513, // addi a0, a0, 1 (trap handler)
+            0x30200073, // mret
+        ];
+        let policy = CPUPolicy {
+            trap_vector: Some(PROGRAM_BASE + 24),
+            ..Default::default()
+        };
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        // Both are caught, and execution resumes after each
+        assert_eq!(cpu.gpr[10].read(), 2);
+        assert_eq!(cpu.gpr[11].read(), 7);
+        assert_eq!(cpu.mepc.read(), PROGRAM_BASE + 8);
+        assert_eq!(cpu.mtval.read(), 0xffffffff);
+        assert_eq!(cpu.history.inst_count, 8);
+
+        // Without a handler, the first one aborts the simulation
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let error =
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap_err();
+        assert!(matches!(
+            error,
+            SimulatorError::InvalidInstructionAt(0x1004, 0xffffffff)
+        ));
+
+        // The pipelined backend doesn't trap
+        let policy = CPUPolicy {
+            implementation: crate::cpu::Implementation::Pipelined,
+            ..policy
+        };
+        assert!(policy.validate().is_err());
+    }
+
+    #[test]
+    fn test_jalr_return() {
+        let program = [
+            0x00c000ef, // jal ra, 12
+            0x00700513, // addi a0, x0, 7
+            0x0100006f, // jal x0, 16
+            0x00108093, // addi ra, ra, 1
+            0x00008067, // jalr x0, 0(ra)
+            0x00100593, // addi a1, x0, 1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for cpu in run_both(&program) {
+            // Returns to ra with the low bit cleared
+            assert_eq!(cpu.gpr[10].read(), 7);
+            assert_eq!(cpu.gpr[11].read(), 0);
+            // Nothing is linked
+            assert_eq!(cpu.gpr[0].read(), 0);
+            assert_eq!(cpu.gpr[1].read(), PROGRAM_BASE + 5);
+            for i in [2, 3, 4, 5, 6, 7, 8, 9, 12, 13, 14, 15, 16] {
+                assert_eq!(cpu.gpr[i].read(), 0, "x{}", i);
             }
-            cpu.pc.write(new_pc);
         }
     }
+
+    #[test]
+    fn test_jalr_call() {
+        let program = [
+            0x000012b7, // lui t0, 0x1
+            0x01028293, // addi t0, t0, 16
+            0x000280e7, // jalr ra, 0(t0)
+            0x00100593, // addi a1, x0, 1
+            0x00008513, // addi a0, ra, 0
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for cpu in run_both(&program) {
+            // Both the jump and the link happen
+            assert_eq!(cpu.gpr[1].read(), PROGRAM_BASE + 12);
+            assert_eq!(cpu.gpr[10].read(), PROGRAM_BASE + 12);
+            assert_eq!(cpu.gpr[11].read(), 0);
+        }
+    }
+
+    #[test]
+    fn test_sltiu() {
+        let program = [
+            0x00103513, // sltiu a0, x0, 1
+            0xfff03593, // sltiu a1, x0, -1
+            0xfff00293, // addi t0, x0, -1
+            0xfff2b613, // sltiu a2, t0, -1
+            0x00500313, // addi t1, x0, 5
+            0xfff33693, // sltiu a3, t1, -1
+            0xfff32713, // slti a4, t1, -1
+            0x00533793, // sltiu a5, t1, 5
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for cpu in run_both(&program) {
+            // seqz
+            assert_eq!(cpu.gpr[10].read(), 1);
+            // The immediate is sign-extended, then compared unsigned
+            assert_eq!(cpu.gpr[11].read(), 1);
+            assert_eq!(cpu.gpr[12].read(), 0);
+            assert_eq!(cpu.gpr[13].read(), 1);
+            // Unlike the signed comparison
+            assert_eq!(cpu.gpr[14].read(), 0);
+            assert_eq!(cpu.gpr[15].read(), 0);
+        }
+    }
+
+    #[test]
+    fn test_branch_signedness() {
+        // -1 is less than 1 signed, but greater unsigned
+        let program = [
+            0xfff00293, // addi t0, x0, -1
+            0x00100313, // addi t1, x0, 1
+            0x0062c463, // blt t0, t1, 8
+            0x00100513, // addi a0, x0, 1
+            0x0062e463, // bltu t0, t1, 8
+            0x00100593, // addi a1, x0, 1
+            0x0062d463, // bge t0, t1, 8
+            0x00100613, // addi a2, x0, 1
+            0x0062f463, // bgeu t0, t1, 8
+            0x00100693, // addi a3, x0, 1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for cpu in run_both(&program) {
+            // Only the instructions after untaken branches run
+            assert_eq!(cpu.gpr[10].read(), 0);
+            assert_eq!(cpu.gpr[11].read(), 1);
+            assert_eq!(cpu.gpr[12].read(), 1);
+            assert_eq!(cpu.gpr[13].read(), 0);
+        }
+    }
+
+    #[test]
+    fn test_compressed() {
+        // Pairs of compressed instructions, lower halfword first
+        let program = [
+            0x4581450d, // c.li a0, 3; c.li a1, 0
+            0x157d0589, // c.addi a1, 2; c.addi a0, -1
+            0xa011fd75, // c.bnez a0, -4; c.j 4
+            0x862e45fd, // c.li a1, 31; c.mv a2, a1
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        for cpu in run_both(&program) {
+            assert_eq!(cpu.gpr[10].read(), 0);
+            assert_eq!(cpu.gpr[11].read(), 6);
+            assert_eq!(cpu.gpr[12].read(), 6);
+            assert_eq!(cpu.history.inst_count, 15);
+        }
+    }
+
+    #[test]
+    fn test_run_into_zeros() {
+        // Jumps past the padding, into the zeroed rest of the page
+        let program = [
+            0x00100513, // addi a0, x0, 1
+            0x0180006f, // jal x0, 24
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let error =
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap_err();
+        assert!(matches!(
+            error,
+            SimulatorError::InvalidInstructionAt(0x101c, 0)
+        ));
+        assert_eq!(
+            error.to_string(),
+            "Failed to decode instruction 0x00000000 at PC 0x0000101c"
+        );
+
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        let error =
+            crate::pipelined::run(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap_err();
+        assert!(matches!(
+            error,
+            SimulatorError::InvalidInstructionAt(0x101c, 0)
+        ));
+    }
+
+    #[test]
+    fn test_self_branch() {
+        let program = [
+            0x00100513, // addi a0, x0, 1
+            0x00000063, // beq x0, x0, 0
+        ];
+        let policy = CPUPolicy {
+            hang_threshold: Some(10),
+            ..Default::default()
+        };
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        let error =
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap_err();
+        assert!(matches!(error, SimulatorError::Hang(0x1004)));
+        assert_eq!(cpu.history.inst_count, 11);
+
+        // Looping back to an earlier instruction is progress
+        let program = [
+            0x01400293, // addi t0, x0, 20
+            0xfff28293, // addi t0, t0, -1
+            0xfe029ee3, // bne t0, x0, -4
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load_program(&mut cpu, &program);
+        run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+        assert_eq!(cpu.self_branch_count, 0);
+    }
+
+    /// Recurses to the given depth, pushing 16 bytes per call
+    fn recursion_program(depth: u32) -> [u32; 12] {
+        [
+            0x00000513 | depth << 20, // addi a0, x0, depth
+            0x00c000ef,               // jal ra, 12
+            0x00300893,               // addi a7, x0, 3
+            0x00000073,               // ecall
+            0xff010113,               // addi sp, sp, -16
+            0x00112623,               // sw ra, 12(sp)
+            0x00050663,               // beq a0, x0, 12
+            0xfff50513,               // addi a0, a0, -1
+            0xff1ff0ef,               // jal ra, -16
+            0x00c12083,               // lw ra, 12(sp)
+            0x01010113,               // addi sp, sp, 16
+            0x00008067,               // jalr x0, 0(ra)
+        ]
+    }
+
+    #[test]
+    fn test_peak_stack_usage() {
+        for depth in [0, 1, 5] {
+            let program = recursion_program(depth);
+            let mut cpu = CPUState::make(CPUPolicy::default());
+            let mut mem = load_program(&mut cpu, &program);
+            crate::loader::set_stack(&mut cpu, &mut mem.mmu, 0x10000, 0x1000);
+            assert_eq!(cpu.peak_stack_usage(), 0);
+
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap();
+            // SP is back at the base, but the high-water mark remains
+            assert_eq!(cpu.gpr[2].read(), 0x10000);
+            assert_eq!(cpu.history.min_sp, Some(0x10000 - 16 * (depth + 1)));
+            assert_eq!(cpu.peak_stack_usage(), 16 * (depth + 1));
+        }
+    }
+
+    #[test]
+    fn test_pc_wraparound() {
+        let program: [u32; 2] = [
+            0x00100513, // addi a0, x0, 1
+            0x00200593, // addi a1, x0, 2
+        ];
+        let bytes: Vec<u8> =
+            program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+        let base = 0xfffffff8;
+
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = InclusiveCache::default();
+        mem.mmu.write_bytes(base, &bytes);
+        cpu.pc.write(base);
+        // The PC wraps around to 0, which is never allocated
+        let error =
+            run(&mut cpu, &mut mem, &mut SyscallIo::stdio()).unwrap_err();
+        assert!(matches!(
+            error,
+            SimulatorError::Memory(MemoryError::ReadUnallocated(0))
+        ));
+        assert_eq!(cpu.gpr[10].read(), 1);
+        assert_eq!(cpu.gpr[11].read(), 2);
+
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = InclusiveCache::default();
+        mem.mmu.write_bytes(base, &bytes);
+        cpu.pc.write(base);
+        let error =
+            crate::pipelined::run(&mut cpu, &mut mem, &mut SyscallIo::stdio())
+                .unwrap_err();
+        assert!(matches!(
+            error,
+            SimulatorError::Memory(MemoryError::ReadUnallocated(0))
+        ));
+    }
 }
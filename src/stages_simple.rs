@@ -2,66 +2,115 @@
 
 use crate::alu::{alu, ALUSrc};
 use crate::cpu::CPUState;
-use crate::instruction::Instruction;
+use crate::error::{SimulatorError, SimulatorResult};
+use crate::instruction::{DecodeCache, Instruction};
 use crate::memory::StorageInterface;
-use crate::system_call::syscall;
+use crate::system_call::{self, syscall, SyscallIo};
+use std::io::{BufRead, Write};
 
 /// IF: Fetch the instruction from memory
 pub fn instruction_fetch(
     pc: u32,
     cpu: &mut CPUState,
     mem: &mut impl StorageInterface,
-) -> u32 {
+) -> SimulatorResult<u32> {
     let mut stall_count = Some(0);
     let mut stall_count_worst = Some(0);
-    let raw_inst = mem.get(pc, 4, &mut stall_count, &mut stall_count_worst);
+    let raw_inst = mem.fetch(pc, &mut stall_count, &mut stall_count_worst)?;
     cpu.history.mem_stall_count += stall_count.unwrap();
     cpu.history.mem_stall_worst_count += stall_count_worst.unwrap();
-    assert!(raw_inst != 0, "Instruction fetch failed");
-    raw_inst
+    Ok(raw_inst)
 }
 
-/// ID: Instruction decode
-pub fn instruction_decode(raw_inst: u32) -> Instruction {
-    Instruction::new(raw_inst)
+/// ID: Instruction decode.
+/// An undecodable instruction reports the PC it's fetched from,
+/// e.g., when control runs off into zeroed memory
+pub fn instruction_decode(
+    pc: u32,
+    raw_inst: u32,
+) -> SimulatorResult<Instruction> {
+    Instruction::try_new(raw_inst).map_err(|e| locate_invalid(pc, e))
+}
+
+/// ID: Instruction decode through the given decode cache,
+/// reporting an undecodable instruction like instruction_decode
+pub fn instruction_decode_cached(
+    pc: u32,
+    raw_inst: u32,
+    decoder: &mut DecodeCache,
+) -> SimulatorResult<Instruction> {
+    decoder.decode(raw_inst).map_err(|e| locate_invalid(pc, e))
+}
+
+/// Attaches the PC to an invalid instruction error
+fn locate_invalid(pc: u32, e: SimulatorError) -> SimulatorError {
+    match e {
+        SimulatorError::InvalidInstruction(raw_inst) => {
+            SimulatorError::InvalidInstructionAt(pc, raw_inst)
+        }
+        e => e,
+    }
 }
 
 /// ID: Register read
 pub fn register_read(inst: &Instruction, cpu: &CPUState) -> (i32, i32) {
-    let rs1 = cpu.gpr[inst.attributes.rs1.unwrap_or(0) as usize].read() as i32;
-    let rs2 = cpu.gpr[inst.attributes.rs2.unwrap_or(0) as usize].read() as i32;
+    let rs1 = cpu.read_reg(inst.attributes.rs1.unwrap_or(0) as usize) as i32;
+    let rs2 = cpu.read_reg(inst.attributes.rs2.unwrap_or(0) as usize) as i32;
     (rs1, rs2)
 }
 
 /// EX: Compute stuff
 pub fn execute(
+    pc: u32,
     cpu: &mut CPUState,
     mem: &mut impl StorageInterface,
     inst: &Instruction,
     op1: i32,
     op2: i32,
-) -> i32 {
-    // Increment instruction count
-    cpu.update_inst_count(1);
-
-    use crate::instruction::Opcode;
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<i32> {
+    use crate::instruction::{Function, Opcode};
     if inst.opcode == Opcode::System {
-        // Handle system calls
-        syscall(op1, op2, mem)
+        // Only ECALL is supported among privileged instructions
+        if inst.function != Function::ECALL {
+            let name = format!("{:?}", inst.function);
+            return Err(SimulatorError::UnsupportedInstruction(name));
+        }
+        // Handle system calls,
+        // where any exit is handled as the custom one
+        let call_type = match cpu.policy.is_exit(op2) {
+            true => system_call::EXIT,
+            false => op2,
+        };
+        syscall(op1, call_type, mem, io)
     } else {
         // Handle ALU operations
+        // LUI adds the immediate to zero and AUIPC to the PC
+        let op1 = match inst.opcode {
+            Opcode::Lui => 0,
+            Opcode::AuiPc => pc as i32,
+            _ => op1,
+        };
         use ALUSrc::*;
         let op2 = match inst.controls.alu_src {
+            // LR and SC address rs1 without an offset
+            _ if inst.opcode == Opcode::Amo => 0,
             REG => op2,
             IMM => inst.attributes.imm.unwrap() as i32,
         };
+        if cpu.policy.trap_division_by_zero
+            && inst.controls.alu_op.is_division()
+            && op2 == 0
+        {
+            return Err(SimulatorError::DivisionByZero(pc));
+        }
         if cpu.policy.verbose {
             // Print the instruction
             eprintln!("[VERBOSE] Executing: {:?}", inst);
             // Print the operands
             eprintln!("[VERBOSE] op1: {:#010x}; op2: {:#010x}", op1, op2);
         }
-        alu(&inst, op1, op2)
+        Ok(alu(inst, op1, op2))
     }
 }
 
@@ -73,68 +122,165 @@ pub fn memory_access(
     mem: &mut impl StorageInterface,
     exec_result: i32,
     op2: i32,
-) -> u32 {
+) -> SimulatorResult<u32> {
     let mut mem_result: u32 = 0;
 
     let address = exec_result as u32;
     let mem_step = inst.controls.mem_step;
+    if (inst.controls.mem_read || inst.controls.mem_write) && mem_step == 0 {
+        return Err(SimulatorError::InvalidControls(format!(
+            "{:?} accesses memory with a step of 0",
+            inst.function
+        )));
+    }
 
     let mut stall_count = Some(0);
     let mut stall_count_worst = Some(0);
 
+    if inst.controls.mem_read || inst.controls.mem_write {
+        // A timer reads the current cycle
+        let cycle = cpu.history.cycle_count as u64;
+        mem.mmu().set_current_cycle(cycle);
+    }
+    // SC stores only while the reservation on the address holds,
+    // and clears the reservation either way
+    use crate::instruction::Function;
+    let sc_failed =
+        inst.function == Function::SCW && cpu.reservation != Some(address);
+    if inst.function == Function::SCW {
+        cpu.reservation = None;
+    }
     if inst.controls.mem_read {
         mem_result = mem.get(
             address,
             mem_step,
             &mut stall_count,
             &mut stall_count_worst,
-        );
-    } else if inst.controls.mem_write {
+        )?;
+        if inst.function == Function::LRW {
+            cpu.reservation = Some(address);
+        }
+    } else if inst.controls.mem_write && !sc_failed {
+        mem.mmu().set_current_pc(pc);
         mem.set(
             address,
             mem_step,
             op2 as u32,
             &mut stall_count,
             &mut stall_count_worst,
-        );
+        )?;
     }
 
     cpu.history.mem_stall_count += stall_count.unwrap();
     cpu.history.mem_stall_worst_count += stall_count_worst.unwrap();
 
-    match inst.controls.mem_read {
+    let wb_result = match inst.controls.mem_read {
         true => {
-            // Write the memory result
-            mem_result
+            // Write the memory result,
+            // sign-extended for LB and LH
+            match inst.function {
+                Function::LB => mem_result as u8 as i8 as i32 as u32,
+                Function::LH => mem_result as u16 as i16 as i32 as u32,
+                _ => mem_result,
+            }
         }
         false => {
             // Write the execution result
-            // Special cases: LUI, AUIPC, JAL, JALR
-            use crate::instruction::Function;
-            let imm = inst.attributes.imm.unwrap_or(0) as i32;
+            // Special cases: JAL, JALR, and SC writing 0 on success
             match inst.function {
-                Function::LUI => imm as u32,
-                Function::AUIPC => ((pc as i32) + imm) as u32,
-                Function::JAL | Function::JALR => pc + 4,
+                Function::JAL | Function::JALR => pc.wrapping_add(inst.length),
+                Function::SCW => sc_failed as u32,
                 _ => exec_result as u32,
             }
         }
-    }
+    };
+    Ok(wb_result)
 }
 
-/// WB: Write stuff back to the selected register
+/// WB: Write stuff back to the selected register,
+/// which retires the instruction
 pub fn write_back(
     _: u32,
     inst: &Instruction,
     cpu: &mut CPUState,
     wb_result: u32,
 ) {
+    // Increment instruction count
+    cpu.update_inst_count(1);
+    cpu.history.mix.record(inst.opcode);
+
     // If you need to write
     if inst.controls.reg_write {
         let rd = inst.attributes.rd.unwrap() as usize;
-        // You don't write to x0
-        if rd != 0 {
-            cpu.gpr[rd].write(wb_result);
+        // Writes to x0 are dropped
+        cpu.write_reg(rd, wb_result);
+        if rd == 2 {
+            cpu.record_sp(wb_result);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPUPolicy;
+    use crate::error::MemoryError;
+    use crate::memory::inclusive::InclusiveCache;
+
+    /// Loads from an address holding 0x8080 with the given instruction
+    fn load(raw_inst: u32) -> u32 {
+        let address = 0x1000;
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = InclusiveCache::default();
+        mem.mmu.allocate_page(address);
+        mem.mmu.set8(address, 0x80);
+        mem.mmu.set8(address + 1, 0x80);
+
+        let inst = instruction_decode(0, raw_inst).unwrap();
+        memory_access(0, &inst, &mut cpu, &mut mem, address as i32, 0).unwrap()
+    }
+
+    #[test]
+    fn test_load_extension() {
+        // lb a0, 0(t0)
+        assert_eq!(load(0x00028503), 0xffffff80);
+        // lbu a0, 0(t0)
+        assert_eq!(load(0x0002c503), 0x80);
+        // lh a0, 0(t0)
+        assert_eq!(load(0x00029503), 0xffff8080);
+        // lhu a0, 0(t0)
+        assert_eq!(load(0x0002d503), 0x8080);
+    }
+
+    #[test]
+    fn test_load_unallocated() {
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = InclusiveCache::default();
+
+        // lw a0, 0(t0)
+        let inst = instruction_decode(0, 0x0002a503).unwrap();
+        let result = memory_access(0, &inst, &mut cpu, &mut mem, 0x5000, 0);
+        assert!(matches!(
+            result,
+            Err(SimulatorError::Memory(MemoryError::ReadUnallocated(0x5000)))
+        ));
+    }
+
+    #[test]
+    fn test_zero_mem_step() {
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = InclusiveCache::default();
+        mem.mmu.allocate_page(0x1000);
+
+        // lw a0, 0(t0), with the step lost in decoding
+        let mut inst = instruction_decode(0, 0x0002a503).unwrap();
+        inst.controls.mem_step = 0;
+        let error =
+            memory_access(0, &inst, &mut cpu, &mut mem, 0x1000, 0).unwrap_err();
+        assert!(matches!(error, SimulatorError::InvalidControls(_)));
+        assert_eq!(
+            error.to_string(),
+            "Invalid control signals: LW accesses memory with a step of 0"
+        );
+    }
+}
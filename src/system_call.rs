@@ -1,11 +1,69 @@
 //! System call handler
 
+use crate::error::{SimulatorError, SimulatorResult};
 use crate::memory::StorageInterface;
-use std::io::{self, Write};
-use text_io::scan;
+use std::io::{self, BufRead, Read, Write};
+
+/// System call number of exit in the custom ABI
+pub const EXIT: i32 = 3;
+
+/// System call numbers used by the custom ABI
+pub const CUSTOM_SYSCALLS: std::ops::RangeInclusive<i32> = 0..=5;
+
+/// What the read system calls return once the input runs out
+pub const END_OF_INPUT: i32 = -1;
+
+/// Where system calls read input from and print output to
+pub struct SyscallIo<R: BufRead, W: Write> {
+    pub input: R,
+    pub output: W,
+}
+
+impl SyscallIo<io::BufReader<io::Stdin>, io::Stdout> {
+    /// Make one reading stdin and printing to stdout
+    pub fn stdio() -> Self {
+        Self {
+            input: io::BufReader::new(io::stdin()),
+            output: io::stdout(),
+        }
+    }
+}
+
+/// Skips whitespace and reads the following bytes up to
+/// the next whitespace, which is consumed as well.
+/// Returns None at the end of the input
+fn read_token(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut token = Vec::new();
+    for byte in input.bytes() {
+        let byte = byte?;
+        match (byte.is_ascii_whitespace(), token.is_empty()) {
+            (true, true) => continue,
+            (true, false) => break,
+            (false, _) => token.push(byte),
+        }
+    }
+    Ok((!token.is_empty()).then_some(token))
+}
+
+/// Skips whitespace and reads a single byte,
+/// or returns None at the end of the input
+fn read_char(input: &mut impl BufRead) -> io::Result<Option<u8>> {
+    for byte in input.bytes() {
+        let byte = byte?;
+        if !byte.is_ascii_whitespace() {
+            return Ok(Some(byte));
+        }
+    }
+    Ok(None)
+}
 
 /// Handles a system call
-pub fn syscall(op1: i32, op2: i32, mem: &mut impl StorageInterface) -> i32 {
+pub fn syscall(
+    op1: i32,
+    op2: i32,
+    mem: &mut impl StorageInterface,
+    io: &mut SyscallIo<impl BufRead, impl Write>,
+) -> SimulatorResult<i32> {
     let call_type = op2;
     let call_arg = op1;
 
@@ -15,47 +73,121 @@ pub fn syscall(op1: i32, op2: i32, mem: &mut impl StorageInterface) -> i32 {
     match call_type {
         0 => {
             // Print a string
-            let mut address = call_arg as u32;
-            loop {
-                let ch = mem.get(address, 1, &mut None, &mut None) as u8;
-                if ch == 0 {
-                    break;
-                }
-                print!("{}", ch as char);
-                io::stdout().flush().unwrap();
-                address += 1;
+            let string = mem.mmu().read_cstr(call_arg as u32)?;
+            for ch in string {
+                write!(io.output, "{}", ch as char)?;
             }
+            io.output.flush()?;
         }
         1 => {
             // Print a character
-            print!("{}", (call_arg as u8) as char);
-            io::stdout().flush().unwrap();
+            write!(io.output, "{}", (call_arg as u8) as char)?;
+            io.output.flush()?;
         }
         2 => {
             // Print a signed number
-            print!("{}", call_arg as i32);
-            io::stdout().flush().unwrap();
+            write!(io.output, "{}", call_arg)?;
+            io.output.flush()?;
         }
-        3 => {
+        EXIT => {
             // Exit the program
             // We'll do nothing actually
         }
         4 => {
             // Read a character
-            let c: char;
-            scan!("{}", c);
-            result = c as i32;
+            result = read_char(&mut io.input)?.map_or(END_OF_INPUT, i32::from);
         }
         5 => {
             // Read a signed number
-            let n: i32;
-            scan!("{}", n);
-            result = n;
+            result = match read_token(&mut io.input)? {
+                Some(token) => {
+                    String::from_utf8_lossy(&token).parse().map_err(|_| {
+                        SimulatorError::Other(format!(
+                            "Invalid number input: {}",
+                            String::from_utf8_lossy(&token)
+                        ))
+                    })?
+                }
+                None => END_OF_INPUT,
+            };
         }
         _ => {
             panic!("Unknown system call");
         }
     }
 
-    result
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CPUPolicy, CPUState};
+    use crate::single_cycle;
+    use crate::test_utils::load_program;
+
+    #[test]
+    fn test_captured_output() {
+        let program = [
+            0x00002537, // lui a0, 0x2
+            0x00000893, // addi a7, x0, 0
+            0x00000073, // ecall
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let mut cpu = CPUState::make(CPUPolicy::default());
+        let mut mem = load_program(&mut cpu, &program);
+        mem.mmu.write_bytes(0x2000, b"hello\0");
+
+        let mut io = SyscallIo {
+            input: io::empty(),
+            output: Vec::new(),
+        };
+        single_cycle::run(&mut cpu, &mut mem, &mut io).unwrap();
+        assert_eq!(io.output, b"hello");
+    }
+
+    #[test]
+    fn test_scanned_input() {
+        let mut mem = crate::memory::inclusive::InclusiveCache::default();
+        let mut io = SyscallIo {
+            input: &b"x -42"[..],
+            output: Vec::new(),
+        };
+        assert_eq!(syscall(0, 4, &mut mem, &mut io).unwrap(), 'x' as i32);
+        assert_eq!(syscall(0, 5, &mut mem, &mut io).unwrap(), -42);
+        // Nothing left
+        assert_eq!(syscall(0, 4, &mut mem, &mut io).unwrap(), END_OF_INPUT);
+        assert_eq!(syscall(0, 5, &mut mem, &mut io).unwrap(), END_OF_INPUT);
+
+        let mut io = SyscallIo {
+            input: &b" 12x"[..],
+            output: Vec::new(),
+        };
+        assert!(syscall(0, 5, &mut mem, &mut io).is_err());
+    }
+
+    #[test]
+    fn test_echo_number() {
+        let program = [
+            0x00500893, // addi a7, x0, 5
+            0x00000073, // ecall
+            0x00200893, // addi a7, x0, 2
+            0x00000073, // ecall
+            0x00300893, // addi a7, x0, 3
+            0x00000073, // ecall
+        ];
+        let echo = |input: &[u8]| {
+            let mut cpu = CPUState::make(CPUPolicy::default());
+            let mut mem = load_program(&mut cpu, &program);
+            let mut io = SyscallIo {
+                input,
+                output: Vec::new(),
+            };
+            single_cycle::run(&mut cpu, &mut mem, &mut io).unwrap();
+            String::from_utf8(io.output).unwrap()
+        };
+        assert_eq!(echo(b"42\n"), "42");
+        assert_eq!(echo(b""), "-1");
+    }
 }
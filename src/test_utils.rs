@@ -0,0 +1,128 @@
+//! Helpers shared by unit tests
+
+use crate::cpu::{CPUPolicy, CPUState, Implementation};
+use crate::elf_helper::parse_elf_file;
+use crate::loader;
+use crate::memory::inclusive::InclusiveCache;
+use crate::memory::{WriteHitPolicy, WriteMissPolicy};
+use crate::pipelined::branch_predictor::PredictorHeuristic;
+use crate::run_wrapper::{STACK_BASE, STACK_SIZE};
+use crate::system_call::SyscallIo;
+use crate::{multi_cycle, pipelined, single_cycle};
+
+/// Address where test programs are loaded
+pub const PROGRAM_BASE: u32 = 0x1000;
+
+/// Loads raw instructions at PROGRAM_BASE into a fresh memory
+/// and points the PC at the first one
+pub fn load_program(cpu: &mut CPUState, program: &[u32]) -> InclusiveCache {
+    let mut mem = InclusiveCache::default();
+    for (i, raw_inst) in program.iter().enumerate() {
+        let address = PROGRAM_BASE + 4 * i as u32;
+        for j in 0..4 {
+            mem.mmu.allocate_page(address + j);
+            mem.mmu.set8(address + j, (raw_inst >> (8 * j)) as u8);
+        }
+    }
+    cpu.pc.write(PROGRAM_BASE);
+    mem
+}
+
+/// Input fed to programs run by run_both: a number, then a character
+const CANNED_INPUT: &[u8] = b"42\na\n";
+
+/// Runs raw instructions loaded by load_program on the single-cycle backend
+/// and on the pipelined one with each heuristic, like run_both_elf
+pub fn run_both(program: &[u32]) -> Vec<CPUState> {
+    compare_backends("program", |cpu| load_program(cpu, program))
+}
+
+/// Runs an ELF file without caches on the single-cycle backend
+/// and on the pipelined one with each heuristic.
+/// See run_both for what is compared
+pub fn run_both_elf(elf_file: &str) -> Vec<CPUState> {
+    compare_backends(elf_file, |cpu| {
+        let mut mem = InclusiveCache::make(
+            vec![],
+            WriteHitPolicy::default(),
+            WriteMissPolicy::default(),
+            0,
+            false,
+        );
+        let (elf_reader, elf_data) = parse_elf_file(elf_file).unwrap();
+        loader::set_stack(cpu, &mut mem.mmu, STACK_BASE, STACK_SIZE);
+        loader::load_elf(cpu, &mut mem.mmu, &elf_reader, &elf_data).unwrap();
+        loader::setup_argv(cpu, &mut mem.mmu, &[elf_file]);
+        mem
+    })
+}
+
+/// Runs whatever load sets up on every backend to compare,
+/// and asserts that the exit PC, the registers, the memory, the output
+/// and the instruction count agree with the single-cycle backend.
+/// The final PC and the cycle count intentionally differ:
+/// the pipeline fetches past the exit, and it stalls and flushes.
+/// Returns the single-cycle state first, then the pipelined ones
+fn compare_backends(
+    name: &str,
+    load: impl Fn(&mut CPUState) -> InclusiveCache,
+) -> Vec<CPUState> {
+    let run = |policy: CPUPolicy| {
+        let mut cpu = CPUState::make(policy);
+        let mut mem = load(&mut cpu);
+        let mut io = SyscallIo {
+            input: CANNED_INPUT,
+            output: Vec::new(),
+        };
+        let exit_pc = match policy.implementation {
+            Implementation::SingleCycle => {
+                single_cycle::run(&mut cpu, &mut mem, &mut io)
+            }
+            Implementation::MultiCycle => {
+                multi_cycle::run(&mut cpu, &mut mem, &mut io)
+            }
+            Implementation::Pipelined => {
+                pipelined::run(&mut cpu, &mut mem, &mut io)
+            }
+        }
+        .unwrap();
+        (exit_pc, cpu, mem, io.output)
+    };
+
+    let (reference_exit_pc, reference, reference_mem, reference_output) =
+        run(CPUPolicy::default());
+    let mut results = vec![reference];
+    for heuristic in [
+        PredictorHeuristic::BufferedPrediction,
+        PredictorHeuristic::AlwaysNotTaken,
+    ] {
+        let (exit_pc, cpu, mem, output) = run(CPUPolicy {
+            implementation: Implementation::Pipelined,
+            heuristic,
+            ..Default::default()
+        });
+        assert_eq!(exit_pc, reference_exit_pc, "{}: exit PC differs", name);
+        for i in 0..32 {
+            assert_eq!(
+                cpu.gpr[i].read(),
+                reference.gpr[i].read(),
+                "{}: x{} differs",
+                name,
+                i
+            );
+        }
+        assert!(
+            mem.mmu.same_contents(&reference_mem.mmu),
+            "{}: memory differs",
+            name
+        );
+        assert_eq!(output, reference_output, "{}: output differs", name);
+        assert_eq!(
+            cpu.history.inst_count, reference.history.inst_count,
+            "{}: instruction count differs",
+            name
+        );
+        results.push(cpu);
+    }
+    results
+}
@@ -0,0 +1,72 @@
+//! Command-line behavior of the evaluators
+
+use std::process::Command;
+
+#[test]
+fn test_invalid_level() {
+    for bin in [
+        env!("CARGO_BIN_EXE_single-eval"),
+        env!("CARGO_BIN_EXE_multi-eval"),
+    ] {
+        let output = Command::new(bin)
+            .args(["a.trace", "--level", "4096,64,1", "--level", "100000,64,1"])
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert_eq!(
+            stderr,
+            "Error: L2: cache_size 100000 is not a power of two\n"
+        );
+    }
+}
+
+#[test]
+fn test_amat_is_quiet() {
+    let trace_path = std::env::temp_dir().join("eval_cli_quiet.trace");
+    std::fs::write(&trace_path, "r 0x1000 4\nw 0x1040 4\nr 0x1000 4\n")
+        .unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_single-eval"))
+        .arg(&trace_path)
+        .args(["--level", "4096,64,1", "--level", "65536,64,8"])
+        .output()
+        .unwrap();
+    std::fs::remove_file(&trace_path).unwrap();
+    assert!(output.status.success());
+    // Only the AMAT itself is printed
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("AMAT = "));
+    assert_eq!(String::from_utf8(output.stderr).unwrap(), "");
+}
+
+#[test]
+fn test_error_rows() {
+    // Only add is there; every other program fails to load
+    let dir = std::env::temp_dir().join("eval_cli_error_rows");
+    std::fs::create_dir_all(dir.join("test")).unwrap();
+    std::fs::create_dir_all(dir.join("eval")).unwrap();
+    std::fs::copy("test-riscv/add.riscv", dir.join("test/add.riscv")).unwrap();
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_sim-eval"))
+            .current_dir(&dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let csv = std::fs::read_to_string(dir.join("eval/sim_eval.csv"));
+        csv.unwrap().lines().map(String::from).collect::<Vec<_>>()
+    };
+
+    let rows = run(&[]);
+    // The header, then a row per program
+    assert_eq!(rows.len(), 12);
+    assert!(rows[1].starts_with("add,"));
+    assert!(!rows[1].contains("Error"));
+    assert_eq!(rows[2], "mul-div,Error,,,");
+    assert_eq!(rows[11], "test_branch,Error,,,");
+
+    // Running into the limit is an error too
+    let rows = run(&["--max-instructions", "10"]);
+    assert_eq!(rows[1], "add,Error,,,");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
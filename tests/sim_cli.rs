@@ -0,0 +1,28 @@
+//! Command-line behavior of the simulator
+
+use std::process::Command;
+
+#[test]
+fn test_dump_regs() {
+    let run = |flags: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_sim"))
+            .arg("test-riscv/add.riscv")
+            .args(flags)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stderr).unwrap()
+    };
+    for flag in ["-r", "--dump-regs"] {
+        let stderr = run(&[flag]);
+        let dump: Vec<&str> = stderr
+            .lines()
+            .skip_while(|line| !line.starts_with("pc "))
+            .collect();
+        // The PC and then x0-x31
+        assert_eq!(dump.len(), 33, "{}", stderr);
+        assert!(dump[1].starts_with("x0   zero = 0x00000000"));
+        assert!(dump[32].starts_with("x31  t6   = 0x"));
+    }
+    assert!(!run(&[]).contains("x0   zero"));
+}